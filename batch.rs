@@ -0,0 +1,233 @@
+use std::ops::Range;
+
+use crate::command::{Command, Response, Sequence, ID_DAP_ExecuteCommands};
+use crate::jtag::{get_bit, set_bit};
+use crate::session::DapSession;
+use crate::transport::DapTransport;
+use crate::error::DapError;
+
+/// Bytes taken by the DAP_ExecuteCommands header (command ID and count).
+pub const EXECUTE_HEADER_LEN: usize = 2;
+
+/// A sequence of commands to be executed through DAP_ExecuteCommands.
+///
+/// Commands are packed into as few packets as possible, taking both the
+/// request size and the worst-case response size into account.
+#[derive(Clone, Debug, Default)]
+pub struct CommandBatch {
+    commands: Vec<Command>,
+}
+
+impl CommandBatch {
+    pub fn new() -> Self {
+        CommandBatch { commands: Vec::new() }
+    }
+
+    /// Append a command. Returns the index of its response.
+    pub fn push(&mut self, cmd: Command) -> usize {
+        self.commands.push(cmd);
+        self.commands.len() - 1
+    }
+
+    pub fn len(&self) -> usize {
+        self.commands.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.commands.is_empty()
+    }
+
+    pub fn commands(&self) -> &[Command] {
+        &self.commands
+    }
+
+    /// Split the commands into groups which each fit in one packet of `packet_size` bytes.
+    ///
+    /// Fails for a command whose request or worst-case response alone does not fit,
+    /// such as a DAP_Info string or DAP_UART_Transfer, which may fill a whole packet.
+    pub fn split(&self, packet_size: usize) -> Result<Vec<Range<usize>>, DapError> {
+        let mut groups = Vec::new();
+        let mut start = 0;
+        let mut request_len = EXECUTE_HEADER_LEN;
+        let mut response_len = EXECUTE_HEADER_LEN;
+        for (n, cmd) in self.commands.iter().enumerate() {
+            let cmd_request_len = cmd.request_len();
+            let cmd_response_len = cmd.max_response_len();
+            if EXECUTE_HEADER_LEN + cmd_request_len > packet_size {
+                return Err(DapError::RequestTooLarge { command: cmd.id(), len: cmd_request_len });
+            }
+            // the probe would cut the response short
+            if EXECUTE_HEADER_LEN + cmd_response_len > packet_size {
+                return Err(DapError::ResponseTooLarge { command: cmd.id(), len: cmd_response_len });
+            }
+            let fits = request_len + cmd_request_len <= packet_size
+                && response_len + cmd_response_len <= packet_size
+                && n - start < u8::MAX as usize;
+            if !fits && n > start {
+                groups.push(start..n);
+                start = n;
+                request_len = EXECUTE_HEADER_LEN;
+                response_len = EXECUTE_HEADER_LEN;
+            }
+            request_len += cmd_request_len;
+            response_len += cmd_response_len;
+        }
+        if start < self.commands.len() {
+            groups.push(start..self.commands.len());
+        }
+        Ok(groups)
+    }
+
+    /// The DAP_ExecuteCommands packets carrying the commands.
+    pub fn packets(&self, packet_size: usize) -> Result<Vec<Command>, DapError> {
+        let packets = self
+            .split(packet_size)?
+            .into_iter()
+            .map(|range| Command::ExecuteCommands(self.commands[range].to_vec()))
+            .collect();
+        Ok(packets)
+    }
+
+    /// Execute every command and return the responses in the original order.
+    ///
+    /// Firmware without DAP_ExecuteCommands gets the commands one per packet instead.
+    pub fn execute<T: DapTransport>(&self, session: &mut DapSession<T>) -> Result<Vec<Response>, DapError> {
+        if !session.supports(ID_DAP_ExecuteCommands) {
+            return session.commands(&self.commands);
+        }
+        let packets = self.packets(session.packet_size())?;
+        let mut responses = Vec::with_capacity(self.commands.len());
+        for response in session.execute_chain(&packets)? {
+            match response {
+                Response::ExecuteCommands(group) => responses.extend(group),
+                _ => unreachable!(),
+            }
+        }
+        Ok(responses)
+    }
+}
+
+/// Run JTAG or SWD `sequences` with as few sequence commands as fit in a packet
+/// and return the bits read as one LSB first bit string.
+pub fn run_sequences<T: DapTransport, S: Sequence>(session: &mut DapSession<T>, sequences: &[S]) -> Result<Vec<u8>, DapError> {
+    // each command may end up inside DAP_ExecuteCommands
+    let packet_size = session.packet_size() - EXECUTE_HEADER_LEN;
+    let mut groups = Vec::new();
+    let mut group = Vec::new();
+    let mut request_len = 2;
+    let mut response_len = 2;
+    for sequence in sequences {
+        let fits = request_len + 1 + sequence.request_data_len() <= packet_size
+            && response_len + sequence.response_data_len() <= packet_size
+            && group.len() < u8::MAX as usize;
+        if !fits && !group.is_empty() {
+            groups.push(std::mem::take(&mut group));
+            request_len = 2;
+            response_len = 2;
+        }
+        request_len += 1 + sequence.request_data_len();
+        response_len += sequence.response_data_len();
+        group.push(sequence.clone());
+    }
+    if !group.is_empty() {
+        groups.push(group);
+    }
+
+    let commands = groups.iter().cloned().map(S::command).collect();
+    let responses = session.execute_commands(commands)?;
+    let mut bits = Vec::new();
+    let mut len = 0;
+    for (group, response) in groups.iter().zip(responses) {
+        let data = S::response_data(response);
+        let mut ptr = 0;
+        for sequence in group.iter().filter(|sequence| sequence.response_data_len() > 0) {
+            for n in 0..sequence.clock_count() {
+                set_bit(&mut bits, len, get_bit(&data[ptr..], n));
+                len += 1;
+            }
+            ptr += sequence.response_data_len();
+        }
+    }
+    Ok(bits)
+}
+
+impl From<Vec<Command>> for CommandBatch {
+    fn from(commands: Vec<Command>) -> Self {
+        CommandBatch { commands }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::command::{BlockAccess, TransferRequest, DAP_ID_VENDOR};
+
+    fn batch(commands: Vec<Command>) -> CommandBatch {
+        let mut batch = CommandBatch::new();
+        for cmd in commands {
+            batch.push(cmd);
+        }
+        batch
+    }
+
+    /// The groups of `batch.split(packet_size)` as start and end pairs.
+    fn split(batch: &CommandBatch, packet_size: usize) -> Vec<(usize, usize)> {
+        batch.split(packet_size).unwrap().into_iter().map(|range| (range.start, range.end)).collect()
+    }
+
+    #[test]
+    fn split_by_request_len() {
+        // 5 bytes each
+        let batch = batch(vec![Command::SwjClock(1_000_000); 5]);
+        assert_eq!(split(&batch, 64), [(0, 5)]);
+        assert_eq!(split(&batch, 2 + 10), [(0, 2), (2, 4), (4, 5)]);
+        assert_eq!(split(&batch, 2 + 14), [(0, 2), (2, 4), (4, 5)]);
+        assert_eq!(split(&batch, 2 + 15), [(0, 3), (3, 5)]);
+    }
+
+    #[test]
+    fn split_by_response_len() {
+        // a 5 byte request with a response of up to 404 bytes
+        let read = Command::TransferBlock { dap_index: 0, ap: true, addr: 0xC, access: BlockAccess::Read(100) };
+        let batch = batch(vec![Command::SwjClock(1_000_000), read.clone(), read]);
+        assert_eq!(split(&batch, 512), [(0, 2), (2, 3)]);
+        assert_eq!(split(&batch, 1024), [(0, 3)]);
+    }
+
+    #[test]
+    fn response_too_large() {
+        // 4 + 4 * 15 bytes of response
+        let read = |count| Command::TransferBlock { dap_index: 0, ap: true, addr: 0xC, access: BlockAccess::Read(count) };
+        assert!(matches!(batch(vec![read(15)]).split(64), Err(DapError::ResponseTooLarge { len: 64, .. })));
+        assert_eq!(split(&batch(vec![read(15)]), 66), [(0, 1)]);
+        // strings may take up the whole packet
+        assert!(matches!(batch(vec![Command::Info(DAP_ID_VENDOR)]).split(64), Err(DapError::ResponseTooLarge { .. })));
+    }
+
+    #[test]
+    fn split_at_255_commands() {
+        let batch = batch(vec![Command::Delay(1); 300]);
+        assert_eq!(split(&batch, 4096), [(0, 255), (255, 300)]);
+    }
+
+    #[test]
+    fn request_too_large() {
+        let transfers = vec![TransferRequest::dp_write(0x8, 0); 12];
+        let batch = batch(vec![Command::Delay(1), Command::Transfer { dap_index: 0, transfers }]);
+        assert!(matches!(batch.split(64), Err(DapError::RequestTooLarge { len: 63, .. })));
+        assert_eq!(split(&batch, 65), [(0, 1), (1, 2)]);
+        assert_eq!(split(&CommandBatch::new(), 64), []);
+    }
+
+    #[test]
+    fn packets() {
+        let batch = batch(vec![Command::Delay(1), Command::Delay(2), Command::Delay(3)]);
+        assert_eq!(
+            batch.packets(2 + 6).unwrap(),
+            [
+                Command::ExecuteCommands(vec![Command::Delay(1), Command::Delay(2)]),
+                Command::ExecuteCommands(vec![Command::Delay(3)]),
+            ]
+        );
+    }
+}
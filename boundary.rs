@@ -0,0 +1,145 @@
+use std::collections::BTreeMap;
+
+use crate::bsdl::{Bsdl, BsdlError};
+use crate::jtag::{get_bit, get_bits, set_bit, Jtag};
+use crate::transport::DapTransport;
+
+/// Boundary scan of one device of the scan chain, described by its BSDL.
+///
+/// The other devices are kept in BYPASS. Outputs are driven from an image of the
+/// boundary register which starts out with the safe values of the BSDL and is
+/// changed with [`BoundaryScan::set_pin`]. Every scan captures the pins, which
+/// [`BoundaryScan::pins`] reports by name.
+pub struct BoundaryScan<'a, T: DapTransport> {
+    jtag: Jtag<'a, T>,
+    bsdl: Bsdl,
+    ir_lengths: Vec<u8>,
+    index: usize,
+    image: Vec<u8>,
+    captured: Vec<u8>,
+}
+
+impl<'a, T: DapTransport> BoundaryScan<'a, T> {
+    /// `ir_lengths` describes the whole chain, index 0 nearest to TDO, and `index`
+    /// is the position of the device the BSDL is for.
+    pub fn new(jtag: Jtag<'a, T>, bsdl: Bsdl, ir_lengths: &[u8], index: usize) -> Result<Self, BsdlError> {
+        let actual = ir_lengths.get(index).copied().unwrap_or(0) as usize;
+        if actual != bsdl.instruction_length {
+            return Err(BsdlError::IrLength { index, expected: bsdl.instruction_length, actual });
+        }
+        let mut image = Vec::new();
+        for cell in &bsdl.cells {
+            set_bit(&mut image, cell.number, cell.safe.unwrap_or(false));
+        }
+        Ok(BoundaryScan { jtag, bsdl, ir_lengths: ir_lengths.to_vec(), index, image, captured: Vec::new() })
+    }
+
+    pub fn jtag(&mut self) -> &mut Jtag<'a, T> {
+        &mut self.jtag
+    }
+
+    pub fn bsdl(&self) -> &Bsdl {
+        &self.bsdl
+    }
+
+    /// Capture every pin with SAMPLE, leaving the device in normal operation.
+    pub fn sample(&mut self) -> Result<BTreeMap<String, bool>, BsdlError> {
+        self.instruction("SAMPLE")?;
+        self.scan()?;
+        Ok(self.pins())
+    }
+
+    /// Load the boundary register image with PRELOAD, or SAMPLE on devices which
+    /// predate the separate instruction, without driving the pins.
+    pub fn preload(&mut self) -> Result<(), BsdlError> {
+        if self.bsdl.instruction("PRELOAD").is_some() {
+            self.instruction("PRELOAD")?;
+        } else {
+            self.instruction("SAMPLE")?;
+        }
+        self.scan()
+    }
+
+    /// Preload the image and switch to EXTEST, from then on the pins follow the image.
+    pub fn extest(&mut self) -> Result<(), BsdlError> {
+        self.preload()?;
+        self.instruction("EXTEST")
+    }
+
+    /// Shift the image into the boundary register and capture the pins, to be used
+    /// after [`BoundaryScan::extest`].
+    pub fn update(&mut self) -> Result<BTreeMap<String, bool>, BsdlError> {
+        self.scan()?;
+        Ok(self.pins())
+    }
+
+    /// Drive pin `name` high or low, or with `None` turn its output off.
+    /// Only the image changes, the pin follows on the next scan.
+    pub fn set_pin(&mut self, name: &str, level: Option<bool>) -> Result<(), BsdlError> {
+        let cell = self
+            .bsdl
+            .pin_cells(name)
+            .find(|cell| cell.function.is_output())
+            .ok_or_else(|| BsdlError::NotOutput(name.to_string()))?;
+        let (number, disable) = (cell.number, cell.disable);
+        match (level, disable) {
+            (Some(level), disable) => {
+                set_bit(&mut self.image, number, level);
+                if let Some(disable) = disable {
+                    set_bit(&mut self.image, disable.cell, !disable.value);
+                }
+            }
+            (None, Some(disable)) => set_bit(&mut self.image, disable.cell, disable.value),
+            (None, None) => return Err(BsdlError::NotOutput(name.to_string())),
+        }
+        Ok(())
+    }
+
+    /// Level of pin `name` captured by the last scan.
+    pub fn pin(&self, name: &str) -> Result<bool, BsdlError> {
+        let cell = self
+            .bsdl
+            .pin_cells(name)
+            .find(|cell| cell.function.is_input())
+            .ok_or_else(|| BsdlError::UnknownPin(name.to_string()))?;
+        Ok(get_bit(&self.captured, cell.number))
+    }
+
+    /// Levels of every pin with an input cell, captured by the last scan.
+    pub fn pins(&self) -> BTreeMap<String, bool> {
+        self.bsdl
+            .cells
+            .iter()
+            .filter(|cell| cell.function.is_input())
+            .filter_map(|cell| Some((cell.port.clone()?, get_bit(&self.captured, cell.number))))
+            .collect()
+    }
+
+    /// Load `name` into the device and BYPASS into every other one.
+    fn instruction(&mut self, name: &str) -> Result<(), BsdlError> {
+        let opcode = self.bsdl.instruction(name).ok_or_else(|| BsdlError::UnknownInstruction(name.to_string()))?;
+        let mut tdi = Vec::new();
+        let mut bits = 0;
+        for (index, &len) in self.ir_lengths.iter().enumerate() {
+            for n in 0..len as usize {
+                set_bit(&mut tdi, bits + n, index != self.index || opcode & (1 << n) != 0);
+            }
+            bits += len as usize;
+        }
+        self.jtag.shift_ir(&tdi, bits)?;
+        Ok(())
+    }
+
+    /// Shift the image through the boundary register, one bypass bit per other device.
+    fn scan(&mut self) -> Result<(), BsdlError> {
+        let len = self.bsdl.boundary_length;
+        let mut tdi = Vec::new();
+        for n in 0..len {
+            set_bit(&mut tdi, self.index + n, get_bit(&self.image, n));
+        }
+        let bits = len + self.ir_lengths.len() - 1;
+        let tdo = self.jtag.shift_dr(&tdi, bits)?;
+        self.captured = get_bits(&tdo, self.index, len);
+        Ok(())
+    }
+}
@@ -0,0 +1,382 @@
+use crate::error::DapError;
+
+/// Failure to parse a BSDL file or to run boundary scan with it.
+#[derive(thiserror::Error, Debug)]
+pub enum BsdlError {
+    #[error("BSDL: {0}")]
+    Parse(String),
+    #[error("no {0} instruction in the BSDL")]
+    UnknownInstruction(String),
+    #[error("no pin {0} in the boundary register")]
+    UnknownPin(String),
+    #[error("pin {0} cannot be driven")]
+    NotOutput(String),
+    #[error("no device on the scan chain matches {0}")]
+    NoDevice(String),
+    #[error("TAP {index} has an IR length of {actual}, the BSDL says {expected}")]
+    IrLength { index: usize, expected: usize, actual: usize },
+    #[error("{0}")]
+    Dap(#[from] DapError),
+}
+
+fn parse_error(message: impl Into<String>) -> BsdlError {
+    BsdlError::Parse(message.into())
+}
+
+/// Function of a boundary scan cell, the third field of a BOUNDARY_REGISTER entry.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CellFunction {
+    Input,
+    Clock,
+    Output2,
+    Output3,
+    Control,
+    Controlr,
+    Internal,
+    Bidir,
+    ObserveOnly,
+}
+
+impl CellFunction {
+    fn from_name(name: &str) -> Option<Self> {
+        Some(match name.to_ascii_lowercase().as_str() {
+            "input" => CellFunction::Input,
+            "clock" => CellFunction::Clock,
+            "output2" => CellFunction::Output2,
+            "output3" => CellFunction::Output3,
+            "control" => CellFunction::Control,
+            "controlr" => CellFunction::Controlr,
+            "internal" => CellFunction::Internal,
+            "bidir" => CellFunction::Bidir,
+            "observe_only" => CellFunction::ObserveOnly,
+            _ => return None,
+        })
+    }
+
+    /// Whether the cell captures the level of its pin.
+    pub fn is_input(self) -> bool {
+        matches!(self, CellFunction::Input | CellFunction::Clock | CellFunction::Bidir | CellFunction::ObserveOnly)
+    }
+
+    /// Whether the cell drives its pin during EXTEST.
+    pub fn is_output(self) -> bool {
+        matches!(self, CellFunction::Output2 | CellFunction::Output3 | CellFunction::Bidir)
+    }
+}
+
+/// The control cell of a three-state output and the value which turns the output off.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Disable {
+    pub cell: usize,
+    pub value: bool,
+}
+
+/// An entry of the BOUNDARY_REGISTER attribute. Cell 0 is nearest to TDO.
+#[derive(Clone, Debug, PartialEq)]
+pub struct BoundaryCell {
+    pub number: usize,
+    /// Cell type such as BC_1 or BC_7.
+    pub cell_type: String,
+    /// `None` for cells without a port, written `*` in BSDL.
+    pub port: Option<String>,
+    pub function: CellFunction,
+    /// `None` where the safe value is X.
+    pub safe: Option<bool>,
+    pub disable: Option<Disable>,
+}
+
+/// What boundary scan needs from a BSDL file.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Bsdl {
+    pub entity: String,
+    pub instruction_length: usize,
+    /// Instruction names and their opcodes.
+    pub instructions: Vec<(String, Vec<u32>)>,
+    /// IDCODE value and mask of the bits which are not X.
+    pub idcode: Option<(u32, u32)>,
+    pub boundary_length: usize,
+    pub cells: Vec<BoundaryCell>,
+}
+
+impl Bsdl {
+    /// Parse the entity, instruction, IDCODE and boundary register attributes of a BSDL file.
+    /// Everything else, such as the port list and the pin maps, is ignored.
+    pub fn parse(text: &str) -> Result<Self, BsdlError> {
+        let text: String = text.lines().map(|line| line.split("--").next().unwrap_or("")).collect::<Vec<_>>().join("\n");
+        let entity = entity_name(&text).ok_or_else(|| parse_error("no entity"))?;
+        let number = |name: &str| -> Result<usize, BsdlError> {
+            let value = attribute(&text, name).ok_or_else(|| parse_error(format!("no {} attribute", name)))?;
+            value.trim().parse().map_err(|_| parse_error(format!("bad {} attribute", name)))
+        };
+        let instruction_length = number("INSTRUCTION_LENGTH")?;
+        let boundary_length = number("BOUNDARY_LENGTH")?;
+
+        let opcodes = attribute(&text, "INSTRUCTION_OPCODE").ok_or_else(|| parse_error("no INSTRUCTION_OPCODE attribute"))?;
+        let mut instructions = Vec::new();
+        for entry in split_top(&opcodes) {
+            let (name, args) = call(entry).ok_or_else(|| parse_error(format!("bad opcode {}", entry)))?;
+            let opcodes = split_top(args).into_iter().map(|bits| binary(bits).map(|(value, _)| value));
+            let opcodes = opcodes.collect::<Option<Vec<_>>>().ok_or_else(|| parse_error(format!("bad opcode {}", entry)))?;
+            instructions.push((name.to_string(), opcodes));
+        }
+
+        let idcode = match attribute(&text, "IDCODE_REGISTER") {
+            Some(value) => Some(binary(&value).ok_or_else(|| parse_error("bad IDCODE_REGISTER attribute"))?),
+            None => None,
+        };
+
+        let register = attribute(&text, "BOUNDARY_REGISTER").ok_or_else(|| parse_error("no BOUNDARY_REGISTER attribute"))?;
+        let mut cells = Vec::new();
+        for entry in split_top(&register) {
+            let cell = parse_cell(entry).ok_or_else(|| parse_error(format!("bad boundary cell {}", entry)))?;
+            if cell.number >= boundary_length || cell.disable.is_some_and(|disable| disable.cell >= boundary_length) {
+                return Err(parse_error(format!("cell {} is outside the boundary register", cell.number)));
+            }
+            cells.push(cell);
+        }
+
+        Ok(Bsdl { entity, instruction_length, instructions, idcode, boundary_length, cells })
+    }
+
+    /// First opcode of the instruction `name`.
+    pub fn instruction(&self, name: &str) -> Option<u32> {
+        self.instructions
+            .iter()
+            .find(|(n, _)| n.eq_ignore_ascii_case(name))
+            .and_then(|(_, opcodes)| opcodes.first().copied())
+    }
+
+    pub fn matches_idcode(&self, idcode: u32) -> bool {
+        self.idcode.is_some_and(|(value, mask)| idcode & mask == value & mask)
+    }
+
+    /// Cells of port `name`, compared ignoring case and spaces as in VHDL.
+    pub fn pin_cells<'s>(&'s self, name: &'s str) -> impl Iterator<Item = &'s BoundaryCell> + 's {
+        let name = normalize(name);
+        self.cells.iter().filter(move |cell| cell.port.as_deref().is_some_and(|port| normalize(port) == name))
+    }
+
+    /// Names of every port with a cell, in boundary register order.
+    pub fn pins(&self) -> Vec<&str> {
+        let mut pins: Vec<&str> = Vec::new();
+        for port in self.cells.iter().filter_map(|cell| cell.port.as_deref()) {
+            if !pins.contains(&port) {
+                pins.push(port);
+            }
+        }
+        pins
+    }
+}
+
+fn normalize(name: &str) -> String {
+    name.chars().filter(|c| !c.is_whitespace()).collect::<String>().to_ascii_lowercase()
+}
+
+fn is_ident_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || c == '_'
+}
+
+fn ident(s: &str) -> &str {
+    let end = s.find(|c| !is_ident_char(c)).unwrap_or(s.len());
+    &s[..end]
+}
+
+/// Name from `entity NAME is`.
+fn entity_name(text: &str) -> Option<String> {
+    let lower = text.to_ascii_lowercase();
+    let mut from = 0;
+    while let Some(pos) = lower[from..].find("entity") {
+        let start = from + pos;
+        from = start + "entity".len();
+        if lower[..start].ends_with(is_ident_char) || lower[from..].starts_with(is_ident_char) {
+            continue;
+        }
+        let name = ident(text[from..].trim_start());
+        if !name.is_empty() && !name.eq_ignore_ascii_case("is") {
+            return Some(name.to_string());
+        }
+    }
+    None
+}
+
+/// Value of `attribute NAME of ENTITY : entity is VALUE;`, with string literals
+/// concatenated with `&` joined into one.
+fn attribute(text: &str, name: &str) -> Option<String> {
+    let lower = text.to_ascii_lowercase();
+    let mut from = 0;
+    while let Some(pos) = lower[from..].find("attribute") {
+        from += pos + "attribute".len();
+        let rest = lower[from..].trim_start();
+        if !ident(rest).eq_ignore_ascii_case(name) {
+            continue;
+        }
+        let colon = from + lower[from..].find(':')?;
+        let rest = lower[colon + 1..].trim_start().strip_prefix("entity")?.trim_start().strip_prefix("is")?;
+        let start = lower.len() - rest.len();
+        let mut quoted = false;
+        let end = start + text[start..].find(|c| {
+            if c == '"' {
+                quoted = !quoted;
+            }
+            c == ';' && !quoted
+        })?;
+        let value = &text[start..end];
+        if !value.contains('"') {
+            return Some(value.trim().to_string());
+        }
+        return Some(value.split('"').skip(1).step_by(2).collect());
+    }
+    None
+}
+
+/// Split at commas which are not inside parentheses.
+fn split_top(s: &str) -> Vec<&str> {
+    let mut parts = Vec::new();
+    let mut depth = 0;
+    let mut start = 0;
+    for (i, c) in s.char_indices() {
+        match c {
+            '(' => depth += 1,
+            ')' => depth -= 1,
+            ',' if depth == 0 => {
+                parts.push(s[start..i].trim());
+                start = i + 1;
+            }
+            _ => (),
+        }
+    }
+    parts.push(s[start..].trim());
+    parts.retain(|part| !part.is_empty());
+    parts
+}
+
+/// Split `NAME (ARGS)` into its name and arguments.
+fn call(s: &str) -> Option<(&str, &str)> {
+    let open = s.find('(')?;
+    let args = s[open + 1..].trim_end().strip_suffix(')')?;
+    Some((s[..open].trim(), args))
+}
+
+/// A binary string, MSB first, as value and mask of the bits which are not X.
+fn binary(s: &str) -> Option<(u32, u32)> {
+    let s = s.trim();
+    if s.is_empty() || s.len() > 32 {
+        return None;
+    }
+    s.chars().try_fold((0, 0), |(value, mask), c| match c {
+        '0' => Some((value << 1, mask << 1 | 1)),
+        '1' => Some((value << 1 | 1, mask << 1 | 1)),
+        'x' | 'X' => Some((value << 1, mask << 1)),
+        _ => None,
+    })
+}
+
+fn bit(s: &str) -> Option<Option<bool>> {
+    match s {
+        "0" => Some(Some(false)),
+        "1" => Some(Some(true)),
+        "x" | "X" => Some(None),
+        _ => None,
+    }
+}
+
+/// `NUM (CELL, PORT, FUNCTION, SAFE [, CCELL, DISVAL, RSLT])`
+fn parse_cell(entry: &str) -> Option<BoundaryCell> {
+    let (number, args) = call(entry)?;
+    let fields = split_top(args);
+    if fields.len() != 4 && fields.len() != 7 {
+        return None;
+    }
+    let disable = if fields.len() == 7 {
+        Some(Disable { cell: fields[4].parse().ok()?, value: bit(fields[5])?? })
+    } else {
+        None
+    };
+    Some(BoundaryCell {
+        number: number.parse().ok()?,
+        cell_type: fields[0].to_string(),
+        port: if fields[1] == "*" { None } else { Some(fields[1].to_string()) },
+        function: CellFunction::from_name(fields[2])?,
+        safe: bit(fields[3])?,
+        disable,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BSDL: &str = r#"
+-- a made-up device with three pins
+entity TEST_DEVICE is
+    generic (PHYSICAL_PIN_MAP : string := "QFN");
+    port (PA0: inout bit; PA1: out bit; NRST: in bit; TCK, TMS, TDI: in bit; TDO: out bit);
+    use STD_1149_1_2001.all;
+    attribute INSTRUCTION_LENGTH of TEST_DEVICE : entity is 5;
+    attribute INSTRUCTION_OPCODE of TEST_DEVICE : entity is
+        "BYPASS (11111)," &
+        "EXTEST (00000, 10000)," & -- two opcodes
+        "SAMPLE (00010)," &
+        "IDCODE (00001)";
+    attribute IDCODE_REGISTER of TEST_DEVICE : entity is
+        "XXXX" & "0110010000010000" & "00000100001" & "1";
+    attribute BOUNDARY_LENGTH of TEST_DEVICE : entity is 6;
+    attribute BOUNDARY_REGISTER of TEST_DEVICE : entity is
+        "0 (BC_1, *, control, 1)," &
+        "1 (BC_7, PA0, bidir, X, 0, 1, Z)," &
+        "2 (BC_1, *, control, 1)," &
+        "3 (BC_1, PA1, output3, X, 2, 1, Z)," &
+        "4 (BC_1, PA1, input, X)," &
+        "5 (BC_4, NRST, observe_only, X)";
+end TEST_DEVICE;
+"#;
+
+    #[test]
+    fn parse() {
+        let bsdl = Bsdl::parse(BSDL).unwrap();
+        assert_eq!(bsdl.entity, "TEST_DEVICE");
+        assert_eq!(bsdl.instruction_length, 5);
+        assert_eq!(bsdl.boundary_length, 6);
+        assert_eq!(bsdl.instruction("extest"), Some(0));
+        assert_eq!(bsdl.instructions[1].1, [0b00000, 0b10000]);
+        assert_eq!(bsdl.instruction("IDCODE"), Some(1));
+        assert_eq!(bsdl.instruction("HIGHZ"), None);
+        assert_eq!(bsdl.idcode, Some((0x0641_0043, 0x0FFF_FFFF)));
+        assert!(bsdl.matches_idcode(0x3641_0043));
+        assert!(!bsdl.matches_idcode(0x0641_0041));
+        assert_eq!(bsdl.cells.len(), 6);
+        assert_eq!(
+            bsdl.cells[1],
+            BoundaryCell {
+                number: 1,
+                cell_type: "BC_7".into(),
+                port: Some("PA0".into()),
+                function: CellFunction::Bidir,
+                safe: None,
+                disable: Some(Disable { cell: 0, value: true }),
+            }
+        );
+        assert_eq!(bsdl.cells[0].port, None);
+        assert_eq!(bsdl.cells[0].safe, Some(true));
+        assert_eq!(bsdl.pins(), ["PA0", "PA1", "NRST"]);
+        let cells: Vec<_> = bsdl.pin_cells("pa1").map(|cell| cell.function).collect();
+        assert_eq!(cells, [CellFunction::Output3, CellFunction::Input]);
+    }
+
+    #[test]
+    fn missing_attributes() {
+        let error = |text: &str| match Bsdl::parse(text) {
+            Err(BsdlError::Parse(message)) => message,
+            result => panic!("expected a parse error, got {:?}", result),
+        };
+        assert_eq!(error("attribute INSTRUCTION_LENGTH of X : entity is 5;"), "no entity");
+        assert_eq!(error(&BSDL.replace("BOUNDARY_LENGTH", "BOUNDARY_SIZE")), "no BOUNDARY_LENGTH attribute");
+        assert_eq!(error(&BSDL.replace("is 6;", "is 5;")), "cell 5 is outside the boundary register");
+        assert_eq!(error(&BSDL.replace("observe_only", "sideways")), "bad boundary cell 5 (BC_4, NRST, sideways, X)");
+        assert_eq!(error(&BSDL.replace("00010", "0002")), "bad opcode SAMPLE (0002)");
+        // without IDCODE_REGISTER there is nothing to match
+        let bsdl = Bsdl::parse(&BSDL.replace("IDCODE_REGISTER", "USERCODE_REGISTER")).unwrap();
+        assert_eq!(bsdl.idcode, None);
+        assert!(!bsdl.matches_idcode(0x0641_0043));
+    }
+}
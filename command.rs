@@ -0,0 +1,1062 @@
+use std::convert::TryInto;
+use std::fmt;
+
+use crate::error::DapError;
+
+pub const ID_DAP_Info: u8 = 0x00;
+pub const ID_DAP_HostStatus: u8 = 0x01;
+pub const ID_DAP_Connect: u8 = 0x02;
+pub const ID_DAP_Disconnect: u8 = 0x03;
+pub const ID_DAP_TransferConfigure: u8 = 0x04;
+pub const ID_DAP_Transfer: u8 = 0x05;
+pub const ID_DAP_TransferBlock: u8 = 0x06;
+pub const ID_DAP_TransferAbort: u8 = 0x07;
+pub const ID_DAP_WriteABORT: u8 = 0x08;
+pub const ID_DAP_Delay: u8 = 0x09;
+pub const ID_DAP_ResetTarget: u8 = 0x0A;
+pub const ID_DAP_SWJ_Pins: u8 = 0x10;
+pub const ID_DAP_SWJ_Clock: u8 = 0x11;
+pub const ID_DAP_SWJ_Sequence: u8 = 0x12;
+pub const ID_DAP_SWD_Configure: u8 = 0x13;
+pub const ID_DAP_JTAG_Sequence: u8 = 0x14;
+pub const ID_DAP_JTAG_Configure: u8 = 0x15;
+pub const ID_DAP_JTAG_IDCODE: u8 = 0x16;
+pub const ID_DAP_SWO_Transport: u8 = 0x17;
+pub const ID_DAP_SWO_Mode: u8 = 0x18;
+pub const ID_DAP_SWO_Baudrate: u8 = 0x19;
+pub const ID_DAP_SWO_Control: u8 = 0x1A;
+pub const ID_DAP_SWO_Status: u8 = 0x1B;
+pub const ID_DAP_SWO_Data: u8 = 0x1C;
+pub const ID_DAP_SWD_Sequence: u8 = 0x1D;
+pub const ID_DAP_SWO_ExtendedStatus: u8 = 0x1E;
+pub const ID_DAP_UART_Transport: u8 = 0x1F;
+pub const ID_DAP_UART_Configure: u8 = 0x20;
+pub const ID_DAP_UART_Transfer: u8 = 0x21;
+pub const ID_DAP_UART_Control: u8 = 0x22;
+pub const ID_DAP_UART_Status: u8 = 0x23;
+pub const ID_DAP_QueueCommands: u8 = 0x7E;
+pub const ID_DAP_ExecuteCommands: u8 = 0x7F;
+pub const ID_DAP_Invalid: u8 = 0xFF;
+
+// ID_DAP_Info
+pub const DAP_ID_VENDOR: u8 = 0x01;
+pub const DAP_ID_PRODUCT: u8 = 0x02;
+pub const DAP_ID_SER_NUM: u8 = 0x03;
+pub const DAP_ID_FW_VER: u8 = 0x04;
+pub const DAP_ID_DEVICE_VENDOR: u8 = 0x05;
+pub const DAP_ID_DEVICE_NAME: u8 = 0x06;
+pub const DAP_ID_BOARD_VENDOR: u8 = 0x07;
+pub const DAP_ID_BOARD_NAME: u8 = 0x08;
+pub const DAP_ID_PRODUCT_FW_VER: u8 = 0x09;
+pub const DAP_ID_CAPABILITIES: u8 = 0xF0;
+pub const DAP_ID_TIMESTAMP_CLOCK: u8 = 0xF1;
+pub const DAP_ID_UART_RX_BUFFER_SIZE: u8 = 0xFB;
+pub const DAP_ID_UART_TX_BUFFER_SIZE: u8 = 0xFC;
+pub const DAP_ID_SWO_BUFFER_SIZE: u8 = 0xFD;
+pub const DAP_ID_PACKET_COUNT: u8 = 0xFE;
+pub const DAP_ID_PACKET_SIZE: u8 = 0xFF;
+
+// ID_DAP_Connect
+pub const DAP_PORT_DEFAULT: u8 = 0x00;
+pub const DAP_PORT_SWD: u8 = 0x01;
+pub const DAP_PORT_JTAG: u8 = 0x02;
+
+// ID_DAP_SWJ_Pins
+pub const DAP_SWJ_SWCLK_TCK: u8 = 1 << 0;
+pub const DAP_SWJ_SWDIO_TMS: u8 = 1 << 1;
+pub const DAP_SWJ_TDI: u8 = 1 << 2;
+pub const DAP_SWJ_TDO: u8 = 1 << 3;
+pub const DAP_SWJ_nTRST: u8 = 1 << 5;
+pub const DAP_SWJ_nRESET: u8 = 1 << 7;
+
+// ID_DAP_Transfer request
+pub const DAP_TRANSFER_APnDP: u8 = 1 << 0;
+pub const DAP_TRANSFER_RnW: u8 = 1 << 1;
+pub const DAP_TRANSFER_A2: u8 = 1 << 2;
+pub const DAP_TRANSFER_A3: u8 = 1 << 3;
+pub const DAP_TRANSFER_MATCH_VALUE: u8 = 1 << 4;
+pub const DAP_TRANSFER_MATCH_MASK: u8 = 1 << 5;
+pub const DAP_TRANSFER_TIMESTAMP: u8 = 1 << 7;
+
+// ID_DAP_Transfer response
+pub const DAP_TRANSFER_OK: u8 = 1 << 0;
+pub const DAP_TRANSFER_WAIT: u8 = 1 << 1;
+pub const DAP_TRANSFER_FAULT: u8 = 1 << 2;
+pub const DAP_TRANSFER_ERROR: u8 = 1 << 3;
+pub const DAP_TRANSFER_MISMATCH: u8 = 1 << 4;
+
+// ID_DAP_SWD_Configure, the turnaround period is encoded as cycles - 1 in the low bits
+pub const DAP_SWD_TURNAROUND_MASK: u8 = 0x03;
+pub const DAP_SWD_DATA_PHASE: u8 = 1 << 2;
+
+// ID_DAP_SWO_Transport
+pub const DAP_SWO_TRANSPORT_NONE: u8 = 0;
+pub const DAP_SWO_TRANSPORT_DATA: u8 = 1;
+pub const DAP_SWO_TRANSPORT_ENDPOINT: u8 = 2;
+
+// ID_DAP_SWO_Mode
+pub const DAP_SWO_MODE_OFF: u8 = 0;
+pub const DAP_SWO_MODE_UART: u8 = 1;
+pub const DAP_SWO_MODE_MANCHESTER: u8 = 2;
+
+// ID_DAP_SWO_Control
+pub const DAP_SWO_CONTROL_STOP: u8 = 0;
+pub const DAP_SWO_CONTROL_START: u8 = 1;
+
+// Trace Status of the SWO status commands
+pub const DAP_SWO_CAPTURE_ACTIVE: u8 = 1 << 0;
+pub const DAP_SWO_STREAM_ERROR: u8 = 1 << 6;
+pub const DAP_SWO_BUFFER_OVERRUN: u8 = 1 << 7;
+
+// ID_DAP_SWO_ExtendedStatus control
+pub const DAP_SWO_EXT_STATUS: u8 = 1 << 0;
+pub const DAP_SWO_EXT_COUNT: u8 = 1 << 1;
+pub const DAP_SWO_EXT_INDEX: u8 = 1 << 2;
+
+// ID_DAP_UART_Transport
+pub const DAP_UART_TRANSPORT_NONE: u8 = 0;
+pub const DAP_UART_TRANSPORT_USB_COM_PORT: u8 = 1;
+pub const DAP_UART_TRANSPORT_DAP_COMMAND: u8 = 2;
+
+// ID_DAP_UART_Configure control, data bits are 5 to 8 in the low nibble
+pub const DAP_UART_DATA_BITS_MASK: u8 = 0x0F;
+pub const DAP_UART_PARITY_NONE: u8 = 0 << 4;
+pub const DAP_UART_PARITY_ODD: u8 = 1 << 4;
+pub const DAP_UART_PARITY_EVEN: u8 = 2 << 4;
+pub const DAP_UART_PARITY_MASK: u8 = 3 << 4;
+pub const DAP_UART_STOP_BITS_1: u8 = 0 << 6;
+pub const DAP_UART_STOP_BITS_1_5: u8 = 1 << 6;
+pub const DAP_UART_STOP_BITS_2: u8 = 2 << 6;
+pub const DAP_UART_STOP_BITS_MASK: u8 = 3 << 6;
+
+// ID_DAP_UART_Configure status
+pub const DAP_UART_CFG_ERROR_DATA_BITS: u8 = 1 << 0;
+pub const DAP_UART_CFG_ERROR_PARITY: u8 = 1 << 1;
+pub const DAP_UART_CFG_ERROR_STOP_BITS: u8 = 1 << 2;
+
+// ID_DAP_UART_Control
+pub const DAP_UART_CONTROL_RX_ENABLE: u8 = 1 << 0;
+pub const DAP_UART_CONTROL_RX_DISABLE: u8 = 1 << 1;
+pub const DAP_UART_CONTROL_RX_BUF_FLUSH: u8 = 1 << 2;
+pub const DAP_UART_CONTROL_TX_ENABLE: u8 = 1 << 4;
+pub const DAP_UART_CONTROL_TX_DISABLE: u8 = 1 << 5;
+pub const DAP_UART_CONTROL_TX_BUF_FLUSH: u8 = 1 << 6;
+
+// UART Status of ID_DAP_UART_Status and ID_DAP_UART_Transfer
+pub const DAP_UART_STATUS_RX_ENABLED: u8 = 1 << 0;
+pub const DAP_UART_STATUS_RX_DATA_LOST: u8 = 1 << 1;
+pub const DAP_UART_STATUS_FRAMING_ERROR: u8 = 1 << 2;
+pub const DAP_UART_STATUS_PARITY_ERROR: u8 = 1 << 3;
+pub const DAP_UART_STATUS_TX_ENABLED: u8 = 1 << 4;
+
+// Status
+pub const DAP_OK: u8 = 0x00;
+pub const DAP_ERROR: u8 = 0xFF;
+
+/// One DP or AP register access inside a DAP_Transfer.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TransferRequest {
+    Read { ap: bool, addr: u8 },
+    Write { ap: bool, addr: u8, value: u32 },
+    /// Read until `(data & match_mask) == value`.
+    ReadMatch { ap: bool, addr: u8, value: u32 },
+    /// Set the mask used by subsequent `ReadMatch` requests.
+    MatchMask(u32),
+}
+
+impl TransferRequest {
+    pub fn dp_read(addr: u8) -> Self {
+        TransferRequest::Read { ap: false, addr }
+    }
+
+    pub fn dp_write(addr: u8, value: u32) -> Self {
+        TransferRequest::Write { ap: false, addr, value }
+    }
+
+    pub fn ap_read(addr: u8) -> Self {
+        TransferRequest::Read { ap: true, addr }
+    }
+
+    pub fn ap_write(addr: u8, value: u32) -> Self {
+        TransferRequest::Write { ap: true, addr, value }
+    }
+
+    /// The request byte as sent to the probe.
+    pub fn request_byte(&self) -> u8 {
+        let port = |ap: bool| if ap { DAP_TRANSFER_APnDP } else { 0 };
+        match *self {
+            TransferRequest::Read { ap, addr } => port(ap) | DAP_TRANSFER_RnW | (addr & 0x0C),
+            TransferRequest::Write { ap, addr, .. } => port(ap) | (addr & 0x0C),
+            TransferRequest::ReadMatch { ap, addr, .. } => {
+                port(ap) | DAP_TRANSFER_RnW | (addr & 0x0C) | DAP_TRANSFER_MATCH_VALUE
+            }
+            TransferRequest::MatchMask(_) => DAP_TRANSFER_MATCH_MASK,
+        }
+    }
+
+    fn encode(&self, buf: &mut Vec<u8>) {
+        buf.push(self.request_byte());
+        match *self {
+            TransferRequest::Read { .. } => (),
+            TransferRequest::Write { value, .. }
+            | TransferRequest::ReadMatch { value, .. }
+            | TransferRequest::MatchMask(value) => buf.extend(value.to_le_bytes()),
+        }
+    }
+
+    /// Bytes taken by this request inside a DAP_Transfer.
+    pub fn request_len(&self) -> usize {
+        match self {
+            TransferRequest::Read { .. } => 1,
+            _ => 1 + 4,
+        }
+    }
+
+    /// Whether the probe returns a data word for this request.
+    pub fn is_read(&self) -> bool {
+        matches!(self, TransferRequest::Read { .. })
+    }
+}
+
+/// Direction and payload of a DAP_TransferBlock.
+#[derive(Clone, Debug, PartialEq)]
+pub enum BlockAccess {
+    /// Read this many words.
+    Read(u16),
+    Write(Vec<u32>),
+}
+
+/// Most clock cycles in a single JTAG or SWD sequence.
+pub const MAX_SEQUENCE_BITS: usize = 64;
+
+/// What the sequences of DAP_JTAG_Sequence and DAP_SWD_Sequence have in common,
+/// so they can be packed into commands the same way.
+pub trait Sequence: Clone {
+    /// Number of clock cycles, 1 to 64.
+    fn clock_count(&self) -> usize;
+    /// Bytes of data after the info byte in the request.
+    fn request_data_len(&self) -> usize;
+    /// Bytes of data in the response, zero unless the line is read.
+    fn response_data_len(&self) -> usize;
+    /// The command carrying `sequences`.
+    fn command(sequences: Vec<Self>) -> Command;
+    /// The data read by the sequences of a command from [`Sequence::command`].
+    fn response_data(response: Response) -> Vec<u8>;
+}
+
+/// One TMS/TDI sequence of a DAP_JTAG_Sequence.
+#[derive(Clone, Debug, PartialEq)]
+pub struct JtagSequence {
+    /// Number of TCK cycles, 1 to 64.
+    pub tck_count: u8,
+    /// TMS level during the whole sequence.
+    pub tms: bool,
+    /// Whether TDO is captured and returned.
+    pub capture: bool,
+    /// TDI bits, LSB first.
+    pub tdi: Vec<u8>,
+}
+
+impl JtagSequence {
+    /// Clock `tck_count` cycles with TDI from `tdi`, which has to hold that many bits.
+    pub fn new(tck_count: u8, tms: bool, capture: bool, tdi: &[u8]) -> Self {
+        assert!((1..=MAX_SEQUENCE_BITS).contains(&(tck_count as usize)), "JTAG sequence of {} cycles", tck_count);
+        JtagSequence { tck_count, tms, capture, tdi: sequence_data(tdi, tck_count as usize, "TDI").to_vec() }
+    }
+
+    fn info_byte(&self) -> u8 {
+        let mut info = self.tck_count & 0x3F; // 64 is encoded as 0
+        if self.tms {
+            info |= 1 << 6;
+        }
+        if self.capture {
+            info |= 1 << 7;
+        }
+        info
+    }
+
+    /// Bytes of TDI data, and of TDO data if captured.
+    pub fn data_len(&self) -> usize {
+        (self.tck_count as usize).div_ceil(8)
+    }
+}
+
+impl Sequence for JtagSequence {
+    fn clock_count(&self) -> usize {
+        self.tck_count as usize
+    }
+
+    fn request_data_len(&self) -> usize {
+        self.data_len()
+    }
+
+    fn response_data_len(&self) -> usize {
+        if self.capture { self.data_len() } else { 0 }
+    }
+
+    fn command(sequences: Vec<Self>) -> Command {
+        Command::JtagSequence(sequences)
+    }
+
+    fn response_data(response: Response) -> Vec<u8> {
+        match response {
+            Response::JtagSequence(tdo) => tdo,
+            _ => unreachable!(),
+        }
+    }
+}
+
+/// One output or input sequence of a DAP_SWD_Sequence.
+#[derive(Clone, Debug, PartialEq)]
+pub struct SwdSequence {
+    /// Number of SWCLK cycles, 1 to 64.
+    pub clock_count: u8,
+    /// Whether SWDIO is read rather than driven.
+    pub input: bool,
+    /// SWDIO bits to drive, LSB first, empty for input.
+    pub swdio: Vec<u8>,
+}
+
+impl SwdSequence {
+    /// Drive `clock_count` bits of `swdio`, which has to hold that many.
+    pub fn output(clock_count: u8, swdio: &[u8]) -> Self {
+        assert!((1..=MAX_SEQUENCE_BITS).contains(&(clock_count as usize)), "SWD sequence of {} cycles", clock_count);
+        SwdSequence { clock_count, input: false, swdio: sequence_data(swdio, clock_count as usize, "SWDIO").to_vec() }
+    }
+
+    pub fn input(clock_count: u8) -> Self {
+        assert!((1..=MAX_SEQUENCE_BITS).contains(&(clock_count as usize)), "SWD sequence of {} cycles", clock_count);
+        SwdSequence { clock_count, input: true, swdio: Vec::new() }
+    }
+
+    fn info_byte(&self) -> u8 {
+        let mut info = self.clock_count & 0x3F; // 64 is encoded as 0
+        if self.input {
+            info |= 1 << 7;
+        }
+        info
+    }
+
+    /// Bytes of SWDIO data, driven or read.
+    pub fn data_len(&self) -> usize {
+        (self.clock_count as usize).div_ceil(8)
+    }
+}
+
+impl Sequence for SwdSequence {
+    fn clock_count(&self) -> usize {
+        self.clock_count as usize
+    }
+
+    fn request_data_len(&self) -> usize {
+        if self.input { 0 } else { self.data_len() }
+    }
+
+    fn response_data_len(&self) -> usize {
+        if self.input { self.data_len() } else { 0 }
+    }
+
+    fn command(sequences: Vec<Self>) -> Command {
+        Command::SwdSequence(sequences)
+    }
+
+    fn response_data(response: Response) -> Vec<u8> {
+        match response {
+            Response::SwdSequence(swdio) => swdio,
+            _ => unreachable!(),
+        }
+    }
+}
+
+/// The bytes holding `bits` bits of `data`, with a clear panic if there are fewer.
+fn sequence_data<'a>(data: &'a [u8], bits: usize, name: &str) -> &'a [u8] {
+    let len = bits.div_ceil(8);
+    match data.get(..len) {
+        Some(data) => data,
+        None => panic!("{} data of {} bytes for a sequence of {} bits", name, data.len(), bits),
+    }
+}
+
+/// A CMSIS-DAP command.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Command {
+    Info(u8),
+    HostStatus { kind: u8, status: u8 },
+    Connect(u8),
+    Disconnect,
+    /// Idle cycles after each transfer, and how often the probe retries WAIT and match reads.
+    TransferConfigure { idle_cycles: u8, wait_retry: u16, match_retry: u16 },
+    Transfer { dap_index: u8, transfers: Vec<TransferRequest> },
+    TransferBlock { dap_index: u8, ap: bool, addr: u8, access: BlockAccess },
+    WriteAbort { dap_index: u8, value: u32 },
+    /// Delay in microseconds.
+    Delay(u16),
+    ResetTarget,
+    SwjPins { output: u8, select: u8, wait: u32 },
+    /// Clock frequency in Hz.
+    SwjClock(u32),
+    /// Output `bit_count` bits of `data`, LSB first. `bit_count` is 1 to 256.
+    SwjSequence { bit_count: u16, data: Vec<u8> },
+    /// Turnaround period and data phase, DAP_SWD_* bits.
+    SwdConfigure(u8),
+    SwdSequence(Vec<SwdSequence>),
+    /// IR length of every device in the scan chain, index 0 nearest to TDO.
+    JtagConfigure(Vec<u8>),
+    JtagSequence(Vec<JtagSequence>),
+    /// Read the IDCODE of the device at this index.
+    JtagIdcode(u8),
+    /// DAP_SWO_TRANSPORT_*.
+    SwoTransport(u8),
+    /// DAP_SWO_MODE_*.
+    SwoMode(u8),
+    /// Requested baudrate in Hz.
+    SwoBaudrate(u32),
+    /// DAP_SWO_CONTROL_*.
+    SwoControl(u8),
+    SwoStatus,
+    /// Which fields to return, DAP_SWO_EXT_*.
+    SwoExtendedStatus(u8),
+    /// Read at most this many bytes of trace data.
+    SwoData(u16),
+    /// DAP_UART_TRANSPORT_*.
+    UartTransport(u8),
+    /// Data bits, parity and stop bits as DAP_UART_* bits, and the baudrate in Hz.
+    UartConfigure { control: u8, baudrate: u32 },
+    /// DAP_UART_CONTROL_* bits.
+    UartControl(u8),
+    UartStatus,
+    /// Send these bytes and return what has been received, as much as fits in the response.
+    UartTransfer(Vec<u8>),
+    ExecuteCommands(Vec<Command>),
+}
+
+/// Data returned by DAP_Transfer.
+#[derive(Clone, Debug, PartialEq)]
+pub struct TransferResponse {
+    /// Number of transfers executed.
+    pub count: u8,
+    /// Response of the last transfer, DAP_TRANSFER_*.
+    pub ack: u8,
+    /// Data of the read requests, in order.
+    pub data: Vec<u32>,
+}
+
+impl TransferResponse {
+    pub fn ack(&self) -> TransferAck {
+        TransferAck::from(self.ack)
+    }
+
+    /// Fail unless all `expected` transfers completed with an OK response.
+    pub fn check(&self, expected: usize) -> Result<(), DapError> {
+        match self.ack() {
+            TransferAck::Ok if self.count as usize == expected => Ok(()),
+            TransferAck::Ok => Err(DapError::MalformedResponse {
+                command: ID_DAP_Transfer,
+                reason: "transfers missing without an error",
+            }),
+            ack => Err(DapError::TransferFailed { command: ID_DAP_Transfer, index: self.count as usize, ack }),
+        }
+    }
+}
+
+/// Outcome of the last transfer of a DAP_Transfer or DAP_TransferBlock.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TransferAck {
+    Ok,
+    Wait,
+    Fault,
+    /// The target did not drive an ACK at all.
+    NoAck,
+    /// SWD parity error in the data phase of a read.
+    ProtocolError,
+    /// A match read did not get the expected value within the match retry count.
+    Mismatch,
+}
+
+impl From<u8> for TransferAck {
+    fn from(ack: u8) -> Self {
+        if ack & DAP_TRANSFER_ERROR != 0 {
+            TransferAck::ProtocolError
+        } else if ack & DAP_TRANSFER_MISMATCH != 0 {
+            TransferAck::Mismatch
+        } else {
+            match ack & 0x07 {
+                DAP_TRANSFER_OK => TransferAck::Ok,
+                DAP_TRANSFER_WAIT => TransferAck::Wait,
+                DAP_TRANSFER_FAULT => TransferAck::Fault,
+                _ => TransferAck::NoAck,
+            }
+        }
+    }
+}
+
+impl fmt::Display for TransferAck {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let s = match self {
+            TransferAck::Ok => "OK",
+            TransferAck::Wait => "WAIT",
+            TransferAck::Fault => "FAULT",
+            TransferAck::NoAck => "no ACK",
+            TransferAck::ProtocolError => "protocol error",
+            TransferAck::Mismatch => "value mismatch",
+        };
+        f.write_str(s)
+    }
+}
+
+/// Data returned by DAP_TransferBlock.
+#[derive(Clone, Debug, PartialEq)]
+pub struct TransferBlockResponse {
+    pub count: u16,
+    pub ack: u8,
+    pub data: Vec<u32>,
+}
+
+/// A decoded CMSIS-DAP response.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Response {
+    /// Raw DAP_Info data, without the terminating NUL for strings.
+    Info(Vec<u8>),
+    /// The port actually selected, 0 on failure.
+    Connect(u8),
+    /// DAP_OK or DAP_ERROR of a command that returns nothing else.
+    Status(u8),
+    ResetTarget { status: u8, execute: u8 },
+    SwjPins(u8),
+    Transfer(TransferResponse),
+    TransferBlock(TransferBlockResponse),
+    /// Captured TDO data, each captured sequence starting at a byte boundary.
+    JtagSequence(Vec<u8>),
+    /// SWDIO data of the input sequences, each starting at a byte boundary.
+    SwdSequence(Vec<u8>),
+    JtagIdcode(u32),
+    /// The baudrate actually configured.
+    SwoBaudrate(u32),
+    /// Trace Status, DAP_SWO_* bits, and the number of bytes in the trace buffer.
+    SwoStatus { status: u8, count: u32 },
+    /// Fields which were not requested are `None`.
+    SwoExtendedStatus { status: Option<u8>, count: Option<u32>, index: Option<u32>, timestamp: Option<u32> },
+    SwoData { status: u8, data: Vec<u8> },
+    /// DAP_UART_CFG_ERROR_* bits for the settings which were rejected, and the baudrate actually configured.
+    UartConfigure { status: u8, baudrate: u32 },
+    /// UART Status, DAP_UART_STATUS_* bits, and the number of bytes in the receive and transmit buffers.
+    UartStatus { status: u8, rx_count: u32, tx_count: u32 },
+    /// How many of the bytes sent were taken into the transmit buffer, and the bytes received.
+    UartTransfer { status: u8, sent: usize, data: Vec<u8> },
+    ExecuteCommands(Vec<Response>),
+}
+
+impl Response {
+    /// Interpret DAP_Info data as a string.
+    pub fn as_string(&self) -> Option<String> {
+        match self {
+            Response::Info(data) if !data.is_empty() => Some(String::from_utf8_lossy(data).into_owned()),
+            _ => None,
+        }
+    }
+
+    /// Interpret DAP_Info data as a little endian number of 1, 2 or 4 bytes.
+    pub fn as_number(&self) -> Option<u32> {
+        match self {
+            Response::Info(data) => match data.len() {
+                1 => Some(data[0] as u32),
+                2 => Some(u16::from_le_bytes([data[0], data[1]]) as u32),
+                4 => Some(u32::from_le_bytes(data[0..4].try_into().unwrap())),
+                _ => None,
+            },
+            _ => None,
+        }
+    }
+}
+
+impl Command {
+    /// DAP_SWJ_Sequence of `bit_count` bits of `data`, which has to hold that many.
+    pub fn swj_sequence(bit_count: u16, data: &[u8]) -> Self {
+        assert!((1..=256).contains(&bit_count), "SWJ sequence of {} bits", bit_count);
+        Command::SwjSequence { bit_count, data: sequence_data(data, bit_count as usize, "SWJ").to_vec() }
+    }
+    pub fn id(&self) -> u8 {
+        match self {
+            Command::Info(_) => ID_DAP_Info,
+            Command::HostStatus { .. } => ID_DAP_HostStatus,
+            Command::Connect(_) => ID_DAP_Connect,
+            Command::Disconnect => ID_DAP_Disconnect,
+            Command::TransferConfigure { .. } => ID_DAP_TransferConfigure,
+            Command::Transfer { .. } => ID_DAP_Transfer,
+            Command::TransferBlock { .. } => ID_DAP_TransferBlock,
+            Command::WriteAbort { .. } => ID_DAP_WriteABORT,
+            Command::Delay(_) => ID_DAP_Delay,
+            Command::ResetTarget => ID_DAP_ResetTarget,
+            Command::SwjPins { .. } => ID_DAP_SWJ_Pins,
+            Command::SwjClock(_) => ID_DAP_SWJ_Clock,
+            Command::SwjSequence { .. } => ID_DAP_SWJ_Sequence,
+            Command::SwdConfigure(_) => ID_DAP_SWD_Configure,
+            Command::SwdSequence(_) => ID_DAP_SWD_Sequence,
+            Command::JtagConfigure(_) => ID_DAP_JTAG_Configure,
+            Command::JtagSequence(_) => ID_DAP_JTAG_Sequence,
+            Command::JtagIdcode(_) => ID_DAP_JTAG_IDCODE,
+            Command::SwoTransport(_) => ID_DAP_SWO_Transport,
+            Command::SwoMode(_) => ID_DAP_SWO_Mode,
+            Command::SwoBaudrate(_) => ID_DAP_SWO_Baudrate,
+            Command::SwoControl(_) => ID_DAP_SWO_Control,
+            Command::SwoStatus => ID_DAP_SWO_Status,
+            Command::SwoExtendedStatus(_) => ID_DAP_SWO_ExtendedStatus,
+            Command::SwoData(_) => ID_DAP_SWO_Data,
+            Command::UartTransport(_) => ID_DAP_UART_Transport,
+            Command::UartConfigure { .. } => ID_DAP_UART_Configure,
+            Command::UartControl(_) => ID_DAP_UART_Control,
+            Command::UartStatus => ID_DAP_UART_Status,
+            Command::UartTransfer(_) => ID_DAP_UART_Transfer,
+            Command::ExecuteCommands(_) => ID_DAP_ExecuteCommands,
+        }
+    }
+
+    /// Append the request bytes of this command to `buf`.
+    pub fn encode(&self, buf: &mut Vec<u8>) {
+        buf.push(self.id());
+        match self {
+            Command::Info(id) => buf.push(*id),
+            Command::HostStatus { kind, status } => buf.extend([*kind, *status]),
+            Command::Connect(port) => buf.push(*port),
+            Command::Disconnect | Command::ResetTarget | Command::SwoStatus | Command::UartStatus => (),
+            Command::TransferConfigure { idle_cycles, wait_retry, match_retry } => {
+                buf.push(*idle_cycles);
+                buf.extend(wait_retry.to_le_bytes());
+                buf.extend(match_retry.to_le_bytes());
+            }
+            Command::Transfer { dap_index, transfers } => {
+                buf.extend([*dap_index, transfers.len() as u8]);
+                for transfer in transfers {
+                    transfer.encode(buf);
+                }
+            }
+            Command::TransferBlock { dap_index, ap, addr, access } => {
+                let mut request = (addr & 0x0C) | if *ap { DAP_TRANSFER_APnDP } else { 0 };
+                buf.push(*dap_index);
+                match access {
+                    BlockAccess::Read(count) => {
+                        request |= DAP_TRANSFER_RnW;
+                        buf.extend(count.to_le_bytes());
+                        buf.push(request);
+                    }
+                    BlockAccess::Write(data) => {
+                        buf.extend((data.len() as u16).to_le_bytes());
+                        buf.push(request);
+                        for word in data {
+                            buf.extend(word.to_le_bytes());
+                        }
+                    }
+                }
+            }
+            Command::WriteAbort { dap_index, value } => {
+                buf.push(*dap_index);
+                buf.extend(value.to_le_bytes());
+            }
+            Command::Delay(us) => buf.extend(us.to_le_bytes()),
+            Command::SwjPins { output, select, wait } => {
+                buf.extend([*output, *select]);
+                buf.extend(wait.to_le_bytes());
+            }
+            Command::SwjClock(clock) => buf.extend(clock.to_le_bytes()),
+            Command::SwjSequence { bit_count, data } => {
+                buf.push(*bit_count as u8); // 256 is encoded as 0
+                buf.extend(sequence_data(data, *bit_count as usize, "SWJ"));
+            }
+            Command::SwdConfigure(config) => buf.push(*config),
+            Command::SwdSequence(sequences) => {
+                buf.push(sequences.len() as u8);
+                for sequence in sequences {
+                    buf.push(sequence.info_byte());
+                    if !sequence.input {
+                        buf.extend(sequence_data(&sequence.swdio, sequence.clock_count as usize, "SWDIO"));
+                    }
+                }
+            }
+            Command::JtagConfigure(ir_lengths) => {
+                buf.push(ir_lengths.len() as u8);
+                buf.extend(ir_lengths);
+            }
+            Command::JtagSequence(sequences) => {
+                buf.push(sequences.len() as u8);
+                for sequence in sequences {
+                    buf.push(sequence.info_byte());
+                    buf.extend(sequence_data(&sequence.tdi, sequence.tck_count as usize, "TDI"));
+                }
+            }
+            Command::JtagIdcode(index) => buf.push(*index),
+            Command::SwoTransport(value)
+            | Command::SwoMode(value)
+            | Command::SwoControl(value)
+            | Command::SwoExtendedStatus(value) => buf.push(*value),
+            Command::SwoBaudrate(baudrate) => buf.extend(baudrate.to_le_bytes()),
+            Command::SwoData(count) => buf.extend(count.to_le_bytes()),
+            Command::UartTransport(value) | Command::UartControl(value) => buf.push(*value),
+            Command::UartConfigure { control, baudrate } => {
+                buf.push(*control);
+                buf.extend(baudrate.to_le_bytes());
+            }
+            Command::UartTransfer(data) => {
+                buf.extend((data.len() as u16).to_le_bytes());
+                buf.extend(data);
+            }
+            Command::ExecuteCommands(cmds) => {
+                buf.push(cmds.len() as u8);
+                for cmd in cmds {
+                    cmd.encode(buf);
+                }
+            }
+        }
+    }
+
+    /// Length of the encoded request.
+    pub fn request_len(&self) -> usize {
+        let mut buf = Vec::new();
+        self.encode(&mut buf);
+        buf.len()
+    }
+
+    /// Worst-case length of the response.
+    pub fn max_response_len(&self) -> usize {
+        match self {
+            Command::Info(id) => match *id {
+                DAP_ID_CAPABILITIES => 2 + 2,
+                DAP_ID_PACKET_COUNT => 2 + 1,
+                DAP_ID_PACKET_SIZE => 2 + 2,
+                0xF1..=0xFD => 2 + 4,
+                // strings, the length field limits them to 255 bytes
+                _ => 2 + 255,
+            },
+            Command::ResetTarget => 3,
+            Command::Transfer { transfers, .. } => {
+                3 + 4 * transfers.iter().filter(|transfer| transfer.is_read()).count()
+            }
+            Command::TransferBlock { access, .. } => match access {
+                BlockAccess::Read(count) => 4 + 4 * *count as usize,
+                BlockAccess::Write(_) => 4,
+            },
+            Command::JtagSequence(sequences) => {
+                2 + sequences.iter().filter(|sequence| sequence.capture).map(JtagSequence::data_len).sum::<usize>()
+            }
+            Command::SwdSequence(sequences) => {
+                2 + sequences.iter().filter(|sequence| sequence.input).map(SwdSequence::data_len).sum::<usize>()
+            }
+            Command::JtagIdcode(_) => 2 + 4,
+            Command::SwoBaudrate(_) => 1 + 4,
+            Command::SwoStatus => 2 + 4,
+            Command::SwoExtendedStatus(_) => 2 + 4 + 4 + 4,
+            Command::SwoData(count) => 4 + *count as usize,
+            Command::UartConfigure { .. } => 2 + 4,
+            Command::UartStatus => 2 + 4 + 4,
+            // the probe returns as much received data as fits in a packet
+            Command::UartTransfer(_) => 6 + u16::MAX as usize,
+            Command::ExecuteCommands(cmds) => 2 + cmds.iter().map(Command::max_response_len).sum::<usize>(),
+            _ => 2,
+        }
+    }
+
+    /// Decode the response to this command found at the start of `buf`.
+    /// Returns the response and the number of bytes it occupied.
+    ///
+    /// DAP_ERROR status bytes and a failed DAP_Connect are reported as [`DapError::Status`].
+    pub fn decode_response(&self, buf: &[u8]) -> Result<(Response, usize), DapError> {
+        let command = self.id();
+        let short = DapError::ShortResponse { command, len: buf.len() };
+        match buf.first() {
+            None => return Err(short),
+            Some(&ID_DAP_Invalid) => return Err(DapError::Unsupported { command }),
+            Some(&id) if id != command => return Err(DapError::UnexpectedResponse { command, response: id }),
+            _ => (),
+        }
+        let byte = |n: usize| buf.get(n).copied().ok_or(DapError::ShortResponse { command, len: buf.len() });
+        let word = |n: usize| -> Result<u32, DapError> {
+            let bytes = buf.get(n..n + 4).ok_or(DapError::ShortResponse { command, len: buf.len() })?;
+            Ok(u32::from_le_bytes(bytes.try_into().unwrap()))
+        };
+        let status = |n: usize| -> Result<u8, DapError> {
+            match byte(n)? {
+                DAP_ERROR => Err(DapError::Status { command }),
+                status => Ok(status),
+            }
+        };
+        let decoded = match self {
+            Command::Info(id) => {
+                let len = byte(1)? as usize;
+                let mut data = buf.get(2..2 + len).ok_or(short)?.to_vec();
+                // strings always have a terminating NUL character, numbers start at 0xF0
+                if *id < DAP_ID_CAPABILITIES && data.last() == Some(&0) {
+                    data.pop();
+                }
+                (Response::Info(data), 2 + len)
+            }
+            Command::Connect(_) => match byte(1)? {
+                0 => return Err(DapError::Status { command }),
+                port => (Response::Connect(port), 2),
+            },
+            Command::ResetTarget => (Response::ResetTarget { status: status(1)?, execute: byte(2)? }, 3),
+            Command::SwjPins { .. } => (Response::SwjPins(byte(1)?), 2),
+            Command::Transfer { transfers, .. } => {
+                let count = byte(1)?;
+                let ack = byte(2)?;
+                let mut ptr = 3;
+                let mut data = Vec::new();
+                // data words are present only for the transfers which were executed
+                for transfer in transfers.iter().take(count as usize) {
+                    if transfer.is_read() {
+                        data.push(word(ptr)?);
+                        ptr += 4;
+                    }
+                }
+                (Response::Transfer(TransferResponse { count, ack, data }), ptr)
+            }
+            Command::TransferBlock { access, .. } => {
+                let count = u16::from_le_bytes([byte(1)?, byte(2)?]);
+                let ack = byte(3)?;
+                let mut ptr = 4;
+                let mut data = Vec::new();
+                if let BlockAccess::Read(_) = access {
+                    for _ in 0..count {
+                        data.push(word(ptr)?);
+                        ptr += 4;
+                    }
+                }
+                (Response::TransferBlock(TransferBlockResponse { count, ack, data }), ptr)
+            }
+            Command::JtagSequence(sequences) => {
+                status(1)?;
+                let len = sequences.iter().filter(|sequence| sequence.capture).map(JtagSequence::data_len).sum::<usize>();
+                let tdo = buf.get(2..2 + len).ok_or(short)?.to_vec();
+                (Response::JtagSequence(tdo), 2 + len)
+            }
+            Command::SwdSequence(sequences) => {
+                status(1)?;
+                let len = sequences.iter().filter(|sequence| sequence.input).map(SwdSequence::data_len).sum::<usize>();
+                let swdio = buf.get(2..2 + len).ok_or(short)?.to_vec();
+                (Response::SwdSequence(swdio), 2 + len)
+            }
+            Command::JtagIdcode(_) => {
+                status(1)?;
+                (Response::JtagIdcode(word(2)?), 6)
+            }
+            // 0 means the baudrate could not be configured
+            Command::SwoBaudrate(_) => match word(1)? {
+                0 => return Err(DapError::Status { command }),
+                baudrate => (Response::SwoBaudrate(baudrate), 5),
+            },
+            Command::SwoStatus => (Response::SwoStatus { status: byte(1)?, count: word(2)? }, 6),
+            Command::SwoExtendedStatus(control) => {
+                // only the requested fields are present
+                let mut ptr = 1;
+                let mut status = None;
+                if control & DAP_SWO_EXT_STATUS != 0 {
+                    status = Some(byte(ptr)?);
+                    ptr += 1;
+                }
+                let mut count = None;
+                if control & DAP_SWO_EXT_COUNT != 0 {
+                    count = Some(word(ptr)?);
+                    ptr += 4;
+                }
+                let (mut index, mut timestamp) = (None, None);
+                if control & DAP_SWO_EXT_INDEX != 0 {
+                    index = Some(word(ptr)?);
+                    timestamp = Some(word(ptr + 4)?);
+                    ptr += 8;
+                }
+                (Response::SwoExtendedStatus { status, count, index, timestamp }, ptr)
+            }
+            Command::SwoData(_) => {
+                let status = byte(1)?;
+                let count = u16::from_le_bytes([byte(2)?, byte(3)?]) as usize;
+                let data = buf.get(4..4 + count).ok_or(short)?.to_vec();
+                (Response::SwoData { status, data }, 4 + count)
+            }
+            Command::UartConfigure { .. } => (Response::UartConfigure { status: byte(1)?, baudrate: word(2)? }, 6),
+            Command::UartStatus => {
+                (Response::UartStatus { status: byte(1)?, rx_count: word(2)?, tx_count: word(6)? }, 10)
+            }
+            Command::UartTransfer(_) => {
+                let status = status(1)?;
+                let sent = u16::from_le_bytes([byte(2)?, byte(3)?]) as usize;
+                let count = u16::from_le_bytes([byte(4)?, byte(5)?]) as usize;
+                let data = buf.get(6..6 + count).ok_or(short)?.to_vec();
+                (Response::UartTransfer { status, sent, data }, 6 + count)
+            }
+            Command::ExecuteCommands(cmds) => {
+                let count = byte(1)? as usize;
+                if count != cmds.len() {
+                    return Err(DapError::MalformedResponse { command, reason: "not all commands were executed" });
+                }
+                let mut ptr = 2;
+                let mut responses = Vec::new();
+                for cmd in cmds {
+                    let (response, len) = cmd.decode_response(&buf[ptr..])?;
+                    responses.push(response);
+                    ptr += len;
+                }
+                (Response::ExecuteCommands(responses), ptr)
+            }
+            Command::HostStatus { .. }
+            | Command::Disconnect
+            | Command::TransferConfigure { .. }
+            | Command::WriteAbort { .. }
+            | Command::Delay(_)
+            | Command::SwjClock(_)
+            | Command::SwjSequence { .. }
+            | Command::SwdConfigure(_)
+            | Command::JtagConfigure(_)
+            | Command::SwoTransport(_)
+            | Command::SwoMode(_)
+            | Command::SwoControl(_)
+            | Command::UartTransport(_)
+            | Command::UartControl(_) => (Response::Status(status(1)?), 2),
+        };
+        Ok(decoded)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn encode(cmd: &Command) -> Vec<u8> {
+        let mut buf = Vec::new();
+        cmd.encode(&mut buf);
+        buf
+    }
+
+    #[test]
+    fn encode_transfer() {
+        let cmd = Command::Transfer {
+            dap_index: 0,
+            transfers: vec![
+                TransferRequest::dp_read(0x0),
+                TransferRequest::ap_write(0xC, 0x1234_5678),
+                TransferRequest::MatchMask(0xF0),
+                TransferRequest::ReadMatch { ap: false, addr: 0x4, value: 0x30 },
+            ],
+        };
+        assert_eq!(
+            encode(&cmd),
+            [
+                ID_DAP_Transfer, 0, 4,
+                0x02,
+                0x0D, 0x78, 0x56, 0x34, 0x12,
+                0x20, 0xF0, 0, 0, 0,
+                0x16, 0x30, 0, 0, 0,
+            ]
+        );
+        assert_eq!(cmd.request_len(), 19);
+        assert_eq!(cmd.max_response_len(), 3 + 4);
+    }
+
+    #[test]
+    fn encode_transfer_block() {
+        let read = Command::TransferBlock { dap_index: 1, ap: true, addr: 0xC, access: BlockAccess::Read(300) };
+        assert_eq!(encode(&read), [ID_DAP_TransferBlock, 1, 0x2C, 0x01, 0x0F]);
+        assert_eq!(read.max_response_len(), 4 + 1200);
+        let write = Command::TransferBlock { dap_index: 0, ap: true, addr: 0xC, access: BlockAccess::Write(vec![1, 2]) };
+        assert_eq!(encode(&write), [ID_DAP_TransferBlock, 0, 2, 0, 0x0D, 1, 0, 0, 0, 2, 0, 0, 0]);
+    }
+
+    #[test]
+    fn encode_sequences() {
+        // 256 bits are encoded as 0
+        let swj = Command::swj_sequence(256, &[0xFF; 32]);
+        assert_eq!(encode(&swj)[..2], [ID_DAP_SWJ_Sequence, 0]);
+        assert_eq!(swj.request_len(), 2 + 32);
+        // bits beyond the count are not sent
+        assert_eq!(encode(&Command::swj_sequence(9, &[0xFF, 0x01, 0xAA])), [ID_DAP_SWJ_Sequence, 9, 0xFF, 0x01]);
+
+        let swd = Command::SwdSequence(vec![SwdSequence::output(8, &[0xA5]), SwdSequence::input(64)]);
+        assert_eq!(encode(&swd), [ID_DAP_SWD_Sequence, 2, 8, 0xA5, 0x80]);
+        assert_eq!(swd.max_response_len(), 2 + 8);
+
+        let jtag = Command::JtagSequence(vec![JtagSequence::new(3, true, true, &[0x05]), JtagSequence::new(64, false, false, &[0; 8])]);
+        assert_eq!(encode(&jtag)[..5], [ID_DAP_JTAG_Sequence, 2, 0xC3, 0x05, 0x00]);
+        assert_eq!(jtag.request_len(), 2 + 2 + 9);
+        assert_eq!(jtag.max_response_len(), 2 + 1);
+    }
+
+    #[test]
+    #[should_panic(expected = "SWDIO")]
+    fn short_sequence_data() {
+        encode(&Command::SwdSequence(vec![SwdSequence { clock_count: 16, input: false, swdio: vec![0] }]));
+    }
+
+    #[test]
+    fn decode_info() {
+        let (response, len) = Command::Info(DAP_ID_SER_NUM).decode_response(&[ID_DAP_Info, 4, b'A', b'B', b'C', 0, 0xEE]).unwrap();
+        assert_eq!(len, 6);
+        assert_eq!(response.as_string().as_deref(), Some("ABC"));
+        let (response, _) = Command::Info(DAP_ID_PACKET_SIZE).decode_response(&[ID_DAP_Info, 2, 0x00, 0x02]).unwrap();
+        assert_eq!(response.as_number(), Some(512));
+        // no data means the information is not available
+        let (response, _) = Command::Info(DAP_ID_VENDOR).decode_response(&[ID_DAP_Info, 0]).unwrap();
+        assert_eq!(response.as_string(), None);
+    }
+
+    #[test]
+    fn decode_transfer() {
+        let cmd = Command::Transfer {
+            dap_index: 0,
+            transfers: vec![TransferRequest::dp_read(0x0), TransferRequest::dp_write(0x8, 0), TransferRequest::ap_read(0xC)],
+        };
+        let buf = [ID_DAP_Transfer, 3, DAP_TRANSFER_OK, 0x77, 0x14, 0xA0, 0x2B, 0x21, 0x00, 0x77, 0x04];
+        let (response, len) = cmd.decode_response(&buf).unwrap();
+        assert_eq!(len, buf.len());
+        assert_eq!(response, Response::Transfer(TransferResponse { count: 3, ack: DAP_TRANSFER_OK, data: vec![0x2BA0_1477, 0x0477_0021] }));
+
+        // only the data of the executed transfers is present
+        let buf = [ID_DAP_Transfer, 1, DAP_TRANSFER_FAULT, 0x77, 0x14, 0xA0, 0x2B];
+        let (response, len) = cmd.decode_response(&buf).unwrap();
+        assert_eq!(len, 7);
+        match response {
+            Response::Transfer(response) => {
+                assert_eq!(response.ack(), TransferAck::Fault);
+                assert!(matches!(response.check(3), Err(DapError::TransferFailed { index: 1, ack: TransferAck::Fault, .. })));
+            }
+            response => panic!("{:?}", response),
+        }
+    }
+
+    #[test]
+    fn decode_errors() {
+        let cmd = Command::Transfer { dap_index: 0, transfers: vec![TransferRequest::dp_read(0x0)] };
+        assert!(matches!(cmd.decode_response(&[ID_DAP_Transfer, 1, DAP_TRANSFER_OK, 0x77]), Err(DapError::ShortResponse { .. })));
+        assert!(matches!(cmd.decode_response(&[]), Err(DapError::ShortResponse { len: 0, .. })));
+        assert!(matches!(cmd.decode_response(&[ID_DAP_Invalid]), Err(DapError::Unsupported { command: ID_DAP_Transfer })));
+        assert!(matches!(
+            cmd.decode_response(&[ID_DAP_Info, 0]),
+            Err(DapError::UnexpectedResponse { command: ID_DAP_Transfer, response: ID_DAP_Info })
+        ));
+        assert!(matches!(Command::Connect(DAP_PORT_SWD).decode_response(&[ID_DAP_Connect, 0]), Err(DapError::Status { .. })));
+        assert!(matches!(Command::SwjClock(1_000_000).decode_response(&[ID_DAP_SWJ_Clock, DAP_ERROR]), Err(DapError::Status { .. })));
+    }
+
+    #[test]
+    fn execute_commands() {
+        let cmd = Command::ExecuteCommands(vec![
+            Command::SwjClock(1_000_000),
+            Command::Transfer { dap_index: 0, transfers: vec![TransferRequest::dp_read(0x0)] },
+        ]);
+        let request = encode(&cmd);
+        assert_eq!(request[..3], [ID_DAP_ExecuteCommands, 2, ID_DAP_SWJ_Clock]);
+        assert_eq!(request.len(), 2 + 5 + 4);
+        assert_eq!(cmd.max_response_len(), 2 + 2 + 7);
+
+        let buf = [ID_DAP_ExecuteCommands, 2, ID_DAP_SWJ_Clock, DAP_OK, ID_DAP_Transfer, 1, DAP_TRANSFER_OK, 1, 2, 3, 4];
+        let (response, len) = cmd.decode_response(&buf).unwrap();
+        assert_eq!(len, buf.len());
+        assert_eq!(
+            response,
+            Response::ExecuteCommands(vec![
+                Response::Status(DAP_OK),
+                Response::Transfer(TransferResponse { count: 1, ack: DAP_TRANSFER_OK, data: vec![0x0403_0201] }),
+            ])
+        );
+        assert!(matches!(
+            cmd.decode_response(&[ID_DAP_ExecuteCommands, 1, ID_DAP_SWJ_Clock, DAP_OK]),
+            Err(DapError::MalformedResponse { .. })
+        ));
+    }
+}
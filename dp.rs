@@ -0,0 +1,256 @@
+use crate::command::{BlockAccess, Command, Response, TransferAck, TransferRequest, ID_DAP_Transfer, ID_DAP_TransferBlock};
+use crate::error::DapError;
+use crate::session::DapSession;
+use crate::transport::DapTransport;
+
+// DP registers (A[3:2])
+pub const DP_IDCODE: u8 = 0x0; // R
+pub const DP_ABORT: u8 = 0x0; // W
+pub const DP_CTRL_STAT: u8 = 0x4;
+pub const DP_SELECT: u8 = 0x8; // W
+pub const DP_RESEND: u8 = 0x8; // R
+pub const DP_RDBUFF: u8 = 0xC; // R
+pub const DP_TARGETSEL: u8 = 0xC; // W, SWD multi-drop
+
+// DP_SELECT.DPBANKSEL of the DPv2 registers at A[3:2] = 0x4
+pub const DP_BANK_TARGETID: u32 = 2;
+pub const DP_BANK_DLPIDR: u32 = 3;
+
+// DP_CTRL/STAT bits
+pub const CSYSPWRUPACK: u32 = 1 << 31;
+pub const CSYSPWRUPREQ: u32 = 1 << 30;
+pub const CDBGPWRUPACK: u32 = 1 << 29;
+pub const CDBGPWRUPREQ: u32 = 1 << 28;
+pub const WDATAERR: u32 = 1 << 7;
+pub const STICKYERR: u32 = 1 << 5;
+pub const STICKYCMP: u32 = 1 << 4;
+pub const STICKYORUN: u32 = 1 << 1;
+
+// DP_ABORT bits
+pub const ORUNERRCLR: u32 = 1 << 4;
+pub const WDERRCLR: u32 = 1 << 3;
+pub const STKERRCLR: u32 = 1 << 2;
+pub const STKCMPCLR: u32 = 1 << 1;
+pub const DAPABORT: u32 = 1 << 0;
+
+/// How often transfers answered with WAIT are reissued by default.
+/// The probe already retries WAIT itself, so this only covers a very slow target.
+const DEFAULT_WAIT_RETRIES: usize = 8;
+
+/// DP and AP register access through DAP_Transfer and DAP_TransferBlock.
+///
+/// Requests are split over as many commands as needed. Transfers which
+/// the probe gave up on with WAIT are reissued, and after a FAULT the sticky error
+/// flags are cleared through DP_ABORT so the next access can succeed.
+pub struct DebugPort<'a, T: DapTransport> {
+    session: &'a mut DapSession<T>,
+    dap_index: u8,
+    wait_retries: usize,
+    select: Option<u32>,
+}
+
+impl<'a, T: DapTransport> DebugPort<'a, T> {
+    /// Access the DP at `dap_index` in the JTAG chain. Use 0 for SWD.
+    pub fn new(session: &'a mut DapSession<T>, dap_index: u8) -> Self {
+        DebugPort { session, dap_index, wait_retries: DEFAULT_WAIT_RETRIES, select: None }
+    }
+
+    pub fn set_wait_retries(&mut self, retries: usize) {
+        self.wait_retries = retries;
+    }
+
+    pub fn session(&mut self) -> &mut DapSession<T> {
+        self.session
+    }
+
+    /// Last value written to DP_SELECT through this port, if known.
+    pub fn selected(&self) -> Option<u32> {
+        self.select
+    }
+
+    /// Execute `transfers` in order and return the data of the read requests.
+    ///
+    /// On failure the error holds the index of the failing transfer within `transfers`.
+    pub fn transfer(&mut self, transfers: &[TransferRequest]) -> Result<Vec<u32>, DapError> {
+        let result = self.transfer_all(transfers);
+        self.select = match result {
+            Ok(_) => transfers.iter().fold(self.select, |select, transfer| match *transfer {
+                TransferRequest::Write { ap: false, addr: DP_SELECT, value } => Some(value),
+                _ => select,
+            }),
+            // no telling which writes made it
+            Err(_) => None,
+        };
+        result
+    }
+
+    fn transfer_all(&mut self, transfers: &[TransferRequest]) -> Result<Vec<u32>, DapError> {
+        let mut data = Vec::new();
+        let mut done = 0;
+        let mut retries = 0;
+        while done < transfers.len() {
+            let chunk = &transfers[done..done + self.chunk_len(&transfers[done..])];
+            let cmd = Command::Transfer { dap_index: self.dap_index, transfers: chunk.to_vec() };
+            let response = match self.session.command(&cmd)? {
+                Response::Transfer(response) => response,
+                _ => unreachable!(),
+            };
+            data.extend(&response.data);
+            let count = response.count as usize;
+            self.check_ack(ID_DAP_Transfer, response.ack(), done + count, count == chunk.len(), &mut retries)?;
+            done += count;
+        }
+        Ok(data)
+    }
+
+    /// Read AP register `addr` of the selected AP `count` times with DAP_TransferBlock,
+    /// as for AP_DRW with TAR auto increment.
+    pub fn read_ap_block(&mut self, addr: u8, count: usize) -> Result<Vec<u32>, DapError> {
+        self.transfer_block(addr, count, None)
+    }
+
+    /// Write every word of `data` to AP register `addr` of the selected AP with DAP_TransferBlock.
+    pub fn write_ap_block(&mut self, addr: u8, data: &[u32]) -> Result<(), DapError> {
+        self.transfer_block(addr, data.len(), Some(data))?;
+        Ok(())
+    }
+
+    fn transfer_block(&mut self, addr: u8, count: usize, write: Option<&[u32]>) -> Result<Vec<u32>, DapError> {
+        // 4 bytes of header in a response, 5 in a request
+        let packet_size = self.session.packet_size();
+        let header = if write.is_some() { 5 } else { 4 };
+        let max = ((packet_size - header) / 4).clamp(1, u16::MAX as usize);
+        let mut data = Vec::new();
+        let mut done = 0;
+        let mut retries = 0;
+        while done < count {
+            let len = max.min(count - done);
+            let access = match write {
+                Some(words) => BlockAccess::Write(words[done..done + len].to_vec()),
+                None => BlockAccess::Read(len as u16),
+            };
+            let cmd = Command::TransferBlock { dap_index: self.dap_index, ap: true, addr, access };
+            let response = match self.session.command(&cmd)? {
+                Response::TransferBlock(response) => response,
+                _ => unreachable!(),
+            };
+            data.extend(&response.data);
+            let count = response.count as usize;
+            let ack = TransferAck::from(response.ack);
+            self.check_ack(ID_DAP_TransferBlock, ack, done + count, count == len, &mut retries)?;
+            done += count;
+        }
+        Ok(data)
+    }
+
+    /// Deal with the ACK of a command which stopped at transfer `index`, reissuing
+    /// transfers after WAIT up to the retry limit.
+    fn check_ack(
+        &mut self,
+        command: u8,
+        ack: TransferAck,
+        index: usize,
+        complete: bool,
+        retries: &mut usize,
+    ) -> Result<(), DapError> {
+        let error = |ack| DapError::TransferFailed { command, index, ack };
+        match ack {
+            TransferAck::Ok if complete => *retries = 0,
+            TransferAck::Ok => {
+                return Err(DapError::MalformedResponse { command, reason: "transfers missing without an error" })
+            }
+            TransferAck::Wait if *retries < self.wait_retries => {
+                log::debug!("transfer {} answered WAIT, retrying", index);
+                *retries += 1;
+            }
+            TransferAck::Wait => {
+                // give up on the stalled AP transaction
+                self.abort(DAPABORT)?;
+                return Err(error(TransferAck::Wait));
+            }
+            TransferAck::Fault => {
+                self.clear_sticky_errors()?;
+                return Err(error(TransferAck::Fault));
+            }
+            TransferAck::Mismatch => {
+                // no stale compare result for the next match read
+                self.abort(STKCMPCLR)?;
+                return Err(error(TransferAck::Mismatch));
+            }
+            ack => return Err(error(ack)),
+        }
+        Ok(())
+    }
+
+    /// How many of `transfers` fit in a single DAP_Transfer.
+    fn chunk_len(&self, transfers: &[TransferRequest]) -> usize {
+        let packet_size = self.session.packet_size();
+        let mut request_len = 3;
+        let mut response_len = 3;
+        let mut len = 0;
+        for transfer in transfers.iter().take(u8::MAX as usize) {
+            request_len += transfer.request_len();
+            if transfer.is_read() {
+                response_len += 4;
+            }
+            if request_len > packet_size || response_len > packet_size {
+                break;
+            }
+            len += 1;
+        }
+        len.max(1)
+    }
+
+    pub fn read_dp(&mut self, addr: u8) -> Result<u32, DapError> {
+        Ok(self.transfer(&[TransferRequest::dp_read(addr)])?[0])
+    }
+
+    pub fn write_dp(&mut self, addr: u8, value: u32) -> Result<(), DapError> {
+        self.transfer(&[TransferRequest::dp_write(addr, value)])?;
+        Ok(())
+    }
+
+    /// Read an AP register of the AP and bank currently selected in DP_SELECT.
+    pub fn read_ap(&mut self, addr: u8) -> Result<u32, DapError> {
+        Ok(self.transfer(&[TransferRequest::ap_read(addr)])?[0])
+    }
+
+    pub fn write_ap(&mut self, addr: u8, value: u32) -> Result<(), DapError> {
+        self.transfer(&[TransferRequest::ap_write(addr, value)])?;
+        Ok(())
+    }
+
+    /// Request system and debug power and wait until both are acknowledged.
+    pub fn power_up(&mut self) -> Result<(), DapError> {
+        let ack = CSYSPWRUPACK | CDBGPWRUPACK;
+        self.transfer(&[
+            TransferRequest::dp_write(DP_SELECT, 0),
+            TransferRequest::dp_write(DP_CTRL_STAT, CSYSPWRUPREQ | CDBGPWRUPREQ),
+            TransferRequest::MatchMask(ack),
+            TransferRequest::ReadMatch { ap: false, addr: DP_CTRL_STAT, value: ack },
+        ])?;
+        Ok(())
+    }
+
+    /// Write DP_ABORT with DAP_WriteABORT, which works even while the DP answers WAIT.
+    pub fn abort(&mut self, value: u32) -> Result<(), DapError> {
+        self.session.command(&Command::WriteAbort { dap_index: self.dap_index, value })?;
+        Ok(())
+    }
+
+    /// Clear all sticky error flags in DP_CTRL/STAT.
+    pub fn clear_sticky_errors(&mut self) -> Result<(), DapError> {
+        log::debug!("clearing sticky errors");
+        self.abort(ORUNERRCLR | WDERRCLR | STKERRCLR | STKCMPCLR)
+    }
+}
+
+/// At least 50 cycles with SWDIO high followed by idle cycles.
+pub fn swd_reset_sequence() -> Command {
+    Command::swj_sequence(56, &[0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0x0F])
+}
+
+/// The 16-bit JTAG-to-SWD select sequence 0xE79E, preceded by a line reset.
+pub fn jtag_to_swd_sequence() -> Command {
+    Command::swj_sequence(72, &[0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0x9E, 0xE7])
+}
@@ -0,0 +1,122 @@
+use crate::command::*;
+use crate::info::FirmwareVersion;
+
+/// Name of a command as used in the CMSIS-DAP specification.
+pub fn command_name(id: u8) -> &'static str {
+    match id {
+        ID_DAP_Info => "DAP_Info",
+        ID_DAP_HostStatus => "DAP_HostStatus",
+        ID_DAP_Connect => "DAP_Connect",
+        ID_DAP_Disconnect => "DAP_Disconnect",
+        ID_DAP_TransferConfigure => "DAP_TransferConfigure",
+        ID_DAP_Transfer => "DAP_Transfer",
+        ID_DAP_TransferBlock => "DAP_TransferBlock",
+        ID_DAP_TransferAbort => "DAP_TransferAbort",
+        ID_DAP_WriteABORT => "DAP_WriteABORT",
+        ID_DAP_Delay => "DAP_Delay",
+        ID_DAP_ResetTarget => "DAP_ResetTarget",
+        ID_DAP_SWJ_Pins => "DAP_SWJ_Pins",
+        ID_DAP_SWJ_Clock => "DAP_SWJ_Clock",
+        ID_DAP_SWJ_Sequence => "DAP_SWJ_Sequence",
+        ID_DAP_SWD_Configure => "DAP_SWD_Configure",
+        ID_DAP_JTAG_Sequence => "DAP_JTAG_Sequence",
+        ID_DAP_JTAG_Configure => "DAP_JTAG_Configure",
+        ID_DAP_JTAG_IDCODE => "DAP_JTAG_IDCODE",
+        ID_DAP_SWO_Transport => "DAP_SWO_Transport",
+        ID_DAP_SWO_Mode => "DAP_SWO_Mode",
+        ID_DAP_SWO_Baudrate => "DAP_SWO_Baudrate",
+        ID_DAP_SWO_Control => "DAP_SWO_Control",
+        ID_DAP_SWO_Status => "DAP_SWO_Status",
+        ID_DAP_SWO_Data => "DAP_SWO_Data",
+        ID_DAP_SWD_Sequence => "DAP_SWD_Sequence",
+        ID_DAP_SWO_ExtendedStatus => "DAP_SWO_ExtendedStatus",
+        ID_DAP_UART_Transport => "DAP_UART_Transport",
+        ID_DAP_UART_Configure => "DAP_UART_Configure",
+        ID_DAP_UART_Transfer => "DAP_UART_Transfer",
+        ID_DAP_UART_Control => "DAP_UART_Control",
+        ID_DAP_UART_Status => "DAP_UART_Status",
+        ID_DAP_QueueCommands => "DAP_QueueCommands",
+        ID_DAP_ExecuteCommands => "DAP_ExecuteCommands",
+        _ => "unknown command",
+    }
+}
+
+/// Failure to find or open a probe.
+#[derive(thiserror::Error, Debug)]
+pub enum ProbeCreationError {
+    #[error("Probe was not found.")]
+    NotFound,
+    #[error("USB device could not be opened. Please check the permissions.")]
+    CouldNotOpen,
+    // #[error("{0}")]
+    // HidApi(#[from] hidapi::HidError),
+    #[error("{0}")]
+    Rusb(#[from] rusb::Error),
+    #[error("An error specific to a probe type occured: {0}")]
+    ProbeSpecific(#[source] Box<dyn std::error::Error + Send + Sync>),
+    #[error("{0}")]
+    Other(&'static str),
+}
+
+/// Everything that can go wrong while talking to an opened probe.
+#[derive(thiserror::Error, Debug)]
+pub enum DapError {
+    #[error("{}: USB error: {source}", command_name(*command))]
+    Usb { command: u8, source: rusb::Error },
+    #[error("{}: probe did not respond in time", command_name(*command))]
+    Timeout { command: u8 },
+    #[error("{}: request of {len} bytes does not fit in a packet", command_name(*command))]
+    RequestTooLarge { command: u8, len: usize },
+    #[error("{}: response of up to {len} bytes does not fit in a packet", command_name(*command))]
+    ResponseTooLarge { command: u8, len: usize },
+    #[error("{}: response is too short ({len} bytes)", command_name(*command))]
+    ShortResponse { command: u8, len: usize },
+    #[error("{}: malformed response: {reason}", command_name(*command))]
+    MalformedResponse { command: u8, reason: &'static str },
+    #[error("{}: response is for command {response:#04X}", command_name(*command))]
+    UnexpectedResponse { command: u8, response: u8 },
+    #[error("{}: command is not supported by the probe", command_name(*command))]
+    Unsupported { command: u8 },
+    #[error("{}: requires CMSIS-DAP {required} or later", command_name(*command))]
+    FirmwareTooOld { command: u8, required: FirmwareVersion },
+    #[error("{}: probe returned DAP_ERROR", command_name(*command))]
+    Status { command: u8 },
+    /// `index` is the position of the failing transfer within the request.
+    #[error("{}: transfer {index} failed: {ack}", command_name(*command))]
+    TransferFailed { command: u8, index: usize, ack: TransferAck },
+    #[error("JTAG scan chain: {0}")]
+    ScanChain(&'static str),
+    #[error("TARGETSEL {targetsel:#010X}: {reason}")]
+    TargetSel { targetsel: u32, reason: &'static str },
+    #[error("address {addr:#010X} is not word aligned")]
+    Unaligned { addr: u32 },
+    #[error("{0}")]
+    Probe(#[from] ProbeCreationError),
+}
+
+impl DapError {
+    pub fn usb(command: u8, source: rusb::Error) -> Self {
+        match source {
+            rusb::Error::Timeout => DapError::Timeout { command },
+            source => DapError::Usb { command, source },
+        }
+    }
+
+    /// The command which failed, if the error is about a command.
+    pub fn command(&self) -> Option<u8> {
+        match self {
+            DapError::Usb { command, .. }
+            | DapError::Timeout { command }
+            | DapError::RequestTooLarge { command, .. }
+            | DapError::ResponseTooLarge { command, .. }
+            | DapError::ShortResponse { command, .. }
+            | DapError::MalformedResponse { command, .. }
+            | DapError::UnexpectedResponse { command, .. }
+            | DapError::Unsupported { command }
+            | DapError::FirmwareTooOld { command, .. }
+            | DapError::Status { command }
+            | DapError::TransferFailed { command, .. } => Some(*command),
+            DapError::ScanChain(_) | DapError::TargetSel { .. } | DapError::Unaligned { .. } | DapError::Probe(_) => None,
+        }
+    }
+}
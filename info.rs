@@ -0,0 +1,248 @@
+use std::fmt;
+use std::str::FromStr;
+
+use crate::command::*;
+use crate::error::DapError;
+use crate::session::DapSession;
+use crate::transport::DapTransport;
+
+// DAP_ID_CAPABILITIES, first byte
+const CAP_SWD: u8 = 1 << 0;
+const CAP_JTAG: u8 = 1 << 1;
+const CAP_SWO_UART: u8 = 1 << 2;
+const CAP_SWO_MANCHESTER: u8 = 1 << 3;
+const CAP_ATOMIC_COMMANDS: u8 = 1 << 4;
+const CAP_TEST_DOMAIN_TIMER: u8 = 1 << 5;
+const CAP_SWO_STREAMING: u8 = 1 << 6;
+const CAP_UART: u8 = 1 << 7;
+// DAP_ID_CAPABILITIES, second byte
+const CAP_USB_COM_PORT: u8 = 1 << 0;
+
+/// CMSIS-DAP protocol version as reported by DAP_Info FW_VER.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct FirmwareVersion {
+    pub major: u8,
+    pub minor: u8,
+    pub patch: u8,
+}
+
+impl FirmwareVersion {
+    pub const fn new(major: u8, minor: u8, patch: u8) -> Self {
+        FirmwareVersion { major, minor, patch }
+    }
+}
+
+impl FromStr for FirmwareVersion {
+    type Err = ();
+
+    /// Accepts "1.10", "2.1.0" and the like. Trailing text after a number is ignored.
+    fn from_str(s: &str) -> Result<Self, ()> {
+        let mut fields = s.trim().splitn(3, '.').map(|field| {
+            let digits: String = field.chars().take_while(char::is_ascii_digit).collect();
+            digits.parse::<u8>().map_err(|_| ())
+        });
+        let major = fields.next().ok_or(())??;
+        // a bare number such as "0254" is a DAPLink build number, not a protocol version
+        let minor = fields.next().ok_or(())??;
+        let patch = fields.next().transpose()?.unwrap_or(0);
+        Ok(FirmwareVersion { major, minor, patch })
+    }
+}
+
+impl fmt::Display for FirmwareVersion {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}.{}.{}", self.major, self.minor, self.patch)
+    }
+}
+
+/// Protocol version which introduced command `id`, and whether `capabilities` allow it.
+pub fn command_support(id: u8, capabilities: &Capabilities) -> (FirmwareVersion, bool) {
+    let swo = capabilities.swo_uart || capabilities.swo_manchester;
+    match id {
+        ID_DAP_QueueCommands | ID_DAP_ExecuteCommands => (FirmwareVersion::new(1, 1, 0), capabilities.atomic_commands),
+        ID_DAP_SWD_Configure => (FirmwareVersion::new(1, 0, 0), capabilities.swd),
+        ID_DAP_JTAG_Sequence | ID_DAP_JTAG_Configure | ID_DAP_JTAG_IDCODE => {
+            (FirmwareVersion::new(1, 0, 0), capabilities.jtag)
+        }
+        ID_DAP_SWO_Transport..=ID_DAP_SWO_Data => (FirmwareVersion::new(1, 1, 0), swo),
+        ID_DAP_SWD_Sequence => (FirmwareVersion::new(1, 2, 0), capabilities.swd),
+        ID_DAP_SWO_ExtendedStatus => (FirmwareVersion::new(1, 2, 0), swo),
+        ID_DAP_UART_Transport..=ID_DAP_UART_Status => (FirmwareVersion::new(2, 1, 0), capabilities.uart),
+        _ => (FirmwareVersion::new(1, 0, 0), true),
+    }
+}
+
+/// Features reported by DAP_Info Capabilities.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Capabilities {
+    pub swd: bool,
+    pub jtag: bool,
+    pub swo_uart: bool,
+    pub swo_manchester: bool,
+    /// DAP_ExecuteCommands and DAP_QueueCommands.
+    pub atomic_commands: bool,
+    pub test_domain_timer: bool,
+    /// SWO data through the dedicated streaming endpoint.
+    pub swo_streaming: bool,
+    /// UART communication port, DAP_UART_* commands.
+    pub uart: bool,
+    /// The UART is also exposed as a USB COM port.
+    pub usb_com_port: bool,
+}
+
+impl Capabilities {
+    /// Parse the one or two bytes of DAP_Info Capabilities.
+    pub fn from_bytes(data: &[u8]) -> Self {
+        let byte0 = data.first().copied().unwrap_or(0);
+        let byte1 = data.get(1).copied().unwrap_or(0);
+        Capabilities {
+            swd: byte0 & CAP_SWD != 0,
+            jtag: byte0 & CAP_JTAG != 0,
+            swo_uart: byte0 & CAP_SWO_UART != 0,
+            swo_manchester: byte0 & CAP_SWO_MANCHESTER != 0,
+            atomic_commands: byte0 & CAP_ATOMIC_COMMANDS != 0,
+            test_domain_timer: byte0 & CAP_TEST_DOMAIN_TIMER != 0,
+            swo_streaming: byte0 & CAP_SWO_STREAMING != 0,
+            uart: byte0 & CAP_UART != 0,
+            usb_com_port: byte1 & CAP_USB_COM_PORT != 0,
+        }
+    }
+}
+
+impl fmt::Display for Capabilities {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let flags = [
+            (self.swd, "SWD"),
+            (self.jtag, "JTAG"),
+            (self.swo_uart, "SWO-UART"),
+            (self.swo_manchester, "SWO-Manchester"),
+            (self.atomic_commands, "atomic"),
+            (self.test_domain_timer, "timer"),
+            (self.swo_streaming, "SWO-streaming"),
+            (self.uart, "UART"),
+            (self.usb_com_port, "USB-COM"),
+        ];
+        let names: Vec<&str> = flags.iter().filter(|(set, _)| *set).map(|&(_, name)| name).collect();
+        write!(f, "{}", names.join(" "))
+    }
+}
+
+/// Everything DAP_Info can tell about a probe.
+///
+/// Strings and numbers the firmware does not provide are `None`.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct DapInfo {
+    pub vendor: Option<String>,
+    pub product: Option<String>,
+    pub serial_number: Option<String>,
+    /// CMSIS-DAP protocol version string, e.g. "2.1.0". See [`DapInfo::version`].
+    pub protocol_version: Option<String>,
+    pub target_vendor: Option<String>,
+    pub target_name: Option<String>,
+    pub board_vendor: Option<String>,
+    pub board_name: Option<String>,
+    pub product_version: Option<String>,
+    pub capabilities: Capabilities,
+    /// Test domain timer frequency in Hz.
+    pub timer_frequency: Option<u32>,
+    pub uart_rx_buffer_size: Option<u32>,
+    pub uart_tx_buffer_size: Option<u32>,
+    pub swo_buffer_size: Option<u32>,
+    pub packet_count: Option<u8>,
+    pub packet_size: Option<u16>,
+}
+
+impl DapInfo {
+    /// Query every DAP_Info ID, one per packet since a string may fill a whole packet.
+    /// The requests are pipelined up to the packet count.
+    pub fn read<T: DapTransport>(session: &mut DapSession<T>) -> Result<Self, DapError> {
+        let ids = [
+            DAP_ID_VENDOR,
+            DAP_ID_PRODUCT,
+            DAP_ID_SER_NUM,
+            DAP_ID_FW_VER,
+            DAP_ID_DEVICE_VENDOR,
+            DAP_ID_DEVICE_NAME,
+            DAP_ID_BOARD_VENDOR,
+            DAP_ID_BOARD_NAME,
+            DAP_ID_PRODUCT_FW_VER,
+            DAP_ID_CAPABILITIES,
+            DAP_ID_TIMESTAMP_CLOCK,
+            DAP_ID_UART_RX_BUFFER_SIZE,
+            DAP_ID_UART_TX_BUFFER_SIZE,
+            DAP_ID_SWO_BUFFER_SIZE,
+            DAP_ID_PACKET_COUNT,
+            DAP_ID_PACKET_SIZE,
+        ];
+        let cmds: Vec<Command> = ids.iter().map(|&id| Command::Info(id)).collect();
+        let responses = session.commands(&cmds)?;
+        let mut info = DapInfo::default();
+        for (&id, response) in ids.iter().zip(&responses) {
+            match id {
+                DAP_ID_VENDOR => info.vendor = response.as_string(),
+                DAP_ID_PRODUCT => info.product = response.as_string(),
+                DAP_ID_SER_NUM => info.serial_number = response.as_string(),
+                DAP_ID_FW_VER => info.protocol_version = response.as_string(),
+                DAP_ID_DEVICE_VENDOR => info.target_vendor = response.as_string(),
+                DAP_ID_DEVICE_NAME => info.target_name = response.as_string(),
+                DAP_ID_BOARD_VENDOR => info.board_vendor = response.as_string(),
+                DAP_ID_BOARD_NAME => info.board_name = response.as_string(),
+                DAP_ID_PRODUCT_FW_VER => info.product_version = response.as_string(),
+                DAP_ID_CAPABILITIES => {
+                    if let Response::Info(data) = response {
+                        info.capabilities = Capabilities::from_bytes(data);
+                    }
+                }
+                DAP_ID_TIMESTAMP_CLOCK => info.timer_frequency = response.as_number(),
+                DAP_ID_UART_RX_BUFFER_SIZE => info.uart_rx_buffer_size = response.as_number(),
+                DAP_ID_UART_TX_BUFFER_SIZE => info.uart_tx_buffer_size = response.as_number(),
+                DAP_ID_SWO_BUFFER_SIZE => info.swo_buffer_size = response.as_number(),
+                DAP_ID_PACKET_COUNT => info.packet_count = response.as_number().map(|n| n as u8),
+                DAP_ID_PACKET_SIZE => info.packet_size = response.as_number().map(|n| n as u16),
+                _ => unreachable!(),
+            }
+        }
+        Ok(info)
+    }
+
+    /// The protocol version, if the firmware reports one that can be parsed.
+    pub fn version(&self) -> Option<FirmwareVersion> {
+        self.protocol_version.as_ref()?.parse().ok()
+    }
+}
+
+impl fmt::Display for DapInfo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let strings = [
+            ("VENDOR", &self.vendor),
+            ("PRODUCT", &self.product),
+            ("SERIAL_NUMBER", &self.serial_number),
+            ("FW_VER", &self.protocol_version),
+            ("DEVICE_VENDOR", &self.target_vendor),
+            ("DEVICE_NAME", &self.target_name),
+            ("BOARD_VENDOR", &self.board_vendor),
+            ("BOARD_NAME", &self.board_name),
+            ("PRODUCT_FW_VER", &self.product_version),
+        ];
+        for (name, value) in strings.iter() {
+            if let Some(value) = value {
+                writeln!(f, "{} = {}", name, value)?;
+            }
+        }
+        writeln!(f, "CAPABILITIES = {}", self.capabilities)?;
+        let numbers = [
+            ("TIMESTAMP_CLOCK", self.timer_frequency),
+            ("UART_RX_BUFFER_SIZE", self.uart_rx_buffer_size),
+            ("UART_TX_BUFFER_SIZE", self.uart_tx_buffer_size),
+            ("SWO_BUFFER_SIZE", self.swo_buffer_size),
+            ("PACKET_COUNT", self.packet_count.map(u32::from)),
+            ("PACKET_SIZE", self.packet_size.map(u32::from)),
+        ];
+        for (name, value) in numbers.iter() {
+            if let Some(value) = value {
+                writeln!(f, "{} = {}", name, value)?;
+            }
+        }
+        Ok(())
+    }
+}
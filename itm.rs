@@ -0,0 +1,420 @@
+use crate::error::DapError;
+use crate::memory::MemAp;
+use crate::swo::SwoMode;
+use crate::transport::DapTransport;
+
+// Debug Exception and Monitor Control Register
+pub const DEMCR: u32 = 0xE000_EDFC;
+pub const DEMCR_TRCENA: u32 = 1 << 24;
+
+// ITM registers
+pub const ITM_STIM0: u32 = 0xE000_0000;
+pub const ITM_TER: u32 = 0xE000_0E00;
+pub const ITM_TPR: u32 = 0xE000_0E40;
+pub const ITM_TCR: u32 = 0xE000_0E80;
+pub const ITM_LAR: u32 = 0xE000_0FB0;
+
+// ITM_TCR bits
+pub const ITM_TCR_ITMENA: u32 = 1 << 0;
+pub const ITM_TCR_TSENA: u32 = 1 << 1;
+pub const ITM_TCR_SYNCENA: u32 = 1 << 2;
+pub const ITM_TCR_TXENA: u32 = 1 << 3;
+pub const ITM_TCR_SWOENA: u32 = 1 << 4;
+/// Global timestamp every 8192 cycles.
+pub const ITM_TCR_GTSFREQ_8192: u32 = 0b10 << 10;
+pub const ITM_TCR_TRACEBUSID_SHIFT: u32 = 16;
+
+/// Unlocks ITM register writes through ITM_LAR.
+pub const CORESIGHT_UNLOCK: u32 = 0xC5AC_CE55;
+
+// DWT registers
+pub const DWT_CTRL: u32 = 0xE000_1000;
+pub const DWT_COMP0: u32 = 0xE000_1020;
+pub const DWT_MASK0: u32 = 0xE000_1024;
+pub const DWT_FUNCTION0: u32 = 0xE000_1028;
+
+// DWT_CTRL bits
+pub const DWT_CTRL_CYCCNTENA: u32 = 1 << 0;
+pub const DWT_CTRL_POSTPRESET_SHIFT: u32 = 1;
+pub const DWT_CTRL_CYCTAP: u32 = 1 << 9;
+pub const DWT_CTRL_SYNCTAP_SHIFT: u32 = 10;
+pub const DWT_CTRL_PCSAMPLENA: u32 = 1 << 12;
+pub const DWT_CTRL_EXCTRCENA: u32 = 1 << 16;
+
+// DWT_FUNCTION values for data trace, ARMv7-M
+pub const DWT_FUNCTION_PC: u32 = 0b0001;
+pub const DWT_FUNCTION_DATA_VALUE: u32 = 0b0010;
+pub const DWT_FUNCTION_PC_AND_DATA_VALUE: u32 = 0b0011;
+/// With EMITRANGE the address offset is emitted instead of the PC.
+pub const DWT_FUNCTION_EMITRANGE: u32 = 1 << 5;
+
+// TPIU registers
+pub const TPIU_CSPSR: u32 = 0xE004_0004;
+pub const TPIU_ACPR: u32 = 0xE004_0010;
+pub const TPIU_SPPR: u32 = 0xE004_00F0;
+pub const TPIU_FFCR: u32 = 0xE004_0304;
+
+// TPIU_SPPR values
+pub const TPIU_SPPR_MANCHESTER: u32 = 0b01;
+pub const TPIU_SPPR_NRZ: u32 = 0b10;
+
+/// TPIU_FFCR with the formatter bypassed, as needed for SWO.
+pub const TPIU_FFCR_BYPASS: u32 = 1 << 8;
+
+/// A DWT comparator set up to trace accesses to a data address.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct DataTrace {
+    pub comparator: u8,
+    pub address: u32,
+    /// Number of low address bits ignored by the comparison.
+    pub mask: u32,
+    /// DWT_FUNCTION_* value.
+    pub function: u32,
+}
+
+/// What [`configure`] enables on the target.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ItmConfig {
+    /// Frequency of the TPIU trace clock, usually the core clock, in Hz.
+    pub trace_clock: u32,
+    /// SWO baudrate in Hz, as configured on the probe.
+    pub baudrate: u32,
+    pub mode: SwoMode,
+    /// Bit n enables stimulus port n.
+    pub stimulus_ports: u32,
+    pub local_timestamps: bool,
+    pub global_timestamps: bool,
+    pub exception_trace: bool,
+    /// Sample the PC every 1024 cycles.
+    pub pc_sampling: bool,
+    pub data_trace: Vec<DataTrace>,
+}
+
+impl Default for ItmConfig {
+    fn default() -> Self {
+        ItmConfig {
+            trace_clock: 0,
+            baudrate: 0,
+            mode: SwoMode::Uart,
+            stimulus_ports: 0xFFFF_FFFF,
+            local_timestamps: false,
+            global_timestamps: false,
+            exception_trace: false,
+            pc_sampling: false,
+            data_trace: Vec::new(),
+        }
+    }
+}
+
+/// Set up the TPIU, ITM and DWT of a Cortex-M so trace goes out on SWO.
+pub fn configure<T: DapTransport>(ap: &mut MemAp<T>, config: &ItmConfig) -> Result<(), DapError> {
+    let demcr = ap.read32(DEMCR)?;
+    ap.write32(DEMCR, demcr | DEMCR_TRCENA)?;
+
+    // TPIU: 1 bit port, formatter bypassed
+    let prescaler = (config.trace_clock / config.baudrate.max(1)).max(1) - 1;
+    let protocol = match config.mode {
+        SwoMode::Uart => TPIU_SPPR_NRZ,
+        SwoMode::Manchester => TPIU_SPPR_MANCHESTER,
+    };
+    ap.write32(TPIU_CSPSR, 1)?;
+    ap.write32(TPIU_ACPR, prescaler)?;
+    ap.write32(TPIU_SPPR, protocol)?;
+    ap.write32(TPIU_FFCR, TPIU_FFCR_BYPASS)?;
+
+    let mut dwt_ctrl = ap.read32(DWT_CTRL)? & 0xF000_0000; // NUMCOMP
+    // synchronisation packets every 2^24 cycles
+    dwt_ctrl |= DWT_CTRL_CYCCNTENA | 0b01 << DWT_CTRL_SYNCTAP_SHIFT;
+    if config.pc_sampling {
+        dwt_ctrl |= DWT_CTRL_PCSAMPLENA | 0xF << DWT_CTRL_POSTPRESET_SHIFT;
+    }
+    if config.exception_trace {
+        dwt_ctrl |= DWT_CTRL_EXCTRCENA;
+    }
+    ap.write32(DWT_CTRL, dwt_ctrl)?;
+    for trace in &config.data_trace {
+        let offset = 0x10 * trace.comparator as u32;
+        ap.write32(DWT_COMP0 + offset, trace.address)?;
+        ap.write32(DWT_MASK0 + offset, trace.mask)?;
+        ap.write32(DWT_FUNCTION0 + offset, trace.function)?;
+    }
+
+    let mut tcr = ITM_TCR_ITMENA | ITM_TCR_SYNCENA | ITM_TCR_SWOENA | 1 << ITM_TCR_TRACEBUSID_SHIFT;
+    if config.local_timestamps {
+        tcr |= ITM_TCR_TSENA;
+    }
+    if config.global_timestamps {
+        tcr |= ITM_TCR_GTSFREQ_8192;
+    }
+    if config.pc_sampling || config.exception_trace || !config.data_trace.is_empty() {
+        tcr |= ITM_TCR_TXENA;
+    }
+    ap.write32(ITM_LAR, CORESIGHT_UNLOCK)?;
+    ap.write32(ITM_TCR, tcr)?;
+    ap.write32(ITM_TPR, 0)?;
+    ap.write32(ITM_TER, config.stimulus_ports)?;
+    Ok(())
+}
+
+/// What happened to the exception of an exception trace packet.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ExceptionAction {
+    Entered,
+    Exited,
+    Returned,
+}
+
+/// How a local timestamp relates to the packet it belongs to, the TC field.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TimestampQuality {
+    Sync,
+    TimestampDelayed,
+    PacketDelayed,
+    BothDelayed,
+}
+
+/// A decoded ITM or DWT packet.
+#[derive(Clone, Debug, PartialEq)]
+pub enum ItmEvent {
+    Sync,
+    /// Packets were lost because the ITM output FIFO was full.
+    Overflow,
+    /// Software write to stimulus port `port`, `data` is 1, 2 or 4 bytes, little endian.
+    Instrumentation { port: u8, data: Vec<u8> },
+    /// DWT counters which wrapped, the bits of the event counter packet.
+    EventCounter(u8),
+    Exception { number: u16, action: ExceptionAction },
+    /// `None` while the core is sleeping.
+    PcSample(Option<u32>),
+    DataTracePc { comparator: u8, pc: u32 },
+    /// Bits 15:0 of the data address.
+    DataTraceAddress { comparator: u8, address: u16 },
+    DataTraceValue { comparator: u8, write: bool, value: u32, size: u8 },
+    /// Cycles since the previous local timestamp.
+    LocalTimestamp { delta: u32, quality: TimestampQuality },
+    /// Value after a GTS1 or GTS2 packet, combined with the bits of the previous ones.
+    GlobalTimestamp { value: u64, wrapped: bool, clock_changed: bool },
+    /// Stimulus port page of the following instrumentation packets.
+    Extension { page: u32 },
+    /// A header this decoder does not understand, with its payload if any.
+    Unknown(Vec<u8>),
+}
+
+/// Most bytes of a packet with continuation bits, header included, as for a 64 bit GTS2.
+const MAX_CONTINUATION_LEN: usize = 7;
+
+/// Zero bytes which, followed by 0x80, make a synchronisation packet.
+const SYNC_ZEROS: usize = 5;
+
+const OVERFLOW: u8 = 0x70;
+const GTS1: u8 = 0x94;
+const GTS2: u8 = 0xB4;
+
+/// Streaming decoder of the ITM protocol as sent out on SWO.
+///
+/// Bytes can be fed in any chunks, a packet split between two of them is
+/// completed by the next call.
+#[derive(Clone, Debug, Default)]
+pub struct ItmDecoder {
+    packet: Vec<u8>,
+    zeros: usize,
+    global_timestamp: u64,
+}
+
+impl ItmDecoder {
+    pub fn new() -> Self {
+        ItmDecoder::default()
+    }
+
+    /// Decode `data` and return the packets completed by it.
+    pub fn feed(&mut self, data: &[u8]) -> Vec<ItmEvent> {
+        data.iter().filter_map(|&byte| self.push(byte)).collect()
+    }
+
+    /// Decode one byte, returning the packet it completes, if any.
+    pub fn push(&mut self, byte: u8) -> Option<ItmEvent> {
+        if self.packet.is_empty() {
+            if byte == 0 {
+                self.zeros += 1;
+                return None;
+            }
+            let zeros = std::mem::take(&mut self.zeros);
+            if byte == 0x80 && zeros >= SYNC_ZEROS {
+                return Some(ItmEvent::Sync);
+            }
+        }
+        self.packet.push(byte);
+        let header = self.packet[0];
+        let len = self.packet.len();
+        let complete = match packet_len(header) {
+            Some(expected) => len == expected,
+            // continuation bit in bit 7 of every byte but the last
+            None => len > 1 && byte & 0x80 == 0 || len == MAX_CONTINUATION_LEN,
+        };
+        if !complete {
+            return None;
+        }
+        let packet = std::mem::take(&mut self.packet);
+        Some(self.decode(&packet))
+    }
+
+    fn decode(&mut self, packet: &[u8]) -> ItmEvent {
+        let header = packet[0];
+        let payload = &packet[1..];
+        if header & 0x03 != 0 {
+            return decode_source(header, payload);
+        }
+        match header {
+            OVERFLOW => ItmEvent::Overflow,
+            GTS1 => {
+                // bits 25:0, those which are not sent are unchanged
+                let mut value = self.global_timestamp;
+                for (n, &byte) in payload.iter().enumerate().take(4) {
+                    let mask: u8 = if n == 3 { 0x1F } else { 0x7F };
+                    value = value & !((mask as u64) << (7 * n)) | ((byte & mask) as u64) << (7 * n);
+                }
+                self.global_timestamp = value;
+                let last = payload.get(3).copied().unwrap_or(0);
+                ItmEvent::GlobalTimestamp { value, wrapped: last & 0x40 != 0, clock_changed: last & 0x20 != 0 }
+            }
+            GTS2 => {
+                let high = continuation_value(payload);
+                self.global_timestamp = (self.global_timestamp & 0x03FF_FFFF) | high << 26;
+                ItmEvent::GlobalTimestamp { value: self.global_timestamp, wrapped: false, clock_changed: false }
+            }
+            h if h & 0x0F == 0 && h & 0x80 == 0 => {
+                ItmEvent::LocalTimestamp { delta: ((h >> 4) & 0x7) as u32, quality: TimestampQuality::Sync }
+            }
+            h if h & 0xCF == 0xC0 => {
+                let quality = match (h >> 4) & 0x3 {
+                    0 => TimestampQuality::Sync,
+                    1 => TimestampQuality::TimestampDelayed,
+                    2 => TimestampQuality::PacketDelayed,
+                    _ => TimestampQuality::BothDelayed,
+                };
+                ItmEvent::LocalTimestamp { delta: continuation_value(payload) as u32, quality }
+            }
+            h if h & 0x0B == 0x08 => {
+                // EX[2:0] in the header, more bits in the payload
+                let page = ((h >> 4) & 0x7) as u32 | (continuation_value(payload) as u32) << 3;
+                ItmEvent::Extension { page }
+            }
+            _ => ItmEvent::Unknown(packet.to_vec()),
+        }
+    }
+}
+
+/// Length of a packet starting with `header`, or `None` if it ends at the first byte
+/// without a continuation bit.
+fn packet_len(header: u8) -> Option<usize> {
+    match header & 0x03 {
+        0b01 => Some(2),
+        0b10 => Some(3),
+        0b11 => Some(5),
+        // overflow, short local timestamps and headers without continuation
+        _ if header & 0x80 == 0 => Some(1),
+        _ => None,
+    }
+}
+
+/// 7 bits from every byte, least significant first.
+fn continuation_value(payload: &[u8]) -> u64 {
+    payload.iter().enumerate().fold(0, |value, (n, &byte)| value | ((byte & 0x7F) as u64) << (7 * n))
+}
+
+/// Instrumentation packets and the hardware source packets of the DWT.
+fn decode_source(header: u8, payload: &[u8]) -> ItmEvent {
+    let id = header >> 3;
+    let value = payload.iter().rev().fold(0, |value, &byte| value << 8 | byte as u32);
+    if header & 0x04 == 0 {
+        return ItmEvent::Instrumentation { port: id, data: payload.to_vec() };
+    }
+    match (id, payload.len()) {
+        (0, 1) => ItmEvent::EventCounter(payload[0]),
+        (1, 2) => {
+            let action = match (payload[1] >> 4) & 0x3 {
+                1 => ExceptionAction::Entered,
+                2 => ExceptionAction::Exited,
+                3 => ExceptionAction::Returned,
+                _ => return ItmEvent::Unknown([&[header], payload].concat()),
+            };
+            ItmEvent::Exception { number: (value & 0x1FF) as u16, action }
+        }
+        (2, 4) => ItmEvent::PcSample(Some(value)),
+        (2, 1) => ItmEvent::PcSample(None),
+        (8..=15, 4) if id & 1 == 0 => ItmEvent::DataTracePc { comparator: (id >> 1) & 0x3, pc: value },
+        (8..=15, 2) if id & 1 == 1 => ItmEvent::DataTraceAddress { comparator: (id >> 1) & 0x3, address: value as u16 },
+        (16..=23, size) => ItmEvent::DataTraceValue {
+            comparator: (id >> 1) & 0x3,
+            write: id & 1 != 0,
+            value,
+            size: size as u8,
+        },
+        _ => ItmEvent::Unknown([&[header], payload].concat()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sync_and_overflow() {
+        let mut decoder = ItmDecoder::new();
+        assert_eq!(decoder.feed(&[0, 0, 0, 0, 0, 0x80, OVERFLOW]), [ItmEvent::Sync, ItmEvent::Overflow]);
+        // too few zeros
+        assert_eq!(decoder.feed(&[0, 0, 0x80]), []);
+    }
+
+    #[test]
+    fn instrumentation() {
+        let mut decoder = ItmDecoder::new();
+        let events = decoder.feed(&[0x01, b'A', 0x0A, b'B', b'C', 0x0B, 1, 2, 3, 4]);
+        assert_eq!(
+            events,
+            [
+                ItmEvent::Instrumentation { port: 0, data: b"A".to_vec() },
+                ItmEvent::Instrumentation { port: 1, data: b"BC".to_vec() },
+                ItmEvent::Instrumentation { port: 1, data: vec![1, 2, 3, 4] },
+            ]
+        );
+    }
+
+    #[test]
+    fn packet_split_between_chunks() {
+        let mut decoder = ItmDecoder::new();
+        assert_eq!(decoder.feed(&[0x03, b'H', b'e']), []);
+        assert_eq!(decoder.feed(b"l"), []);
+        assert_eq!(decoder.feed(&[b'l', 0x01]), [ItmEvent::Instrumentation { port: 0, data: b"Hell".to_vec() }]);
+        assert_eq!(decoder.feed(b"o"), [ItmEvent::Instrumentation { port: 0, data: b"o".to_vec() }]);
+    }
+
+    #[test]
+    fn hardware_sources() {
+        let mut decoder = ItmDecoder::new();
+        let events = decoder.feed(&[0x0E, 0x0F, 0x10, 0x17, 0x00, 0x10, 0x00, 0x08, 0x15, 0x00]);
+        assert_eq!(
+            events,
+            [
+                ItmEvent::Exception { number: 15, action: ExceptionAction::Entered },
+                ItmEvent::PcSample(Some(0x0800_1000)),
+                ItmEvent::PcSample(None),
+            ]
+        );
+    }
+
+    #[test]
+    fn timestamps() {
+        let mut decoder = ItmDecoder::new();
+        let events = decoder.feed(&[0x30, 0xE0, 0x81, 0x01, 0x94, 0x85, 0x01, 0xB4, 0x02]);
+        assert_eq!(
+            events,
+            [
+                ItmEvent::LocalTimestamp { delta: 3, quality: TimestampQuality::Sync },
+                ItmEvent::LocalTimestamp { delta: 0x81, quality: TimestampQuality::PacketDelayed },
+                ItmEvent::GlobalTimestamp { value: 0x85, wrapped: false, clock_changed: false },
+                ItmEvent::GlobalTimestamp { value: 0x85 | 2 << 26, wrapped: false, clock_changed: false },
+            ]
+        );
+    }
+}
@@ -0,0 +1,464 @@
+use std::fmt;
+
+use crate::batch::run_sequences;
+use crate::command::{Command, JtagSequence, Response, MAX_SEQUENCE_BITS};
+use crate::error::DapError;
+use crate::session::DapSession;
+use crate::transport::DapTransport;
+
+/// Upper bound for the number of devices and for the total IR length of a scan chain.
+const MAX_CHAIN_BITS: usize = 256;
+
+/// JEP106 manufacturer codes (continuation count << 7 | ID) found in IDCODEs.
+const MANUFACTURERS: [(u16, &str); 11] = [
+    (0x009, "Intel"),
+    (0x015, "NXP"),
+    (0x017, "Texas Instruments"),
+    (0x01F, "Atmel"),
+    (0x020, "STMicroelectronics"),
+    (0x021, "Lattice"),
+    (0x029, "Microchip"),
+    (0x049, "Xilinx"),
+    (0x06E, "Altera"),
+    (0x23B, "ARM"),
+    (0x489, "SiFive"),
+];
+
+/// Switch an SWJ-DP from SWD to JTAG: line reset, 0xE73C, then Test-Logic-Reset.
+pub fn swd_to_jtag_sequence() -> Command {
+    Command::swj_sequence(80, &[0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0x3C, 0xE7, 0xFF])
+}
+
+/// Value of bit `n` of an LSB first bit string.
+pub fn get_bit(data: &[u8], n: usize) -> bool {
+    data.get(n / 8).is_some_and(|byte| byte & (1 << (n % 8)) != 0)
+}
+
+/// Set bit `n` of an LSB first bit string, growing it as needed.
+pub fn set_bit(data: &mut Vec<u8>, n: usize, value: bool) {
+    if data.len() <= n / 8 {
+        data.resize(n / 8 + 1, 0);
+    }
+    if value {
+        data[n / 8] |= 1 << (n % 8);
+    } else {
+        data[n / 8] &= !(1 << (n % 8));
+    }
+}
+
+/// `len` bits of `data` starting at bit `start`.
+pub fn get_bits(data: &[u8], start: usize, len: usize) -> Vec<u8> {
+    let mut bits = vec![0; len.div_ceil(8)];
+    for n in 0..len {
+        set_bit(&mut bits, n, get_bit(data, start + n));
+    }
+    bits
+}
+
+/// JTAG TAP controller states.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TapState {
+    TestLogicReset,
+    RunTestIdle,
+    SelectDr,
+    CaptureDr,
+    ShiftDr,
+    Exit1Dr,
+    PauseDr,
+    Exit2Dr,
+    UpdateDr,
+    SelectIr,
+    CaptureIr,
+    ShiftIr,
+    Exit1Ir,
+    PauseIr,
+    Exit2Ir,
+    UpdateIr,
+}
+
+impl TapState {
+    /// State after one TCK cycle with `tms`.
+    pub fn next(self, tms: bool) -> Self {
+        use TapState::*;
+        match (self, tms) {
+            (TestLogicReset, false) | (RunTestIdle, false) | (UpdateDr, false) | (UpdateIr, false) => RunTestIdle,
+            (TestLogicReset, true) | (SelectIr, true) => TestLogicReset,
+            (RunTestIdle, true) | (UpdateDr, true) | (UpdateIr, true) => SelectDr,
+            (SelectDr, false) => CaptureDr,
+            (SelectDr, true) => SelectIr,
+            (CaptureDr, false) | (ShiftDr, false) | (Exit2Dr, false) => ShiftDr,
+            (CaptureDr, true) | (ShiftDr, true) => Exit1Dr,
+            (Exit1Dr, false) | (PauseDr, false) => PauseDr,
+            (Exit1Dr, true) | (Exit2Dr, true) => UpdateDr,
+            (PauseDr, true) => Exit2Dr,
+            (SelectIr, false) => CaptureIr,
+            (CaptureIr, false) | (ShiftIr, false) | (Exit2Ir, false) => ShiftIr,
+            (CaptureIr, true) | (ShiftIr, true) => Exit1Ir,
+            (Exit1Ir, false) | (PauseIr, false) => PauseIr,
+            (Exit1Ir, true) | (Exit2Ir, true) => UpdateIr,
+            (PauseIr, true) => Exit2Ir,
+        }
+    }
+
+    /// The shortest TMS sequence from this state to `to`.
+    pub fn path_to(self, to: TapState) -> Vec<bool> {
+        if to == TapState::TestLogicReset {
+            // works from anywhere, even if the current state is not known for sure
+            return vec![true; 5];
+        }
+        // breadth first search, the graph has 16 nodes
+        let mut paths: Vec<(TapState, Vec<bool>)> = vec![(self, Vec::new())];
+        let mut n = 0;
+        while let Some((state, path)) = paths.get(n).cloned() {
+            if state == to {
+                return path;
+            }
+            for &tms in &[false, true] {
+                let next = state.next(tms);
+                if paths.iter().all(|(seen, _)| *seen != next) {
+                    let mut path = path.clone();
+                    path.push(tms);
+                    paths.push((next, path));
+                }
+            }
+            n += 1;
+        }
+        unreachable!()
+    }
+
+    /// Whether the TAP can stay in this state while TCK runs.
+    pub fn is_stable(self) -> bool {
+        matches!(self, TapState::TestLogicReset | TapState::RunTestIdle | TapState::PauseDr | TapState::PauseIr)
+    }
+}
+
+/// A JTAG IDCODE split into its fields.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Idcode(pub u32);
+
+impl Idcode {
+    pub fn version(&self) -> u8 {
+        (self.0 >> 28) as u8
+    }
+
+    pub fn part(&self) -> u16 {
+        (self.0 >> 12) as u16
+    }
+
+    /// JEP106 manufacturer code, the continuation count in bits 10:7 and the ID in bits 6:0.
+    pub fn manufacturer(&self) -> u16 {
+        ((self.0 >> 1) & 0x7FF) as u16
+    }
+
+    pub fn manufacturer_name(&self) -> Option<&'static str> {
+        MANUFACTURERS.iter().find(|&&(code, _)| code == self.manufacturer()).map(|&(_, name)| name)
+    }
+
+    /// Whether this is the JTAG-DP of an ARM debug port.
+    pub fn is_arm_dp(&self) -> bool {
+        self.manufacturer() == 0x23B && self.part() & 0xFF00 == 0xBA00
+    }
+
+    /// IR length of the device, where the IDCODE tells.
+    pub fn ir_len(&self) -> Option<u8> {
+        if self.is_arm_dp() {
+            Some(4)
+        } else {
+            None
+        }
+    }
+}
+
+impl fmt::Display for Idcode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:#010X} (", self.0)?;
+        match self.manufacturer_name() {
+            Some(name) => write!(f, "{}", name)?,
+            None => write!(f, "manufacturer {:#05X}", self.manufacturer())?,
+        }
+        write!(f, ", part {:#06X}, version {})", self.part(), self.version())
+    }
+}
+
+/// A device found by [`Jtag::scan_chain`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ScannedTap {
+    /// `None` for a device which comes up with BYPASS selected.
+    pub idcode: Option<Idcode>,
+    pub ir_len: u8,
+}
+
+/// Raw access to the JTAG scan chain through DAP_JTAG_Sequence.
+///
+/// Every shift starts and ends in Run-Test/Idle, where [`Jtag::reset`] leaves the TAPs.
+pub struct Jtag<'a, T: DapTransport> {
+    session: &'a mut DapSession<T>,
+}
+
+impl<'a, T: DapTransport> Jtag<'a, T> {
+    pub fn new(session: &'a mut DapSession<T>) -> Self {
+        Jtag { session }
+    }
+
+    pub fn session(&mut self) -> &mut DapSession<T> {
+        self.session
+    }
+
+    /// Tell the probe the IR length of every device, index 0 nearest to TDO.
+    /// DAP_Transfer and DAP_JTAG_IDCODE address a device by this index.
+    pub fn configure(&mut self, ir_lengths: &[u8]) -> Result<(), DapError> {
+        self.session.command(&Command::JtagConfigure(ir_lengths.to_vec()))?;
+        Ok(())
+    }
+
+    /// IDCODE of the device at `index` of the configured chain.
+    pub fn idcode(&mut self, index: u8) -> Result<u32, DapError> {
+        match self.session.command(&Command::JtagIdcode(index))? {
+            Response::JtagIdcode(idcode) => Ok(idcode),
+            _ => unreachable!(),
+        }
+    }
+
+    /// Go to Test-Logic-Reset and then to Run-Test/Idle.
+    pub fn reset(&mut self) -> Result<(), DapError> {
+        self.sequences(&tms_sequences(&[true, true, true, true, true, false]))?;
+        Ok(())
+    }
+
+    /// Stay in Run-Test/Idle for `cycles` TCK cycles.
+    pub fn idle(&mut self, cycles: usize) -> Result<(), DapError> {
+        let sequences: Vec<JtagSequence> = (0..cycles)
+            .step_by(MAX_SEQUENCE_BITS)
+            .map(|start| JtagSequence::new((cycles - start).min(MAX_SEQUENCE_BITS) as u8, false, false, &[0; 8]))
+            .collect();
+        self.sequences(&sequences)?;
+        Ok(())
+    }
+
+    /// Find every device on the scan chain, index 0 nearest to TDO.
+    ///
+    /// Chain length and total IR length are measured by flushing the registers with
+    /// zeros and counting how long a one takes to come out. The captured IR values,
+    /// which start with 0b01 on every device, split the total into IR lengths.
+    /// Captured values with more 0b01 pairs in their upper bits are fine as long as
+    /// only one split fits the number of devices and the IR lengths known from their IDCODEs.
+    pub fn scan_chain(&mut self) -> Result<Vec<ScannedTap>, DapError> {
+        self.reset()?;
+        let mut flush = vec![0u8; MAX_CHAIN_BITS / 8];
+        flush.extend(vec![0xFF; MAX_CHAIN_BITS / 8]);
+
+        // this leaves ones, i.e. BYPASS, in every instruction register
+        let tdo_ir = self.shift_ir(&flush, 2 * MAX_CHAIN_BITS)?;
+        let ir_total = first_one(&tdo_ir, MAX_CHAIN_BITS).ok_or(DapError::ScanChain("no TDO response, or IR chain too long"))?;
+        let tdo_bypass = self.shift_dr(&flush, 2 * MAX_CHAIN_BITS)?;
+        let count = first_one(&tdo_bypass, MAX_CHAIN_BITS).ok_or(DapError::ScanChain("no TDO response, or too many devices"))?;
+        if count == 0 {
+            return Err(DapError::ScanChain("no devices"));
+        }
+
+        // after reset every device has IDCODE or BYPASS selected
+        self.reset()?;
+        let tdo = self.shift_dr(&vec![0xFF; count * 4], count * 32)?;
+        let mut idcodes = Vec::with_capacity(count);
+        let mut ptr = 0;
+        for _ in 0..count {
+            let idcode = if get_bit(&tdo, ptr) {
+                let bits = get_bits(&tdo, ptr, 32);
+                ptr += 32;
+                Some(Idcode(u32::from_le_bytes([bits[0], bits[1], bits[2], bits[3]])))
+            } else {
+                ptr += 1;
+                None
+            };
+            idcodes.push(idcode);
+        }
+
+        let known: Vec<Option<u8>> = idcodes.iter().map(|idcode| idcode.and_then(|idcode| idcode.ir_len())).collect();
+        let ir_lengths = split_ir(&tdo_ir, ir_total, &known)?;
+        let taps: Vec<ScannedTap> =
+            idcodes.into_iter().zip(ir_lengths).map(|(idcode, ir_len)| ScannedTap { idcode, ir_len }).collect();
+        for (n, tap) in taps.iter().enumerate() {
+            log::debug!("TAP {}: IR length {}, IDCODE {:?}", n, tap.ir_len, tap.idcode);
+        }
+        Ok(taps)
+    }
+
+    /// Shift `bits` bits of `tdi` through the instruction registers and return what came out of TDO.
+    pub fn shift_ir(&mut self, tdi: &[u8], bits: usize) -> Result<Vec<u8>, DapError> {
+        self.shift(&[true, true, false, false], tdi, bits)
+    }
+
+    /// Shift `bits` bits of `tdi` through the selected data registers and return what came out of TDO.
+    pub fn shift_dr(&mut self, tdi: &[u8], bits: usize) -> Result<Vec<u8>, DapError> {
+        self.shift(&[true, false, false], tdi, bits)
+    }
+
+    fn shift(&mut self, path: &[bool], tdi: &[u8], bits: usize) -> Result<Vec<u8>, DapError> {
+        if bits == 0 {
+            return Ok(Vec::new());
+        }
+        let mut sequences = tms_sequences(path);
+        sequences.extend(shift_sequences(tdi, bits, true));
+        // Update-xR, Run-Test/Idle
+        sequences.extend(tms_sequences(&[true, false]));
+        self.sequences(&sequences)
+    }
+
+    /// Run `sequences` and return the captured TDO bits as one LSB first bit string.
+    pub fn sequences(&mut self, sequences: &[JtagSequence]) -> Result<Vec<u8>, DapError> {
+        run_sequences(self.session, sequences)
+    }
+}
+
+/// Position of the first one at or after bit `start`, relative to `start`.
+fn first_one(data: &[u8], start: usize) -> Option<usize> {
+    (start..data.len() * 8).find(|&n| get_bit(data, n)).map(|n| n - start)
+}
+
+/// Split `ir_total` captured IR bits into the IR lengths of one device per entry of
+/// `known`, which holds the IR lengths that are known in advance.
+///
+/// Every device captures 0b01 in its two lowest bits, so each starts at such a pair.
+/// The split is only accepted if no other one fits as well.
+fn split_ir(tdo: &[u8], ir_total: usize, known: &[Option<u8>]) -> Result<Vec<u8>, DapError> {
+    let count = known.len();
+    let starts: Vec<usize> = (0..ir_total.saturating_sub(1)).filter(|&n| get_bit(tdo, n) && !get_bit(tdo, n + 1)).collect();
+    let fits = |device: usize, len: usize| len >= 2 && known[device].is_none_or(|known| known as usize == len);
+    // ways[d][i]: splits of the bits from starts[i] on into devices d and later, counting up to 2
+    let mut ways = vec![vec![0u8; starts.len()]; count];
+    for device in (0..count).rev() {
+        for i in 0..starts.len() {
+            ways[device][i] = if device + 1 == count {
+                fits(device, ir_total - starts[i]) as u8
+            } else {
+                (i + 1..starts.len())
+                    .filter(|&j| fits(device, starts[j] - starts[i]))
+                    .fold(0, |sum, j| (sum + ways[device + 1][j]).min(2))
+            };
+        }
+    }
+    match (starts.first(), ways[0].first()) {
+        (Some(0), Some(1)) => (),
+        (Some(0), Some(2)) => return Err(DapError::ScanChain("IR lengths are ambiguous")),
+        _ => return Err(DapError::ScanChain("captured IR values do not fit the devices found")),
+    }
+    // follow the only split from the first bit
+    let mut ir_lengths = Vec::with_capacity(count);
+    let mut i = 0;
+    for device in 0..count - 1 {
+        let next = (i + 1..starts.len()).find(|&j| fits(device, starts[j] - starts[i]) && ways[device + 1][j] > 0).unwrap();
+        ir_lengths.push((starts[next] - starts[i]) as u8);
+        i = next;
+    }
+    ir_lengths.push((ir_total - starts[i]) as u8);
+    Ok(ir_lengths)
+}
+
+/// One TCK cycle per TMS value, without capture.
+pub fn tms_sequences(tms: &[bool]) -> Vec<JtagSequence> {
+    tms.iter().map(|&tms| JtagSequence::new(1, tms, false, &[0xFF])).collect()
+}
+
+/// Shift `bits` bits of `tdi` with TDO captured, starting in Shift-xR.
+/// With `exit` the last bit is shifted while moving on to Exit1-xR.
+pub fn shift_sequences(tdi: &[u8], bits: usize, exit: bool) -> Vec<JtagSequence> {
+    let stay = if exit { bits.saturating_sub(1) } else { bits };
+    let mut sequences: Vec<JtagSequence> = (0..stay)
+        .step_by(MAX_SEQUENCE_BITS)
+        .map(|start| {
+            let len = (stay - start).min(MAX_SEQUENCE_BITS);
+            JtagSequence::new(len as u8, false, true, &get_bits(tdi, start, len))
+        })
+        .collect();
+    if exit && bits > 0 {
+        sequences.push(JtagSequence::new(1, true, true, &get_bits(tdi, bits - 1, 1)));
+    }
+    sequences
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::command::DAP_PORT_JTAG;
+    use crate::simulator::{JtagChain, JtagTap, SimulatedProbe};
+
+    fn connect(taps: Vec<JtagTap>) -> DapSession<SimulatedProbe> {
+        let mut probe = SimulatedProbe::new();
+        probe.jtag = JtagChain::new(taps);
+        let mut session = DapSession::open(probe).unwrap();
+        session.execute_commands(vec![Command::Connect(DAP_PORT_JTAG), swd_to_jtag_sequence()]).unwrap();
+        session
+    }
+
+    #[test]
+    fn scan_chain() {
+        // an FPGA, the DP of a Cortex-M and a device without IDCODE, nearest to TDO first
+        let mut session = connect(vec![
+            JtagTap::new(6, Some(0x0362_D093), 0x09),
+            JtagTap::arm_dp(0x4BA0_0477),
+            JtagTap::new(3, None, 0),
+        ]);
+        let mut jtag = Jtag::new(&mut session);
+        let taps = jtag.scan_chain().unwrap();
+        assert_eq!(
+            taps,
+            [
+                ScannedTap { idcode: Some(Idcode(0x0362_D093)), ir_len: 6 },
+                ScannedTap { idcode: Some(Idcode(0x4BA0_0477)), ir_len: 4 },
+                ScannedTap { idcode: None, ir_len: 3 },
+            ]
+        );
+
+        // DAP_JTAG_IDCODE uses the IDCODE instruction of an ARM DP
+        jtag.configure(&[6, 4, 3]).unwrap();
+        assert_eq!(jtag.idcode(1).unwrap(), 0x4BA0_0477);
+    }
+
+    #[test]
+    fn scan_long_chain() {
+        let taps = (0..8).map(|n| JtagTap::new(5, Some(0x1000_0001 | n << 12), 1)).collect();
+        let mut session = connect(taps);
+        let taps = Jtag::new(&mut session).scan_chain().unwrap();
+        assert_eq!(taps.len(), 8);
+        assert!(taps.iter().enumerate().all(|(n, tap)| tap.ir_len == 5 && tap.idcode == Some(Idcode(0x1000_0001 | (n as u32) << 12))));
+    }
+
+    /// A TAP which captures `ir_capture` in its instruction register.
+    fn tap(ir_len: u8, idcode: Option<u32>, ir_capture: u32) -> JtagTap {
+        let mut tap = JtagTap::new(ir_len, idcode, 0x09);
+        tap.ir_capture = ir_capture;
+        tap
+    }
+
+    fn ir_lengths(taps: Vec<JtagTap>) -> Result<Vec<u8>, DapError> {
+        let mut session = connect(taps);
+        let taps = Jtag::new(&mut session).scan_chain()?;
+        Ok(taps.iter().map(|tap| tap.ir_len).collect())
+    }
+
+    #[test]
+    fn scan_realistic_ir_captures() {
+        // a Xilinx 7-series FPGA captures XXXX01, here with DONE and INIT set: 0b010101
+        let fpga = || tap(6, Some(0x0362_D093), 0b01_0101);
+        assert_eq!(ir_lengths(vec![fpga()]).unwrap(), [6]);
+        // the IR length of the ARM DP pins down where the FPGA starts
+        assert_eq!(ir_lengths(vec![JtagTap::arm_dp(0x4BA0_0477), fpga()]).unwrap(), [4, 6]);
+        // a captured one in the last bit of the chain does not start a device
+        let status = || tap(5, None, 0b1_0001);
+        assert_eq!(ir_lengths(vec![JtagTap::arm_dp(0x4BA0_0477), status()]).unwrap(), [4, 5]);
+        assert_eq!(ir_lengths(vec![status(), tap(4, None, 0b0001)]).unwrap(), [5, 4]);
+    }
+
+    #[test]
+    fn scan_ambiguous_ir_captures() {
+        // 0b0101 followed by 0b010101 could be split after 2, 4, 6 or 8 bits
+        let result = ir_lengths(vec![tap(4, None, 0b0101), tap(6, None, 0b01_0101)]);
+        assert!(matches!(result, Err(DapError::ScanChain("IR lengths are ambiguous"))), "{:?}", result);
+    }
+
+    #[test]
+    fn scan_empty_chain() {
+        let mut session = connect(Vec::new());
+        assert!(matches!(Jtag::new(&mut session).scan_chain(), Err(DapError::ScanChain(_))));
+    }
+}
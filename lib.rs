@@ -0,0 +1,46 @@
+//! CMSIS-DAP debug probe access over rusb.
+//!
+//! The layers, from the bottom up:
+//!
+//! * [`probe`] finds CMSIS-DAP probes and opens one as a [`transport::DapTransport`]
+//! * [`command`] encodes DAP commands and decodes their responses
+//! * [`session`] and [`batch`] exchange commands with an opened probe
+//! * [`info`] reads what the probe reports about itself
+//! * [`jtag`] shifts raw JTAG sequences
+//! * [`svf`] and [`xsvf`] play SVF and XSVF files through [`jtag`]
+//! * [`bsdl`] and [`boundary`] sample and drive pins by name with boundary scan
+//! * [`swd`] sends raw SWD sequences, e.g. to wake a dormant debug port or select one on a multi-drop bus
+//! * [`dp`] accesses debug port and access port registers, over SWD or a JTAG TAP
+//! * [`memory`] reads and writes target memory through a MEM-AP
+//! * [`target`] holds clock and transfer retry settings for kinds of targets
+//! * [`swo`] configures SWO and reads the trace data as a byte stream
+//! * [`itm`] sets up ITM and DWT trace on the target and decodes its packets
+//! * [`uart`] connects to the target console through the probe's UART
+//!
+//! [`simulator`] provides a probe and target in software.
+#![allow(non_upper_case_globals)]
+
+pub mod transport;
+pub mod simulator;
+pub mod session;
+pub mod command;
+pub mod batch;
+pub mod probe;
+pub mod error;
+pub mod swd;
+pub mod dp;
+pub mod memory;
+pub mod info;
+pub mod jtag;
+pub mod svf;
+pub mod xsvf;
+pub mod bsdl;
+pub mod boundary;
+pub mod swo;
+pub mod itm;
+pub mod uart;
+pub mod target;
+
+pub use error::{DapError, ProbeCreationError};
+pub use probe::{ProbeFinder, ProbeInfo, ProbeSelector};
+pub use session::DapSession;
@@ -5,21 +5,30 @@ use rusb::{Context, DeviceHandle, Error, UsbContext, Device, request_type, Direc
 use rusb::{Language};
 
 use std::fmt;
+use std::io::{Read, Write};
+use std::sync::Arc;
 use std::time::Duration;
 use std::convert::TryInto;
 
-const USB_VID: u16 = 0x0D28;
-const USB_PID: u16 = 0x0204;
-
-fn is_cmsis_dap_device<T: UsbContext>(device: &Device<T>) -> bool {
-    // Check the VID/PID.
-    if let Ok(descriptor) = device.device_descriptor() {
-        (descriptor.vendor_id() == USB_VID)
-            && (descriptor.product_id() == USB_PID)
-    } else {
-        false
-    }
-}
+use rusb_cmsis_dap::transport::DapTransport;
+use rusb_cmsis_dap::simulator::{SimulatedProbe, SwdTarget};
+use rusb_cmsis_dap::session::DapSession;
+use rusb_cmsis_dap::command::*;
+use rusb_cmsis_dap::probe::{ProbeFinder, ProbeSelector};
+use rusb_cmsis_dap::error::DapError;
+use rusb_cmsis_dap::dp::{jtag_to_swd_sequence, swd_reset_sequence, DebugPort, DP_IDCODE};
+use rusb_cmsis_dap::info::DapInfo;
+use rusb_cmsis_dap::jtag::{swd_to_jtag_sequence, Jtag};
+use rusb_cmsis_dap::memory::{MemAp, AP_IDR};
+use rusb_cmsis_dap::svf::{parse_svf, Player, SvfError};
+use rusb_cmsis_dap::xsvf::parse_xsvf;
+use rusb_cmsis_dap::bsdl::{Bsdl, BsdlError};
+use rusb_cmsis_dap::boundary::BoundaryScan;
+use rusb_cmsis_dap::swd::{dormant_to_swd_sequences, swd_to_dormant_sequences, Swd, RP2040_CORE0, RP2040_CORE1};
+use rusb_cmsis_dap::swo::{Swo, SwoMode};
+use rusb_cmsis_dap::itm::{self, ItmConfig, ItmDecoder, ItmEvent};
+use rusb_cmsis_dap::uart::{UartConfig, UartStream};
+use rusb_cmsis_dap::target::{TargetProfile, PROFILES};
 
 fn main() {
     // pretty_env_logger::init();
@@ -47,516 +56,360 @@ fn main() {
 
     log::trace!("initialized logger");
 
-    match rusb_test() {
-        Ok(_) => println!("OK"),
-        e => println!("ERROR {:?}", e),
-    }
-}
-
-#[derive(thiserror::Error, Debug)]
-pub enum ProbeCreationError {
-    #[error("Probe was not found.")]
-    NotFound,
-    #[error("USB device could not be opened. Please check the permissions.")]
-    CouldNotOpen,
-    // #[error("{0}")]
-    // HidApi(#[from] hidapi::HidError),
-    #[error("{0}")]
-    Rusb(#[from] rusb::Error),
-    #[error("An error specific to a probe type occured: {0}")]
-    ProbeSpecific(#[source] Box<dyn std::error::Error + Send + Sync>),
-    #[error("{0}")]
-    Other(&'static str),
-}
+    let args: Vec<String> = std::env::args().skip(1).collect();
 
-fn dump_buf(buf: &[u8]) {
-    let len = buf.len();
-    // println!("len = {}", len);
-    for i in 0..len {
-        print!("{:02X}", buf[i]);
-        if i % 16 == 15 || i == len - 1 {
-            println!();
-        } else if i % 16 == 7 {
-            print!(",   ");
-        } else {
-            print!(", ");
+    // --allow VID:PID accepts a probe which does not call itself CMSIS-DAP
+    let mut finder = ProbeFinder::new();
+    for value in args.windows(2).filter(|pair| pair[0] == "--allow").map(|pair| &pair[1]) {
+        match value.parse() {
+            Ok(ProbeSelector::VidPid { vendor_id, product_id, .. }) => finder.allow(vendor_id, product_id),
+            _ => println!("ignoring --allow {}", value),
         }
     }
-}
-
 
-trait DeviceHandleEx {
-    fn read(&mut self, if_num: u8, in_ep: u8, buf: &mut [u8]) -> Result<usize, ProbeCreationError>;
-    fn write(&mut self, if_num: u8, out_ep: u8, buf: &[u8]) -> Result<usize, ProbeCreationError>;
-}
+    if args.iter().any(|arg| arg == "--list") {
+        match finder.list_probes() {
+            Ok(probes) => {
+                for (index, info) in probes.iter().enumerate() {
+                    println!("{}: {}", index, info);
+                }
+            }
+            e => println!("ERROR {:?}", e),
+        }
+        return;
+    }
 
-impl<T: UsbContext> DeviceHandleEx for DeviceHandle<T> {
-    fn read(&mut self, if_num: u8, in_ep: u8, buf: &mut [u8]) -> Result<usize, ProbeCreationError> {
-        let timeout = Duration::from_secs(5);
-        let len =
-            if in_ep == 0 {
-                // GET_REPORT
-                self.read_control(0xA1, 0x01, 0x0100, if_num as u16, buf, timeout)?
+    // --jtag connects through JTAG instead of SWD
+    let port = if args.iter().any(|arg| arg == "--jtag") { DAP_PORT_JTAG } else { DAP_PORT_SWD };
+    let sim = args.iter().any(|arg| arg == "--sim");
+    // --probe <index | serial | VID:PID[:SERIAL]>
+    let selector = args.iter()
+        .position(|arg| arg == "--probe")
+        .and_then(|n| args.get(n + 1))
+        .map(|s| s.parse())
+        .unwrap_or(Ok(ProbeSelector::Index(0)));
+
+    // --svf FILE or --xsvf FILE plays the file through JTAG instead of the test sequence
+    let player_file = args.windows(2).find(|pair| pair[0] == "--svf" || pair[0] == "--xsvf");
+    if let Some(pair) = player_file {
+        let xsvf = pair[0] == "--xsvf";
+        let data = match std::fs::read(&pair[1]) {
+            Ok(data) => data,
+            Err(e) => {
+                println!("ERROR {}: {}", pair[1], e);
+                return;
+            }
+        };
+        let result =
+            if sim {
+                run_player(SimulatedProbe::new(), &data, xsvf)
             } else {
-                // maybe it's better to use read_interrput() for HID interface. but it works.
-                self.read_bulk(in_ep, buf, timeout)?
+                selector.map_err(DapError::from)
+                    .and_then(|selector| Ok(finder.open_probe(&selector)?))
+                    .map_err(SvfError::from)
+                    .and_then(|transport| run_player(transport, &data, xsvf))
             };
-        Ok(len)
+        match result {
+            Ok(_) => println!("OK"),
+            Err(e) => println!("ERROR {}", e),
+        }
+        return;
     }
-    fn write(&mut self, if_num: u8, out_ep: u8, buf: &[u8]) -> Result<usize, ProbeCreationError> {
-        let timeout = Duration::from_secs(5);
-        let len =
-            if  out_ep == 0 {
-                // SET_REPORT
-                self.write_control(0x21, 0x09, 0x0200, if_num as u16, &buf, timeout)?
+
+    // --bsdl FILE samples every pin of the device the BSDL file is for
+    if let Some(pair) = args.windows(2).find(|pair| pair[0] == "--bsdl") {
+        let bsdl = match std::fs::read_to_string(&pair[1]).map(|text| Bsdl::parse(&text)) {
+            Ok(Ok(bsdl)) => bsdl,
+            Ok(Err(e)) => {
+                println!("ERROR {}: {}", pair[1], e);
+                return;
+            }
+            Err(e) => {
+                println!("ERROR {}: {}", pair[1], e);
+                return;
+            }
+        };
+        let result =
+            if sim {
+                run_sample(SimulatedProbe::new(), bsdl)
             } else {
-                // maybe it's better to use write_interrput() for HID interface. but it works.
-                self.write_bulk(out_ep, &buf, timeout)?
+                selector.map_err(DapError::from)
+                    .and_then(|selector| Ok(finder.open_probe(&selector)?))
+                    .map_err(BsdlError::from)
+                    .and_then(|transport| run_sample(transport, bsdl))
             };
-        Ok(len)
+        match result {
+            Ok(_) => println!("OK"),
+            Err(e) => println!("ERROR {}", e),
+        }
+        return;
     }
-}
-
-fn rusb_test() -> Result<(), ProbeCreationError> {
-
-    let context = Context::new()?;
-    
-    let device = context
-        .devices()?
-        .iter()
-        .filter(is_cmsis_dap_device)
-        .find_map(|device| {
-            Some(device)
-        })
-        .map_or(Err(ProbeCreationError::NotFound), Ok)?;
-    
-    let mut device_handle = device.open()?;
 
-    log::debug!("Aquired handle for probe");
-
-    let config = device.active_config_descriptor()?;
-
-    log::debug!("Active config descriptor: {:?}", &config);    
-
-
-    let descriptor = device.device_descriptor()?;
-
-    log::debug!("Device descriptor: {:?}", &descriptor);
-
-    {
-        for interface in config.interfaces() {
-            for interface_desc in interface.descriptors() {
-                log::debug!("Interface Desc: {:?}", interface_desc);
-                let languages = device_handle.read_languages(Duration::from_secs(2)).unwrap();
-                for lang in languages {
-                    log::debug!("Lang: {:?} {:#06X}", lang, lang.lang_id());
-                    let if_str = device_handle.read_interface_string(lang, &interface_desc, Duration::from_secs(2)).unwrap();
-                    log::debug!("Interface String: {}", if_str);
-                }
-                if let Some(n) = interface_desc.description_string_index() {
-                    log::debug!("string[{}] = {}", n, device_handle.read_string_descriptor_ascii(n).unwrap())
-                }
+    // --uart BAUD connects stdin and stdout, or with --pty a pseudo terminal, to the target UART
+    if let Some(pair) = args.windows(2).find(|pair| pair[0] == "--uart") {
+        let baudrate = match pair[1].parse::<u32>() {
+            Ok(baudrate) => baudrate,
+            Err(e) => {
+                println!("ERROR --uart: {}", e);
+                return;
             }
+        };
+        let pty = args.iter().any(|arg| arg == "--pty");
+        let result =
+            if sim {
+                run_console(SimulatedProbe::new(), baudrate, pty)
+            } else {
+                selector.map_err(DapError::from)
+                    .and_then(|selector| Ok(finder.open_probe(&selector)?))
+                    .map_err(std::io::Error::other)
+                    .and_then(|transport| run_console(transport, baudrate, pty))
+            };
+        match result {
+            Ok(_) => println!("OK"),
+            Err(e) => println!("ERROR {}", e),
         }
+        return;
     }
 
-    // device_handle.unconfigure();
-    // device_handle.set_active_configuration(1);
-    let languages = device_handle.read_languages(Duration::from_secs(2)).unwrap();
-    for lang in languages {
-        log::debug!("Lang: {:?} {:#06X}", lang, lang.lang_id());
-    }
-
-    log::debug!("string[1] = {}", device_handle.read_string_descriptor_ascii(1).unwrap());
-    log::debug!("string[2] = {}", device_handle.read_string_descriptor_ascii(2).unwrap());
-    log::debug!("string[3] = {}", device_handle.read_string_descriptor_ascii(3).unwrap());
-
-    // device_handle.set_alternate_setting(0, 0)?;
-
-    // log::debug!("Done set interface alternate setting of interface 0.");
-
-    // let out_ep = 0x01;
-    // let in_ep = 0x81;
-
-    let use_hid_out_ep = false;
-    let use_cmsis_dap_v2 = true;
-    let (if_num, out_ep, in_ep) =
-    {
-        let mut if_num = 0;
-        let mut out_ep = 0;
-        let mut in_ep = 0;
-        // Search CMSIS-DAP v1 interface
-        for interface in config.interfaces() {
-            if let Some(descriptor) = interface.descriptors().next() {
-                if let Some(string_index) = descriptor.description_string_index() {
-                    let interface_string = device_handle.read_string_descriptor_ascii(string_index).unwrap();
-                    println!("interface {} : {}", interface.number(), interface_string);
-                    // if interface_string.starts_with("CMSIS-DAP v1") || interface_string.starts_with("CMSIS-DAP-v1"){
-                    let cc_sub_prot = (descriptor.class_code(), descriptor.sub_class_code(), descriptor.protocol_code());
-                    if cc_sub_prot == (0x03, 0x00, 0x00) {
-                        if_num = interface.number();
-                        for endpoint in descriptor.endpoint_descriptors() {
-                            println!("interface {} ep {:#04X}", interface.number(), endpoint.address());
-                            let ep = endpoint.address();
-                            if ep & 0x80 != 0 {
-                                in_ep = ep;
-                            } else if use_hid_out_ep {
-                                out_ep = ep;
-                            }
-                        }
-                    }
-                }
-            }
+    // --swo BAUD captures SWO in UART mode after the test sequence, until interrupted
+    let swo = args.windows(2).find(|pair| pair[0] == "--swo").map(|pair| pair[1].parse::<u32>());
+    let swo = match swo.transpose() {
+        Ok(swo) => swo,
+        Err(e) => {
+            println!("ERROR --swo: {}", e);
+            return;
         }
-        // Search CMISIS-DAP v2 interface and override with it
-        for interface in config.interfaces() {
-            if let Some(descriptor) = interface.descriptors().next() {
-                if let Some(string_index) = descriptor.description_string_index() {
-                    let interface_string = device_handle.read_string_descriptor_ascii(string_index).unwrap();
-                    // println!("interface {} : {}", interface.number(), interface_string);
-                    if interface_string.starts_with("CMSIS-DAP v2") && use_cmsis_dap_v2 {
-                        if_num = interface.number();
-                        for endpoint in descriptor.endpoint_descriptors() {
-                            println!("interface {} ep {:#04X}", interface.number(), endpoint.address());
-                            let ep = endpoint.address();
-                            if ep & 0x80 != 0 {
-                                in_ep = ep;
-                            } else {
-                                out_ep = ep;
-                            }
-                        }
-                        if in_ep == 0 || out_ep == 0 {
-                            in_ep = 0;
-                            out_ep = 0;
-                            if_num = 0;
-                        }
-                    }
-                }
-            }
+    };
+    // --trace-clock HZ also sets up the TPIU, ITM and DWT of the target for SWO
+    let trace_clock = args.windows(2).find(|pair| pair[0] == "--trace-clock").map(|pair| pair[1].parse::<u32>());
+    let trace_clock = match trace_clock.transpose() {
+        Ok(trace_clock) => trace_clock,
+        Err(e) => {
+            println!("ERROR --trace-clock: {}", e);
+            return;
         }
-        (if_num, out_ep, in_ep)
     };
-    log::debug!("if_num = {}", if_num);
-    log::debug!("out_ep = {:#04X}", out_ep);
-    log::debug!("in_ep = {:#04X}", in_ep);
-    device_handle.claim_interface(if_num)?;
-    log::debug!("Claimed interface {} of USB device.", if_num);
 
-    // device_handle.clear_halt(0x01);
-    // device_handle.clear_halt(0x81);
+    // --target NAME picks the SWJ clock and transfer retries for the kind of target
+    let profile = match args.windows(2).find(|pair| pair[0] == "--target") {
+        Some(pair) => match TargetProfile::by_name(&pair[1]) {
+            Some(profile) => *profile,
+            None => {
+                let names: Vec<&str> = PROFILES.iter().map(|profile| profile.name).collect();
+                println!("ERROR --target: {} is none of {}", pair[1], names.join(", "));
+                return;
+            }
+        },
+        None => TargetProfile::default(),
+    };
 
-    const ID_DAP_Info: u8 = 0x00;
-    const ID_DAP_Connect: u8 = 0x02;
-    const ID_DAP_Transfer: u8 = 0x05;
-    const ID_DAP_SWJ_Clock: u8 = 0x11;
-    const ID_DAP_SWJ_Sequence: u8 = 0x12;
-    const ID_DAP_QueueCommands: u8 = 0x7E;
-    const ID_DAP_ExecuteCommands: u8 = 0x7F;
-
-    // ID_DAP_Info
-    const DAP_ID_VENDOR: u8 = 0x01;
-    const DAP_ID_PRODUCT: u8 = 0x02;
-    const DAP_ID_SER_NUM: u8 = 0x03;
-    const DAP_ID_FW_VER: u8 = 0x04;
-    const DAP_ID_DEVICE_VENDOR: u8 = 0x05;
-    const DAP_ID_DEVICE_NAME: u8 = 0x06;
-    const DAP_ID_BOARD_VENDOR: u8 = 0x07;
-    const DAP_ID_BOARD_NAME: u8 = 0x08;
-    const DAP_ID_PRODUCT_FW_VER: u8 = 0x09;
-
-    // ID_DAP_Connect
-    const DAP_PORT_SWD: u8 = 0x01;
-    const DAP_PORT_JTAG: u8 = 0x02;
-
-    let mut cmds = Vec::new();
-    let mut checkers: Vec<Box<dyn Fn(&[u8]) -> usize>> = Vec::new();
-    // add_info_str(&mut cmds, &mut checkers, DAP_ID_VENDOR);
-    add_info_str(&mut cmds, &mut checkers, DAP_ID_PRODUCT);
-    // add_info_str(&mut cmds, &mut checkers, DAP_ID_SER_NUM);
-    add_info_str(&mut cmds, &mut checkers, DAP_ID_FW_VER);
-    // add_info_str(&mut cmds, &mut checkers, DAP_ID_DEVICE_VENDOR);
-    // add_info_str(&mut cmds, &mut checkers, DAP_ID_DEVICE_NAME);
-    // add_info_str(&mut cmds, &mut checkers, DAP_ID_BOARD_VENDOR);
-    // add_info_str(&mut cmds, &mut checkers, DAP_ID_BOARD_NAME);
-    add_info_str(&mut cmds, &mut checkers, DAP_ID_PRODUCT_FW_VER);
-
-    let mut buf = [0u8; 64];
-    buf[0] = ID_DAP_ExecuteCommands;
-    buf[1] = checkers.len() as u8;
-    assert!(cmds.len() <= 64 - 2);
-    buf[2..(2+cmds.len())].copy_from_slice(cmds.as_ref());
-    let len = device_handle.write(if_num, out_ep, &buf)?;
-    println!("write len = {}", len);
+    // --targetsel HEX[,HEX...] connects to the first of these DPs which answers on a multi-drop SWD bus
+    let targetsel = args.windows(2).find(|pair| pair[0] == "--targetsel").map(|pair| {
+        pair[1].split(',').map(|value| u32::from_str_radix(value.trim_start_matches("0x"), 16)).collect::<Result<Vec<_>, _>>()
+    });
+    let targetsel = match targetsel.transpose() {
+        Ok(targetsel) => targetsel,
+        Err(e) => {
+            println!("ERROR --targetsel: {}", e);
+            return;
+        }
+    };
 
-/***/
+    // --sim runs the same sequence against a simulated probe, no hardware needed.
+    let result =
+        if sim {
+            let mut probe = SimulatedProbe::new();
+            if targetsel.is_some() {
+                // the two cores of an RP2040
+                probe.target = SwdTarget::multidrop(RP2040_CORE0);
+                probe.multidrop.push(SwdTarget::multidrop(RP2040_CORE1));
+            }
+            run_test(probe, port, &profile, targetsel.as_deref(), swo, trace_clock)
+        } else {
+            selector.map_err(DapError::from).and_then(|selector| rusb_test(&finder, &selector, port, &profile, targetsel.as_deref(), swo, trace_clock))
+        };
 
-    let mut buf = [0u8; 64];
-    buf[0] = 0;
-    buf[1] = 0;
-    let len = device_handle.read(if_num, in_ep, &mut buf)?;
-    println!("read len = {}", len);
-    dump_buf(&buf[..len]);
-    assert!(buf[0] == ID_DAP_ExecuteCommands);
-    assert!(buf[1] == checkers.len() as u8);
-    let mut ptr = 2;
-    for checker in &checkers {
-        ptr = ptr + checker(&buf[ptr..]);
+    match result {
+        Ok(_) => println!("OK"),
+        e => println!("ERROR {:?}", e),
     }
-    assert!(ptr <= len);
-
-/***/
-
-    let mut cmds = Vec::new();
-    let mut checkers: Vec<Box<dyn Fn(&[u8]) -> usize>> = Vec::new();
-    // add_info_str(&mut cmds, &mut checkers, DAP_ID_VENDOR);
-    // add_info_str(&mut cmds, &mut checkers, DAP_ID_PRODUCT);
-    add_info_str(&mut cmds, &mut checkers, DAP_ID_SER_NUM);
-    // add_info_str(&mut cmds, &mut checkers, DAP_ID_FW_VER);
-    // add_info_str(&mut cmds, &mut checkers, DAP_ID_DEVICE_VENDOR);
-    // add_info_str(&mut cmds, &mut checkers, DAP_ID_DEVICE_NAME);
-    // add_info_str(&mut cmds, &mut checkers, DAP_ID_BOARD_VENDOR);
-    // add_info_str(&mut cmds, &mut checkers, DAP_ID_BOARD_NAME);
-    // add_info_str(&mut cmds, &mut checkers, DAP_ID_PRODUCT_FW_VER);
-    add_connect(&mut cmds, &mut checkers);
-    // add_set_clock(&mut cmds, &mut checkers, 0x00000100); // 256Hz
-    // add_set_clock(&mut cmds, &mut checkers, 0x00100000); // 1MHz
-    add_set_clock(&mut cmds, &mut checkers, 0x01000000); // 16MHz
-    add_jtag_to_swd_sequence(&mut cmds, &mut checkers);
-    add_swd_reset_sequence(&mut cmds, &mut checkers);
-
-    let mut buf = [0u8; 64];
-    buf[0] = ID_DAP_ExecuteCommands;
-    buf[1] = checkers.len() as u8;
-    assert!(cmds.len() <= 64 - 2);
-    buf[2..(2+cmds.len())].copy_from_slice(cmds.as_ref());
-    let len = device_handle.write(if_num, out_ep, &buf)?;
-    println!("write len = {}", len);
+}
 
-/***/
+fn rusb_test(finder: &ProbeFinder, selector: &ProbeSelector, port: u8, profile: &TargetProfile, targetsel: Option<&[u32]>, swo: Option<u32>, trace_clock: Option<u32>) -> Result<(), DapError> {
+    let transport = finder.open_probe(selector)?;
 
-    let mut buf = [0u8; 64];
-    buf[0] = 0;
-    buf[1] = 0;
-    let len = device_handle.read(if_num, in_ep, &mut buf)?;
-    println!("read len = {}", len);
-    dump_buf(&buf[..len]);
-    assert!(buf[0] == ID_DAP_ExecuteCommands);
-    assert!(buf[1] == checkers.len() as u8);
-    let mut ptr = 2;
-    for checker in &checkers {
-        ptr = ptr + checker(&buf[ptr..]);
-    }
-    assert!(ptr <= len);
+    // device_handle.clear_halt(0x01);
+    // device_handle.clear_halt(0x81);
 
-/***/
+    run_test(transport, port, profile, targetsel, swo, trace_clock)
+}
 
-    let mut cmds = Vec::new();
-    let mut checkers: Vec<Box<dyn Fn(&[u8]) -> usize>> = Vec::new();
-    add_init_transfer(&mut cmds, &mut checkers);
+fn run_test<T: DapTransport>(transport: T, port: u8, profile: &TargetProfile, targetsel: Option<&[u32]>, swo: Option<u32>, trace_clock: Option<u32>) -> Result<(), DapError> {
+    let mut session = DapSession::open(transport)?;
+    println!("packet size = {}, packet count = {}", session.packet_size(), session.packet_count());
 
-    let mut buf = [0u8; 64];
-    buf[0] = ID_DAP_ExecuteCommands;
-    buf[1] = checkers.len() as u8;
-    assert!(cmds.len() <= buf.len() - 2);
-    buf[2..(2+cmds.len())].copy_from_slice(&cmds.as_ref());
-    let len = device_handle.write(if_num, out_ep, &buf)?;
-    log::debug!("cmds.len() = {}", cmds.len());
-    println!("write len = {}", len);
+    print!("{}", DapInfo::read(&mut session)?);
 
 /***/
 
-    let mut buf = [0u8; 64];
-    buf[0] = 0;
-    buf[1] = 0;
-    let len = device_handle.read(if_num, in_ep, &mut buf)?;
-    println!("read len = {}", len);
-    dump_buf(&buf[..len]);
-    assert!(buf[0] == ID_DAP_ExecuteCommands);
-    assert!(buf[1] == checkers.len() as u8);
-    let mut ptr = 2;
-    for checker in &checkers {
-        ptr = ptr + checker(&buf[ptr..]);
-    }
-    assert!(ptr <= len);
+    let select_sequence = if port == DAP_PORT_JTAG { swd_to_jtag_sequence() } else { jtag_to_swd_sequence() };
+    session.command(&Command::Connect(port))?;
+    profile.apply(&mut session)?;
+    session.execute_commands(vec![
+        select_sequence,
+        swd_reset_sequence(),
+    ])?;
 
 /***/
 
-    fn add_info_str(cmds: &mut Vec<u8>, checkers: &mut Vec<Box<dyn Fn(&[u8]) -> usize>>, info: u8) {
-        cmds.extend([ID_DAP_Info, info]); // ID_DAP_Info, DAP_ID_*
-        checkers.push( Box::new(move |buf: &[u8]| -> usize {
-            assert!(buf[0] == ID_DAP_Info);
-            assert!(buf[(2+buf[1]-1) as usize] == 0); // always has a terminating NUL character
-            let ver_name = 
-                match info {
-                    DAP_ID_VENDOR => String::from("VENDOR"),
-                    DAP_ID_PRODUCT => String::from("PRODUCT"),
-                    DAP_ID_SER_NUM => String::from("SERIAL_NUMBER"),
-                    DAP_ID_FW_VER => String::from("FW_VER"),
-                    DAP_ID_PRODUCT_FW_VER => String::from("DAPLINK_VER"),
-                    _ => format!("Info {:#04X}", info),
-                };
-            println!("{} = {}", ver_name, std::str::from_utf8(&buf[2..(2+buf[1]) as usize]).unwrap());
-            2 + buf[1] as usize
-        }));
+    let mut dap_index = 0;
+    if port == DAP_PORT_JTAG {
+        let mut jtag = Jtag::new(&mut session);
+        let taps = jtag.scan_chain()?;
+        for (index, tap) in taps.iter().enumerate() {
+            match tap.idcode {
+                Some(idcode) => println!("TAP {}: IR length {}, IDCODE {}", index, tap.ir_len, idcode),
+                None => println!("TAP {}: IR length {}, no IDCODE", index, tap.ir_len),
+            }
+        }
+        jtag.configure(&taps.iter().map(|tap| tap.ir_len).collect::<Vec<_>>())?;
+        dap_index = taps.iter().position(|tap| tap.idcode.is_some_and(|idcode| idcode.is_arm_dp())).unwrap_or(0) as u8;
+        println!("JTAG IDCODE = {:#010X}", jtag.idcode(dap_index)?);
     }
 
-    fn add_connect(cmds: &mut Vec<u8>, checkers: &mut Vec<Box<dyn Fn(&[u8]) -> usize>>) {
-        cmds.extend([ID_DAP_Connect, DAP_PORT_SWD]);
-        checkers.push(Box::new(|buf: &[u8]| -> usize {
-            assert!(buf[0] == ID_DAP_Connect);
-            assert!(buf[1] == DAP_PORT_SWD);
-            2
-        }));
+    if let (DAP_PORT_SWD, Some(candidates)) = (port, targetsel) {
+        let mut swd = Swd::new(&mut session);
+        // multi-drop DPs such as those of the RP2040 come up dormant
+        let mut wake = swd_to_dormant_sequences();
+        wake.extend(dormant_to_swd_sequences());
+        swd.sequences(&wake)?;
+        let targets = swd.scan_targets(candidates)?;
+        for target in &targets {
+            println!("TARGETSEL {:#010X}: IDCODE {:#010X}", target.targetsel, target.idcode);
+        }
+        let target = targets.first().ok_or(DapError::TargetSel { targetsel: candidates[0], reason: "no DP answered" })?;
+        // leaves the DP out of lockout, the IDCODE read below is allowed anyway
+        swd.select_target(target.targetsel)?;
     }
 
-    fn add_set_clock(cmds: &mut Vec<u8>, checkers: &mut Vec<Box<dyn Fn(&[u8]) -> usize>>, clock: u32) {
-        cmds.push(ID_DAP_SWJ_Clock);
-        // cmds.extend([ID_DAP_SWJ_Clock, 0, 1, 0, 0]); // 256Hz
-        // cmds.extend([ID_DAP_SWJ_Clock, 0, 0, 0, 1]); // 16MHz
-        cmds.extend(clock.to_le_bytes());
-        checkers.push(Box::new(|buf: &[u8]| -> usize {
-            assert!(buf[0] == ID_DAP_SWJ_Clock);
-            assert!(buf[1] == 0);
-            2
-        }));
+    let mut dp = DebugPort::new(&mut session, dap_index);
+    if port == DAP_PORT_SWD {
+        // IDCODE has to be the first read after a line reset
+        println!("IDCODE = {:#010X}", dp.read_dp(DP_IDCODE)?);
     }
-
-    fn add_swd_reset_sequence(cmds: &mut Vec<u8>, checkers: &mut Vec<Box<dyn Fn(&[u8]) -> usize>>) {
-        cmds.extend([ID_DAP_SWJ_Sequence,
-                     56, // bits
-                     0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0x0F
-        ]);
-        checkers.push(Box::new(|buf: &[u8]| -> usize {
-            assert!(buf[0] == ID_DAP_SWJ_Sequence);
-            assert!(buf[1] == 0);
-            2
-        }));
+    dp.clear_sticky_errors()?;
+    dp.power_up()?;
+
+    let mut ap = MemAp::new(dp, 0);
+    // 0x04770021 indicates AHB-AP
+    println!("AP_IDR = {:#010X}", ap.read_reg(AP_IDR)?);
+    println!("0xE000ED00 (CPUID) = {:#010X}", ap.read32(0xE000_ED00)?);
+    // println!("0x50000000 (PDID) = {:#010X}", ap.read32(0x5000_0000)?);
+
+    if let Some(baudrate) = swo {
+        let baudrate = Swo::new(ap.dp().session()).configure(SwoMode::Uart, baudrate)?;
+        if let Some(trace_clock) = trace_clock {
+            itm::configure(&mut ap, &ItmConfig { trace_clock, baudrate, ..Default::default() })?;
+        }
+        capture_swo(&mut session, baudrate)?;
     }
 
-    fn add_jtag_to_swd_sequence(cmds: &mut Vec<u8>, checkers: &mut Vec<Box<dyn Fn(&[u8]) -> usize>>) {
-        cmds.extend([ID_DAP_SWJ_Sequence,
-                     72, // bits
-                     0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF,
-                     0x9E, 0xE7
-        ]);
-        checkers.push(Box::new(|buf: &[u8]| -> usize {
-            assert!(buf[0] == ID_DAP_SWJ_Sequence);
-            assert!(buf[1] == 0);
-            2
-        }));
+    Ok(())
+}
+
+fn capture_swo<T: DapTransport>(session: &mut DapSession<T>, baudrate: u32) -> Result<(), DapError> {
+    let mut swo = Swo::new(session);
+    let via = if swo.uses_endpoint() { "SWO endpoint" } else { "DAP_SWO_Data" };
+    println!("SWO {} baud via {}, Ctrl-C to stop", baudrate, via);
+    swo.start()?;
+
+    // stimulus port 0 is printed as text, every other event on a line of its own
+    let mut decoder = ItmDecoder::new();
+    let mut stdout = std::io::stdout();
+    loop {
+        let data = swo.read_data()?;
+        if data.is_empty() {
+            std::thread::sleep(Duration::from_millis(10));
+            continue;
+        }
+        for event in decoder.feed(&data) {
+            match event {
+                ItmEvent::Sync => (),
+                ItmEvent::Instrumentation { port: 0, data } => {
+                    stdout.write_all(&data).ok();
+                }
+                event => println!("{:?}", event),
+            }
+        }
+        stdout.flush().ok();
     }
+}
 
-    fn add_init_transfer(cmds: &mut Vec<u8>, checkers: &mut Vec<Box<dyn Fn(&[u8]) -> usize>>) {
-        let mut transfers = Vec::new();
-        let mut readers: Vec<Box<dyn Fn(&[u8]) -> usize>> = Vec::new();
-        transfers.push(0x02); // DP_IDCODE | DAP_TRANSFER_RnW
-        readers.push(Box::new(|buf: &[u8]| -> usize {
-            println!("IDCODE = {:#010X}", u32::from_le_bytes(buf[0..4].try_into().unwrap()));
-            4
-        }));
-/*
-        transfers.push(0x02); // DP_IDCODE | DAP_TRANSFER_RnW
-        readers.push(Box::new(|buf: &[u8]| -> usize {
-            println!("{:#010X}", u32::from_le_bytes(buf[0..4].try_into().unwrap()));
-            4
-        }));
-*/
-        transfers.push(0x00); // DP_ABORT, nW, clear sticky error bits
-        transfers.extend(0x0000001Du32.to_le_bytes());
-        readers.push(Box::new(|buf: &[u8]| -> usize { 0 }));
-        transfers.push(0x08); // DP_SELECT, nW, set AP0, AP bank 0xF, DP bank 0
-        transfers.extend(0x000000F0u32.to_le_bytes());
-        readers.push(Box::new(|buf: &[u8]| -> usize { 0 }));
-
-        // Startup Debug Circuit
-        transfers.push(0x04); // DP_CTRL/STAT(bank 0), nW, CSYSPWRUPREQ, CDBGPWRUPREQ
-        transfers.extend(0x50000000u32.to_le_bytes());
-        readers.push(Box::new(|buf: &[u8]| -> usize { 0 }));
-        transfers.push(0x20); // DP, nW, MATCH_MASK
-        transfers.extend(0xA0000000u32.to_le_bytes());
-        readers.push(Box::new(|buf: &[u8]| -> usize { 0 }));
-        transfers.push(0x16); // DP_CTRL/STAT(bank 0), R, MATCH_VALUE
-        transfers.extend(0xA0000000u32.to_le_bytes());
-        readers.push(Box::new(|buf: &[u8]| -> usize { 0 }));
-
-        // 0x04770021 indicates AHB-AP
-        transfers.push(0x0F); // AP_xC(bank 0xF) R (AP_IDR)
-        readers.push(Box::new(|buf: &[u8]| -> usize {
-            println!("AP_IDR = {:#010X}", u32::from_le_bytes(buf[0..4].try_into().unwrap()));
-            4
-        }));
-
-        transfers.push(0x08); // DP_SELECT, nW, set AP0, AP bank 0x0, DP bank 0
-        transfers.extend(0x00000000u32.to_le_bytes());
-        readers.push(Box::new(|buf: &[u8]| -> usize { 0 }));
-/*
-        transfers.push(0x03); // AP_x0 R (AP_CSW)
-        readers.push(Box::new(|buf: &[u8]| -> usize {
-            println!("AP CSW {:#010X}", u32::from_le_bytes(buf[0..4].try_into().unwrap()));
-            4
-        }));
-*/
+fn run_player<T: DapTransport>(transport: T, data: &[u8], xsvf: bool) -> Result<(), SvfError> {
+    let mut session = DapSession::open(transport)?;
+    session.execute_commands(vec![
+        Command::Connect(DAP_PORT_JTAG),
+        Command::SwjClock(0x00100000), // 1MHz, FREQUENCY may change it
+        swd_to_jtag_sequence(),
+    ])?;
+
+    let mut player = Player::new(Jtag::new(&mut session))?;
+    if xsvf {
+        player.run_xsvf(&parse_xsvf(data)?)
+    } else {
+        player.run_svf(&parse_svf(&String::from_utf8_lossy(data))?)
+    }
+}
 
-        // Read CPUID
-        transfers.push(0x01); // AP_x0(bank 0) nW (AP_CSW)
-        transfers.extend(0x03000042u32.to_le_bytes());
-        readers.push(Box::new(|buf: &[u8]| -> usize { 0 }));
-        transfers.push(0x05); // AP_x4(bank 0) nW (AP_TAR)
-        transfers.extend(0xE000ED00u32.to_le_bytes());
-        readers.push(Box::new(|buf: &[u8]| -> usize { 0 }));
-        transfers.push(0x0F); // AP_xC(bank 0) R (AP_DRW)
-        readers.push(Box::new(|buf: &[u8]| -> usize {
-            println!("0xE000ED00 (CPUID) {:#010X}", u32::from_le_bytes(buf[0..4].try_into().unwrap()));
-            4
-        }));
+fn run_console<T: DapTransport + Send + 'static>(transport: T, baudrate: u32, pty: bool) -> std::io::Result<()> {
+    let session = DapSession::open(transport).map_err(std::io::Error::other)?;
+    let config = UartConfig { baudrate, ..Default::default() };
+    let stream = Arc::new(UartStream::open(session, &config).map_err(std::io::Error::other)?);
 
-/*
-        // Read PDID
-/*
-        transfers.push(0x01); // AP_x0(bank 0) nW (AP_CSW)
-        transfers.extend(0x03000042u32.to_le_bytes());
-        readers.push(Box::new(|buf: &[u8]| -> usize { 0 }));
-*/
-        transfers.push(0x05); // AP_x4(bank 0) nW (AP_TAR)
-        transfers.extend(0x50000000u32.to_le_bytes());
-        readers.push(Box::new(|buf: &[u8]| -> usize { 0 }));
-        transfers.push(0x0F); // AP_xC(bank 0) R (AP_DRW)
-        readers.push(Box::new(|buf: &[u8]| -> usize {
-            println!("0x50000000 (PDID) {:#010X}", u32::from_le_bytes(buf[0..4].try_into().unwrap()));
-            4
-        }));
-*/
+    #[cfg(target_os = "linux")]
+    if pty {
+        let pty = rusb_cmsis_dap::uart::Pty::open()?;
+        println!("UART {} baud on {}, Ctrl-C to stop", baudrate, pty.path().display());
+        return pty.serve(&stream);
+    }
 
-/*
-        transfers.push(0x02); // DP_IDCODE, R
-        readers.push(Box::new(|buf: &[u8]| -> usize {
-            println!("{:#010X}", u32::from_le_bytes(buf[0..4].try_into().unwrap()));
-            4
-        }));
-        transfers.push(0x00); // DP_ABORT, nW, clear sticky error bits
-        transfers.extend(0x0000001Du32.to_le_bytes());
-        readers.push(Box::new(|buf: &[u8]| -> usize { 0 }));
-*/
-        cmds.extend([ID_DAP_Transfer, 0, readers.len() as u8]);
-        cmds.extend(transfers);
-        checkers.push(Box::new(move |buf: &[u8]| -> usize {
-            let count = readers.len();
-            assert!(buf[0] == ID_DAP_Transfer);
-            if buf[1] != count as u8{
-                println!("Not all transfers completed.");
-            }
-            if buf[2] != 1 /* OK */ {
-                println!("Wrong response code");
+    println!("UART {} baud, Ctrl-C to stop", baudrate);
+    let input = stream.clone();
+    std::thread::spawn(move || std::io::copy(&mut std::io::stdin(), &mut &*input));
+    let mut stdout = std::io::stdout();
+    let mut buf = [0; 256];
+    loop {
+        match (&*stream).read(&mut buf) {
+            Ok(0) => return Ok(()),
+            Ok(len) => {
+                stdout.write_all(&buf[..len])?;
+                stdout.flush()?;
             }
-            let mut ptr = 3;
-            for reader in &readers {
-                ptr = ptr + reader(&buf[ptr..]);
-            }
-            ptr
-        }));
+            Err(e) if e.kind() == std::io::ErrorKind::TimedOut => (),
+            Err(e) => return Err(e),
+        }
     }
+}
 
-/***/
-
+fn run_sample<T: DapTransport>(transport: T, bsdl: Bsdl) -> Result<(), BsdlError> {
+    let mut session = DapSession::open(transport)?;
+    session.execute_commands(vec![
+        Command::Connect(DAP_PORT_JTAG),
+        Command::SwjClock(0x00100000), // 1MHz
+        swd_to_jtag_sequence(),
+    ])?;
+
+    let mut jtag = Jtag::new(&mut session);
+    let taps = jtag.scan_chain()?;
+    let index = taps.iter()
+        .position(|tap| tap.idcode.is_some_and(|idcode| bsdl.matches_idcode(idcode.0)))
+        .ok_or_else(|| BsdlError::NoDevice(bsdl.entity.clone()))?;
+    println!("TAP {}: {}", index, bsdl.entity);
+
+    let ir_lengths: Vec<u8> = taps.iter().map(|tap| tap.ir_len).collect();
+    let mut boundary = BoundaryScan::new(jtag, bsdl, &ir_lengths, index)?;
+    for (pin, level) in boundary.sample()? {
+        println!("{} = {}", pin, level as u8);
+    }
     Ok(())
 }
@@ -0,0 +1,158 @@
+use crate::command::TransferRequest;
+use crate::dp::{DebugPort, DP_SELECT};
+use crate::error::DapError;
+use crate::transport::DapTransport;
+
+// MEM-AP registers (APBANKSEL << 4 | A[3:2])
+pub const AP_CSW: u8 = 0x00;
+pub const AP_TAR: u8 = 0x04;
+pub const AP_DRW: u8 = 0x0C;
+pub const AP_BD0: u8 = 0x10;
+pub const AP_CFG: u8 = 0xF4;
+pub const AP_BASE: u8 = 0xF8;
+pub const AP_IDR: u8 = 0xFC;
+
+// AP_CSW bits
+pub const CSW_SIZE_WORD: u32 = 0x2;
+pub const CSW_ADDRINC_SINGLE: u32 = 1 << 4;
+pub const CSW_DEVICE_EN: u32 = 1 << 6;
+/// HPROT for a privileged data access on an AHB-AP.
+pub const CSW_PROT_PRIV_DATA: u32 = 0x03 << 24;
+
+/// TAR auto increment is only guaranteed within a 1KB block.
+const AUTO_INCREMENT_BLOCK: u32 = 0x400;
+
+/// Runs of fewer words go in the DAP_Transfer which sets TAR, longer ones are
+/// moved with DAP_TransferBlock.
+const MIN_BLOCK_TRANSFER: usize = 8;
+
+/// Word access to target memory through a MEM-AP.
+///
+/// Blocks are read and written with TAR auto increment, reloading TAR at every 1KB
+/// boundary. Long runs of AP_DRW accesses use DAP_TransferBlock at full packet size,
+/// the rest as few DAP_Transfer commands as the packet size allows.
+/// DP_SELECT and AP_CSW are only written when they change.
+pub struct MemAp<'a, T: DapTransport> {
+    dp: DebugPort<'a, T>,
+    apsel: u8,
+    csw: Option<u32>,
+}
+
+impl<'a, T: DapTransport> MemAp<'a, T> {
+    pub fn new(dp: DebugPort<'a, T>, apsel: u8) -> Self {
+        MemAp { dp, apsel, csw: None }
+    }
+
+    pub fn dp(&mut self) -> &mut DebugPort<'a, T> {
+        &mut self.dp
+    }
+
+    /// Read any register of this AP, e.g. [`AP_IDR`].
+    pub fn read_reg(&mut self, reg: u8) -> Result<u32, DapError> {
+        let mut transfers = self.select(reg);
+        transfers.push(TransferRequest::ap_read(reg));
+        Ok(self.dp.transfer(&transfers)?[0])
+    }
+
+    pub fn write_reg(&mut self, reg: u8, value: u32) -> Result<(), DapError> {
+        let mut transfers = self.select(reg);
+        transfers.push(TransferRequest::ap_write(reg, value));
+        self.dp.transfer(&transfers)?;
+        if reg == AP_CSW {
+            self.csw = Some(value);
+        }
+        Ok(())
+    }
+
+    pub fn read32(&mut self, addr: u32) -> Result<u32, DapError> {
+        Ok(self.read_block(addr, 1)?[0])
+    }
+
+    pub fn write32(&mut self, addr: u32, value: u32) -> Result<(), DapError> {
+        self.write_block(addr, &[value])
+    }
+
+    /// Read `count` words starting at the word aligned address `addr`.
+    pub fn read_block(&mut self, addr: u32, count: usize) -> Result<Vec<u32>, DapError> {
+        let mut transfers = self.prologue(addr)?;
+        let mut data = Vec::new();
+        for (addr, len) in blocks(addr, count) {
+            transfers.push(TransferRequest::ap_write(AP_TAR, addr));
+            if len < MIN_BLOCK_TRANSFER {
+                transfers.extend((0..len).map(|_| TransferRequest::ap_read(AP_DRW)));
+                continue;
+            }
+            data.extend(self.run(&transfers)?);
+            transfers.clear();
+            data.extend(self.dp.read_ap_block(AP_DRW, len)?);
+        }
+        data.extend(self.run(&transfers)?);
+        Ok(data)
+    }
+
+    /// Write `data` starting at the word aligned address `addr`.
+    pub fn write_block(&mut self, addr: u32, data: &[u32]) -> Result<(), DapError> {
+        let mut transfers = self.prologue(addr)?;
+        let mut words = data;
+        for (addr, len) in blocks(addr, data.len()) {
+            let (block, rest) = words.split_at(len);
+            words = rest;
+            transfers.push(TransferRequest::ap_write(AP_TAR, addr));
+            if len < MIN_BLOCK_TRANSFER {
+                transfers.extend(block.iter().map(|&value| TransferRequest::ap_write(AP_DRW, value)));
+                continue;
+            }
+            self.run(&transfers)?;
+            transfers.clear();
+            self.dp.write_ap_block(AP_DRW, block)?;
+        }
+        self.run(&transfers)?;
+        Ok(())
+    }
+
+    /// DP_SELECT write needed to reach the bank of `reg`, if any.
+    fn select(&mut self, reg: u8) -> Vec<TransferRequest> {
+        let select = (self.apsel as u32) << 24 | (reg & 0xF0) as u32;
+        if self.dp.selected() == Some(select) {
+            Vec::new()
+        } else {
+            vec![TransferRequest::dp_write(DP_SELECT, select)]
+        }
+    }
+
+    /// Transfers which set up bank 0 and CSW for an auto incremented word access.
+    fn prologue(&mut self, addr: u32) -> Result<Vec<TransferRequest>, DapError> {
+        if addr & 3 != 0 {
+            return Err(DapError::Unaligned { addr });
+        }
+        let mut transfers = self.select(AP_CSW);
+        let csw = CSW_PROT_PRIV_DATA | CSW_DEVICE_EN | CSW_ADDRINC_SINGLE | CSW_SIZE_WORD;
+        if self.csw != Some(csw) {
+            transfers.push(TransferRequest::ap_write(AP_CSW, csw));
+            self.csw = Some(csw);
+        }
+        Ok(transfers)
+    }
+
+    fn run(&mut self, transfers: &[TransferRequest]) -> Result<Vec<u32>, DapError> {
+        let result = self.dp.transfer(transfers);
+        if result.is_err() {
+            // the CSW write may not have happened
+            self.csw = None;
+        }
+        result
+    }
+}
+
+/// Split `count` words at `addr` into runs which do not cross an auto increment boundary.
+fn blocks(mut addr: u32, mut count: usize) -> Vec<(u32, usize)> {
+    let mut blocks = Vec::new();
+    while count > 0 {
+        let room = ((AUTO_INCREMENT_BLOCK - (addr % AUTO_INCREMENT_BLOCK)) / 4) as usize;
+        let len = room.min(count);
+        blocks.push((addr, len));
+        addr = addr.wrapping_add(len as u32 * 4);
+        count -= len;
+    }
+    blocks
+}
@@ -0,0 +1,392 @@
+use rusb::{ConfigDescriptor, Context, Device, DeviceHandle, InterfaceDescriptor, TransferType, UsbContext};
+
+use std::fmt;
+use std::str::FromStr;
+use std::time::Duration;
+
+use crate::transport::{BulkTransport, DapTransport, HidControlTransport, HidTransport};
+use crate::error::ProbeCreationError;
+
+/// VID/PID pairs recognised even when their strings can not be read.
+const KNOWN_PROBES: [(u16, u16); 3] = [
+    (0x0D28, 0x0204), // DAPLink
+    (0x2E8A, 0x000C), // Raspberry Pi Debug Probe / picoprobe
+    (0x1FC9, 0x0143), // MCU-Link
+];
+
+const USE_HID_OUT_EP: bool = false;
+const USE_CMSIS_DAP_V2: bool = true;
+
+fn is_cmsis_dap_string(s: &str) -> bool {
+    s.contains("CMSIS-DAP")
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DapVersion {
+    /// HID based
+    V1,
+    /// Bulk endpoints on a vendor specific interface
+    V2,
+}
+
+/// A CMSIS-DAP probe found on the USB bus.
+#[derive(Clone, Debug)]
+pub struct ProbeInfo {
+    pub vendor_id: u16,
+    pub product_id: u16,
+    pub serial_number: Option<String>,
+    pub product: Option<String>,
+    pub bus_number: u8,
+    pub port_numbers: Vec<u8>,
+    /// `None` if the device could not be opened to inspect its interfaces.
+    pub version: Option<DapVersion>,
+}
+
+impl ProbeInfo {
+    /// Location on the bus in the form used by Linux sysfs, e.g. "1-2.4".
+    pub fn path(&self) -> String {
+        let ports: Vec<String> = self.port_numbers.iter().map(|port| port.to_string()).collect();
+        format!("{}-{}", self.bus_number, ports.join("."))
+    }
+}
+
+impl fmt::Display for ProbeInfo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{:04X}:{:04X}:{} {} [{}]",
+            self.vendor_id,
+            self.product_id,
+            self.serial_number.as_deref().unwrap_or("?"),
+            self.product.as_deref().unwrap_or("?"),
+            self.path(),
+        )?;
+        match self.version {
+            Some(DapVersion::V1) => write!(f, " CMSIS-DAPv1"),
+            Some(DapVersion::V2) => write!(f, " CMSIS-DAPv2"),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Which probe to open.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ProbeSelector {
+    /// Position in the list returned by [`list_probes`].
+    Index(usize),
+    SerialNumber(String),
+    /// A plain number: the probe at that index if there is one, otherwise
+    /// the probe with that serial number, as many serial numbers are all digits.
+    IndexOrSerial(String),
+    VidPid { vendor_id: u16, product_id: u16, serial_number: Option<String> },
+}
+
+impl ProbeSelector {
+    /// Whether the probe at `index` of `count` probes is the one selected.
+    fn matches(&self, index: usize, count: usize, info: &ProbeInfo) -> bool {
+        match self {
+            ProbeSelector::Index(n) => *n == index,
+            ProbeSelector::SerialNumber(serial) => info.serial_number.as_ref() == Some(serial),
+            ProbeSelector::IndexOrSerial(s) => match s.parse::<usize>() {
+                Ok(n) if n < count => n == index,
+                _ => info.serial_number.as_ref() == Some(s),
+            },
+            ProbeSelector::VidPid { vendor_id, product_id, serial_number } => {
+                info.vendor_id == *vendor_id
+                    && info.product_id == *product_id
+                    && (serial_number.is_none() || info.serial_number == *serial_number)
+            }
+        }
+    }
+}
+
+impl FromStr for ProbeSelector {
+    type Err = ProbeCreationError;
+
+    /// `VID:PID[:SERIAL]` with VID and PID in hex, `#N` as an index, a plain number
+    /// as an index or a serial number, anything else as a serial number.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let fields: Vec<&str> = s.splitn(3, ':').collect();
+        if fields.len() >= 2 {
+            let parse = |field: &str| {
+                u16::from_str_radix(field, 16).map_err(|_| ProbeCreationError::Other("Invalid VID:PID[:SERIAL] selector."))
+            };
+            return Ok(ProbeSelector::VidPid {
+                vendor_id: parse(fields[0])?,
+                product_id: parse(fields[1])?,
+                serial_number: fields.get(2).map(|serial| serial.to_string()),
+            });
+        }
+        if let Some(index) = s.strip_prefix('#') {
+            let index = index.parse().map_err(|_| ProbeCreationError::Other("Invalid #INDEX selector."))?;
+            return Ok(ProbeSelector::Index(index));
+        }
+        if !s.is_empty() && s.bytes().all(|c| c.is_ascii_digit()) {
+            return Ok(ProbeSelector::IndexOrSerial(s.to_string()));
+        }
+        Ok(ProbeSelector::SerialNumber(s.to_string()))
+    }
+}
+
+/// The interface of a probe which carries CMSIS-DAP packets.
+struct DapInterface {
+    number: u8,
+    out_ep: u8,
+    in_ep: u8,
+    /// The SWO streaming endpoint of a CMSIS-DAPv2 interface.
+    swo_ep: Option<u8>,
+    version: DapVersion,
+}
+
+fn interface_string<T: UsbContext>(device_handle: &DeviceHandle<T>, descriptor: &InterfaceDescriptor) -> Option<String> {
+    let string_index = descriptor.description_string_index()?;
+    device_handle.read_string_descriptor_ascii(string_index).ok()
+}
+
+fn find_interface<T: UsbContext>(device_handle: &DeviceHandle<T>, config: &ConfigDescriptor) -> Option<DapInterface> {
+    let mut found = None;
+    // Search CMSIS-DAP v1 interface
+    // An HID interface named "CMSIS-DAP" is preferred over any other HID interface.
+    let mut found_by_name = false;
+    for interface in config.interfaces() {
+        if let Some(descriptor) = interface.descriptors().next() {
+            let interface_string = interface_string(device_handle, &descriptor).unwrap_or_default();
+            log::debug!("interface {} : {}", interface.number(), interface_string);
+            let by_name = is_cmsis_dap_string(&interface_string);
+            let cc_sub_prot = (descriptor.class_code(), descriptor.sub_class_code(), descriptor.protocol_code());
+            if cc_sub_prot == (0x03, 0x00, 0x00) && (by_name || !found_by_name) {
+                let mut out_ep = 0;
+                let mut in_ep = 0;
+                for endpoint in descriptor.endpoint_descriptors() {
+                    log::debug!("interface {} ep {:#04X}", interface.number(), endpoint.address());
+                    let ep = endpoint.address();
+                    if ep & 0x80 != 0 {
+                        in_ep = ep;
+                    } else if USE_HID_OUT_EP {
+                        out_ep = ep;
+                    }
+                }
+                found = Some(DapInterface { number: interface.number(), out_ep, in_ep, swo_ep: None, version: DapVersion::V1 });
+                found_by_name |= by_name;
+            }
+        }
+    }
+    // Search CMISIS-DAP v2 interface and override with it
+    for interface in config.interfaces() {
+        if let Some(descriptor) = interface.descriptors().next() {
+            let interface_string = interface_string(device_handle, &descriptor).unwrap_or_default();
+            if is_cmsis_dap_string(&interface_string) && descriptor.class_code() == 0xFF && USE_CMSIS_DAP_V2 {
+                // The first bulk OUT and IN endpoints carry commands and responses,
+                // an optional second IN endpoint carries SWO trace data.
+                let mut out_ep = 0;
+                let mut in_ep = 0;
+                let mut swo_ep = None;
+                for endpoint in descriptor.endpoint_descriptors() {
+                    log::debug!("interface {} ep {:#04X}", interface.number(), endpoint.address());
+                    if endpoint.transfer_type() != TransferType::Bulk {
+                        continue;
+                    }
+                    let ep = endpoint.address();
+                    if ep & 0x80 != 0 {
+                        if in_ep == 0 {
+                            in_ep = ep;
+                        } else if swo_ep.is_none() {
+                            swo_ep = Some(ep);
+                        }
+                    } else if out_ep == 0 {
+                        out_ep = ep;
+                    }
+                }
+                if in_ep != 0 && out_ep != 0 {
+                    found = Some(DapInterface { number: interface.number(), out_ep, in_ep, swo_ep, version: DapVersion::V2 });
+                }
+            }
+        }
+    }
+    found
+}
+
+fn probe_info<T: UsbContext>(device: &Device<T>) -> Result<ProbeInfo, ProbeCreationError> {
+    let descriptor = device.device_descriptor()?;
+    let mut info = ProbeInfo {
+        vendor_id: descriptor.vendor_id(),
+        product_id: descriptor.product_id(),
+        serial_number: None,
+        product: None,
+        bus_number: device.bus_number(),
+        port_numbers: device.port_numbers().unwrap_or_default(),
+        version: None,
+    };
+    // without permission to open the device only the descriptors are known
+    if let Ok(handle) = device.open() {
+        info.serial_number = handle.read_serial_number_string_ascii(&descriptor).ok();
+        info.product = handle.read_product_string_ascii(&descriptor).ok();
+        if let Ok(config) = device.active_config_descriptor() {
+            info.version = find_interface(&handle, &config).map(|interface| interface.version);
+        }
+    }
+    Ok(info)
+}
+
+/// Decides which USB devices are CMSIS-DAP probes.
+///
+/// As the CMSIS-DAP specification requires, a device is a probe if its product string
+/// or one of its interface strings contains "CMSIS-DAP". Devices on the allow-list are
+/// accepted without looking at their strings, which also covers probes the user has
+/// no permission to open.
+#[derive(Clone, Debug)]
+pub struct ProbeFinder {
+    allow_list: Vec<(u16, u16)>,
+}
+
+impl ProbeFinder {
+    pub fn new() -> Self {
+        ProbeFinder { allow_list: KNOWN_PROBES.to_vec() }
+    }
+
+    /// Accept every device with this VID/PID as a probe.
+    pub fn allow(&mut self, vendor_id: u16, product_id: u16) {
+        if !self.allow_list.contains(&(vendor_id, product_id)) {
+            self.allow_list.push((vendor_id, product_id));
+        }
+    }
+
+    pub fn allow_list(&self) -> &[(u16, u16)] {
+        &self.allow_list
+    }
+
+    pub fn is_cmsis_dap_device<T: UsbContext>(&self, device: &Device<T>) -> bool {
+        let descriptor = match device.device_descriptor() {
+            Ok(descriptor) => descriptor,
+            Err(_) => return false,
+        };
+        if self.allow_list.contains(&(descriptor.vendor_id(), descriptor.product_id())) {
+            return true;
+        }
+        if descriptor.class_code() == 0x09 {
+            // hub
+            return false;
+        }
+        let handle = match device.open() {
+            Ok(handle) => handle,
+            Err(_) => return false,
+        };
+        if let Ok(product) = handle.read_product_string_ascii(&descriptor) {
+            if is_cmsis_dap_string(&product) {
+                return true;
+            }
+        }
+        if let Ok(config) = device.active_config_descriptor() {
+            for interface in config.interfaces() {
+                for interface_desc in interface.descriptors() {
+                    if interface_string(&handle, &interface_desc).is_some_and(|s| is_cmsis_dap_string(&s)) {
+                        return true;
+                    }
+                }
+            }
+        }
+        false
+    }
+
+    fn probes(&self, context: &Context) -> Result<Vec<(Device<Context>, ProbeInfo)>, ProbeCreationError> {
+        let mut probes = Vec::new();
+        for device in context.devices()?.iter().filter(|device| self.is_cmsis_dap_device(device)) {
+            let info = probe_info(&device)?;
+            probes.push((device, info));
+        }
+        Ok(probes)
+    }
+
+    /// Every CMSIS-DAP probe attached to the host.
+    pub fn list_probes(&self) -> Result<Vec<ProbeInfo>, ProbeCreationError> {
+        let context = Context::new()?;
+        Ok(self.probes(&context)?.into_iter().map(|(_, info)| info).collect())
+    }
+
+    /// Open the first probe matching `selector` and claim its CMSIS-DAP interface.
+    pub fn open_probe(&self, selector: &ProbeSelector) -> Result<Box<dyn DapTransport + Send>, ProbeCreationError> {
+        let context = Context::new()?;
+
+        let probes = self.probes(&context)?;
+        let count = probes.len();
+        let (device, info) = probes
+            .into_iter()
+            .enumerate()
+            .find(|(index, (_, info))| selector.matches(*index, count, info))
+            .map(|(_, probe)| probe)
+            .ok_or(ProbeCreationError::NotFound)?;
+        log::debug!("Selected probe {}", info);
+
+        open_device(&device)
+    }
+}
+
+impl Default for ProbeFinder {
+    fn default() -> Self {
+        ProbeFinder::new()
+    }
+}
+
+/// Every CMSIS-DAP probe attached to the host.
+pub fn list_probes() -> Result<Vec<ProbeInfo>, ProbeCreationError> {
+    ProbeFinder::new().list_probes()
+}
+
+/// Open the first probe matching `selector` and claim its CMSIS-DAP interface.
+pub fn open_probe(selector: &ProbeSelector) -> Result<Box<dyn DapTransport + Send>, ProbeCreationError> {
+    ProbeFinder::new().open_probe(selector)
+}
+
+fn open_device(device: &Device<Context>) -> Result<Box<dyn DapTransport + Send>, ProbeCreationError> {
+    let mut device_handle = device.open().map_err(|_| ProbeCreationError::CouldNotOpen)?;
+
+    log::debug!("Aquired handle for probe");
+
+    let config = device.active_config_descriptor()?;
+
+    log::debug!("Active config descriptor: {:?}", &config);
+
+    let descriptor = device.device_descriptor()?;
+
+    log::debug!("Device descriptor: {:?}", &descriptor);
+
+    {
+        for interface in config.interfaces() {
+            for interface_desc in interface.descriptors() {
+                log::debug!("Interface Desc: {:?}", interface_desc);
+                let languages = device_handle.read_languages(Duration::from_secs(2)).unwrap_or_default();
+                for lang in languages {
+                    log::debug!("Lang: {:?} {:#06X}", lang, lang.lang_id());
+                    if let Ok(if_str) = device_handle.read_interface_string(lang, &interface_desc, Duration::from_secs(2)) {
+                        log::debug!("Interface String: {}", if_str);
+                    }
+                }
+                if let Some(n) = interface_desc.description_string_index() {
+                    if let Ok(string) = device_handle.read_string_descriptor_ascii(n) {
+                        log::debug!("string[{}] = {}", n, string)
+                    }
+                }
+            }
+        }
+    }
+
+    let interface = find_interface(&device_handle, &config)
+        .ok_or(ProbeCreationError::Other("No CMSIS-DAP interface found."))?;
+    log::debug!("if_num = {}", interface.number);
+    log::debug!("out_ep = {:#04X}", interface.out_ep);
+    log::debug!("in_ep = {:#04X}", interface.in_ep);
+    log::debug!("swo_ep = {:?}", interface.swo_ep);
+    device_handle.claim_interface(interface.number)?;
+    log::debug!("Claimed interface {} of USB device.", interface.number);
+
+    let transport: Box<dyn DapTransport + Send> =
+        if interface.version == DapVersion::V2 {
+            Box::new(BulkTransport::new(device_handle, interface.out_ep, interface.in_ep, interface.swo_ep))
+        } else if interface.in_ep != 0 {
+            let out_ep = if interface.out_ep != 0 { Some(interface.out_ep) } else { None };
+            Box::new(HidTransport::new(device_handle, interface.number, out_ep, interface.in_ep))
+        } else {
+            Box::new(HidControlTransport::new(device_handle, interface.number))
+        };
+    Ok(transport)
+}
@@ -0,0 +1,291 @@
+use crate::batch::CommandBatch;
+use crate::transport::{discard_responses, DapTransport};
+use crate::error::DapError;
+use crate::command::{Command, Response, ID_DAP_Info, ID_DAP_QueueCommands, ID_DAP_ExecuteCommands};
+use crate::command::{DAP_ID_CAPABILITIES, DAP_ID_FW_VER, DAP_ID_PACKET_COUNT, DAP_ID_PACKET_SIZE};
+use crate::info::{command_support, Capabilities, FirmwareVersion};
+
+/// Large enough to hold any response before the real packet size is known.
+const MAX_PACKET_SIZE: usize = 1024;
+
+/// Transfer settings applied with DAP_TransferConfigure.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct TransferConfig {
+    /// Extra idle cycles after each transfer.
+    pub idle_cycles: u8,
+    /// How often the probe retries a transfer answered with WAIT.
+    pub wait_retry: u16,
+    /// How often the probe repeats a match read until the value matches.
+    pub match_retry: u16,
+}
+
+impl Default for TransferConfig {
+    /// What the CMSIS-DAP firmware starts with.
+    fn default() -> Self {
+        TransferConfig { idle_cycles: 0, wait_retry: 100, match_retry: 0 }
+    }
+}
+
+/// An opened CMSIS-DAP probe.
+///
+/// Packet size and packet count are read from DAP_Info when the session is
+/// opened and every buffer is sized accordingly. The protocol version and
+/// capabilities are read as well, and commands the probe does not implement
+/// are refused before they are sent.
+pub struct DapSession<T: DapTransport> {
+    transport: T,
+    packet_size: usize,
+    packet_count: usize,
+    queue_commands: bool,
+    version: Option<FirmwareVersion>,
+    capabilities: Capabilities,
+    transfer_config: TransferConfig,
+}
+
+impl<T: DapTransport> DapSession<T> {
+    pub fn open(mut transport: T) -> Result<Self, DapError> {
+        let mut packet_size = transport.packet_size();
+        let mut packet_count = transport.packet_count();
+
+        let buf = query_info(&mut transport, DAP_ID_PACKET_SIZE)?;
+        if buf.len() >= 2 {
+            packet_size = u16::from_le_bytes([buf[0], buf[1]]) as usize;
+        }
+        let buf = query_info(&mut transport, DAP_ID_PACKET_COUNT)?;
+        if !buf.is_empty() && buf[0] != 0 {
+            packet_count = buf[0] as usize;
+        }
+        log::debug!("packet size = {}, packet count = {}", packet_size, packet_count);
+
+        transport.set_packet_size(packet_size);
+        transport.set_packet_count(packet_count);
+
+        let buf = query_info(&mut transport, DAP_ID_FW_VER)?;
+        let version = std::str::from_utf8(&buf).ok().and_then(|s| s.parse().ok());
+        if version.is_none() {
+            log::warn!("unknown protocol version {:?}, assuming every command is implemented", String::from_utf8_lossy(&buf));
+        }
+        let capabilities = Capabilities::from_bytes(&query_info(&mut transport, DAP_ID_CAPABILITIES)?);
+        log::debug!("protocol version = {:?}, capabilities = {}", version, capabilities);
+
+        let mut session = DapSession {
+            transport,
+            packet_size,
+            packet_count,
+            queue_commands: false,
+            version,
+            capabilities,
+            transfer_config: TransferConfig::default(),
+        };
+        // a chain of queued packets has to fit in the probe's buffers, support itself
+        // follows from the version and the atomic commands capability
+        session.queue_commands = packet_count > 1 && session.supports(ID_DAP_QueueCommands);
+        log::debug!("DAP_QueueCommands supported = {}", session.queue_commands);
+
+        Ok(session)
+    }
+
+    /// CMSIS-DAP protocol version of the firmware, if it reports a valid one.
+    pub fn version(&self) -> Option<FirmwareVersion> {
+        self.version
+    }
+
+    pub fn capabilities(&self) -> &Capabilities {
+        &self.capabilities
+    }
+
+    /// Whether the probe implements the command `id`.
+    pub fn supports(&self, id: u8) -> bool {
+        self.check_id(id).is_ok()
+    }
+
+    fn check_id(&self, id: u8) -> Result<(), DapError> {
+        let (required, capable) = command_support(id, &self.capabilities);
+        match self.version {
+            Some(version) if version < required => Err(DapError::FirmwareTooOld { command: id, required }),
+            _ if !capable => Err(DapError::Unsupported { command: id }),
+            _ => Ok(()),
+        }
+    }
+
+    /// Fail if the probe does not implement `cmd`, or a command inside it.
+    pub fn check_command(&self, cmd: &Command) -> Result<(), DapError> {
+        if let Command::ExecuteCommands(cmds) = cmd {
+            for cmd in cmds {
+                self.check_command(cmd)?;
+            }
+        }
+        self.check_id(cmd.id())
+    }
+
+    /// Whether multi-packet chains are sent with DAP_QueueCommands.
+    pub fn queue_commands(&self) -> bool {
+        self.queue_commands
+    }
+
+    /// Override the use of DAP_QueueCommands, e.g. for firmware which claims support but misbehaves.
+    pub fn set_queue_commands(&mut self, enable: bool) {
+        self.queue_commands = enable && self.packet_count > 1;
+    }
+
+    /// Maximum size of a command or response packet.
+    pub fn packet_size(&self) -> usize {
+        self.packet_size
+    }
+
+    /// Number of packets the probe can buffer, i.e. how many may be in flight.
+    pub fn packet_count(&self) -> usize {
+        self.packet_count
+    }
+
+    /// Settings of the last [`DapSession::configure_transfers`], the firmware defaults before that.
+    pub fn transfer_config(&self) -> TransferConfig {
+        self.transfer_config
+    }
+
+    /// Set idle cycles and retry counts for DAP_Transfer and DAP_TransferBlock.
+    pub fn configure_transfers(&mut self, config: &TransferConfig) -> Result<(), DapError> {
+        self.command(&Command::TransferConfigure {
+            idle_cycles: config.idle_cycles,
+            wait_retry: config.wait_retry,
+            match_retry: config.match_retry,
+        })?;
+        self.transfer_config = *config;
+        Ok(())
+    }
+
+    pub fn transport(&self) -> &T {
+        &self.transport
+    }
+
+    pub fn transport_mut(&mut self) -> &mut T {
+        &mut self.transport
+    }
+
+    /// Send one command packet and return its response packet.
+    pub fn transfer(&mut self, request: &[u8]) -> Result<Vec<u8>, DapError> {
+        let command = request.first().copied().unwrap_or(ID_DAP_Info);
+        self.send(request)?;
+        self.receive(command)
+    }
+
+    fn send(&mut self, request: &[u8]) -> Result<(), DapError> {
+        let command = request.first().copied().unwrap_or(ID_DAP_Info);
+        if request.len() > self.packet_size {
+            return Err(DapError::RequestTooLarge { command, len: request.len() });
+        }
+        let len = self.transport.send_packet(request).map_err(|e| DapError::usb(command, e))?;
+        log::trace!("write len = {}", len);
+        Ok(())
+    }
+
+    fn receive(&mut self, command: u8) -> Result<Vec<u8>, DapError> {
+        let mut buf = vec![0u8; self.packet_size];
+        let len = self.transport.receive_packet(&mut buf).map_err(|e| DapError::usb(command, e))?;
+        log::trace!("read len = {}", len);
+        buf.truncate(len);
+        log::trace!("{:02X?}", buf);
+        Ok(buf)
+    }
+
+    /// Send a single command and decode its response.
+    pub fn command(&mut self, cmd: &Command) -> Result<Response, DapError> {
+        self.check_command(cmd)?;
+        let mut request = Vec::new();
+        cmd.encode(&mut request);
+        let buf = self.transfer(&request)?;
+        let (response, _) = cmd.decode_response(&buf)?;
+        Ok(response)
+    }
+
+    /// Send independent commands, one per packet, pipelined up to the packet count.
+    pub fn commands(&mut self, cmds: &[Command]) -> Result<Vec<Response>, DapError> {
+        let mut requests = Vec::with_capacity(cmds.len());
+        for cmd in cmds {
+            self.check_command(cmd)?;
+            let mut request = Vec::new();
+            cmd.encode(&mut request);
+            if request.len() > self.packet_size {
+                return Err(DapError::RequestTooLarge { command: cmd.id(), len: request.len() });
+            }
+            requests.push(request);
+        }
+        let command = cmds.first().map_or(ID_DAP_Info, Command::id);
+        let bufs = self.transport.transfer_pipelined(&requests).map_err(|e| DapError::usb(command, e))?;
+        let mut responses = Vec::with_capacity(cmds.len());
+        for (cmd, buf) in cmds.iter().zip(bufs) {
+            log::trace!("{:02X?}", buf);
+            let (response, _) = cmd.decode_response(&buf)?;
+            responses.push(response);
+        }
+        Ok(responses)
+    }
+
+    /// Execute a chain of DAP_ExecuteCommands packets and return their responses in order.
+    ///
+    /// If the probe supports it, up to packet count packets are sent back-to-back with all
+    /// but the last one turned into DAP_QueueCommands, so the probe runs them without waiting
+    /// for the host in between. Otherwise the packets are pipelined.
+    ///
+    /// Only for the packets of [`CommandBatch`], which are all DAP_ExecuteCommands.
+    pub(crate) fn execute_chain(&mut self, packets: &[Command]) -> Result<Vec<Response>, DapError> {
+        if !self.queue_commands {
+            return self.commands(packets);
+        }
+        for packet in packets {
+            self.check_command(packet)?;
+        }
+        let mut responses = Vec::with_capacity(packets.len());
+        for chain in packets.chunks(self.packet_count) {
+            for (n, packet) in chain.iter().enumerate() {
+                let mut request = Vec::new();
+                packet.encode(&mut request);
+                if n + 1 < chain.len() {
+                    debug_assert!(request[0] == ID_DAP_ExecuteCommands);
+                    request[0] = ID_DAP_QueueCommands;
+                }
+                if let Err(e) = self.send(&request) {
+                    discard_responses(&mut self.transport, n);
+                    return Err(e);
+                }
+            }
+            // every response of the chain is read before any is decoded, so none is left behind
+            let mut bufs = Vec::with_capacity(chain.len());
+            for (n, packet) in chain.iter().enumerate() {
+                match self.receive(packet.id()) {
+                    Ok(buf) => bufs.push(buf),
+                    Err(e) => {
+                        discard_responses(&mut self.transport, chain.len() - n);
+                        return Err(e);
+                    }
+                }
+            }
+            for (packet, mut buf) in chain.iter().zip(bufs) {
+                // queued packets are answered as DAP_ExecuteCommands
+                if buf.first() == Some(&ID_DAP_QueueCommands) {
+                    buf[0] = ID_DAP_ExecuteCommands;
+                }
+                let (response, _) = packet.decode_response(&buf)?;
+                responses.push(response);
+            }
+        }
+        Ok(responses)
+    }
+
+    /// Execute `cmds`, batched into as few packets as possible, and return their responses in order.
+    pub fn execute_commands(&mut self, cmds: Vec<Command>) -> Result<Vec<Response>, DapError> {
+        CommandBatch::from(cmds).execute(self)
+    }
+}
+
+/// Issue a single DAP_Info request and return the info data.
+fn query_info<T: DapTransport>(transport: &mut T, id: u8) -> Result<Vec<u8>, DapError> {
+    let usb = |e| DapError::usb(ID_DAP_Info, e);
+    transport.send_packet(&[ID_DAP_Info, id]).map_err(usb)?;
+    let mut buf = vec![0u8; MAX_PACKET_SIZE.max(transport.packet_size())];
+    let len = transport.receive_packet(&mut buf).map_err(usb)?;
+    match Command::Info(id).decode_response(&buf[..len])? {
+        (Response::Info(data), _) => Ok(data),
+        _ => unreachable!(),
+    }
+}
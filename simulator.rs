@@ -0,0 +1,1481 @@
+use std::collections::{BTreeMap, VecDeque};
+use std::convert::TryInto;
+use std::time::Duration;
+
+use crate::transport::DapTransport;
+use crate::command::*;
+use crate::dp::*;
+use crate::memory::*;
+use crate::jtag::TapState;
+use crate::itm::{ITM_STIM0, ITM_TCR, ITM_TCR_ITMENA, ITM_TER};
+
+/// ACK of a single SWD transaction as seen by the probe.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum SwdAck {
+    Ok,
+    Wait,
+    Fault,
+    NoAck,
+}
+
+impl SwdAck {
+    fn to_dap(self) -> u8 {
+        match self {
+            SwdAck::Ok => DAP_TRANSFER_OK,
+            SwdAck::Wait => DAP_TRANSFER_WAIT,
+            SwdAck::Fault => DAP_TRANSFER_FAULT,
+            SwdAck::NoAck => 0x07,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum LineMode {
+    Jtag,
+    /// JTAG-to-SWD select sequence has been seen, waiting for a line reset.
+    SwdPending,
+    Swd,
+    /// Waiting for the selection alert and an activation code.
+    Dormant,
+}
+
+/// Selection alert which wakes a dormant SWJ-DP, in the order it is sent.
+const SELECTION_ALERT: u128 = 0x19BC_0EA2_E3DD_AFE9_8685_2D95_6209_F392;
+
+/// SWD activation code after the 4 cycles which follow the selection alert.
+const SWD_ACTIVATION_CODE: u16 = 0x1A << 4;
+
+/// Sparse model of the target address space.
+/// Only addresses inside a mapped region respond, everything else is a bus error.
+pub struct Memory {
+    regions: Vec<(u32, u32)>,
+    words: BTreeMap<u32, u32>,
+}
+
+impl Memory {
+    fn new() -> Self {
+        Memory { regions: Vec::new(), words: BTreeMap::new() }
+    }
+
+    /// Map `size` bytes starting at `start`. Unwritten words read as zero.
+    pub fn add_region(&mut self, start: u32, size: u32) {
+        self.regions.push((start, size));
+    }
+
+    pub fn is_mapped(&self, addr: u32) -> bool {
+        self.regions.iter().any(|&(start, size)| addr >= start && (addr - start) < size)
+    }
+
+    pub fn read(&self, addr: u32) -> Option<u32> {
+        if !self.is_mapped(addr) {
+            return None;
+        }
+        Some(*self.words.get(&(addr & !3)).unwrap_or(&0))
+    }
+
+    /// Write the byte lanes selected by `lanes` (bit n = byte n of the word).
+    pub fn write(&mut self, addr: u32, value: u32, lanes: u8) -> Option<()> {
+        if !self.is_mapped(addr) {
+            return None;
+        }
+        let mut mask = 0u32;
+        for n in 0..4 {
+            if lanes & (1 << n) != 0 {
+                mask |= 0xFF << (n * 8);
+            }
+        }
+        let word = self.words.entry(addr & !3).or_insert(0);
+        *word = (*word & !mask) | (value & mask);
+        Some(())
+    }
+}
+
+/// Model of an ADIv5 SWJ-DP with a single AHB MEM-AP behind it.
+pub struct SwdTarget {
+    pub idcode: u32,
+    pub ap_idr: u32,
+    pub memory: Memory,
+    /// Number of upcoming AP accesses answered with WAIT, as while a slow bus transfer is pending.
+    pub busy: u32,
+    /// Bytes the target has sent on SWO which the probe has not captured yet.
+    pub swo: VecDeque<u8>,
+    /// Bytes the target has sent on its UART which the probe has not received yet.
+    /// The target echoes everything it receives.
+    pub uart: VecDeque<u8>,
+    /// TARGETSEL value which selects this DPv2 on a multi-drop bus, `None` for a single-drop DP.
+    pub targetsel: Option<u32>,
+    mode: LineMode,
+    lockout: bool,
+    /// A TARGETSEL write for another DP was seen, the DP stays locked out until the next line reset.
+    deselected: bool,
+    ones: u32,
+    shift: u16,
+    alert: u128,
+    /// Bits after the selection alert, and how many there are.
+    activation: Option<(u16, u8)>,
+    /// Request bits of an SWD packet driven through DAP_SWD_Sequence, and how many there are.
+    wire_request: (u8, u8),
+    /// A write request which was acknowledged and waits for its data phase.
+    wire_write: Option<u8>,
+    wire_data: (u64, u8),
+    /// What the target drives on SWDIO next.
+    wire_response: VecDeque<bool>,
+    ctrl_stat: u32,
+    select: u32,
+    rdbuff: u32,
+    csw: u32,
+    tar: u32,
+}
+
+impl SwdTarget {
+    /// A Cortex-M4 like target with flash, SRAM and the System Control Space mapped.
+    pub fn new() -> Self {
+        let mut memory = Memory::new();
+        memory.add_region(0x0000_0000, 0x0004_0000); // Flash
+        memory.add_region(0x2000_0000, 0x0001_0000); // SRAM
+        memory.add_region(0xE000_0000, 0x0010_0000); // PPB
+        memory.write(0xE000_ED00, 0x410F_C241, 0xF); // CPUID : Cortex-M4 r0p1
+        SwdTarget {
+            idcode: 0x2BA0_1477,
+            ap_idr: 0x0477_0021,
+            memory,
+            busy: 0,
+            swo: VecDeque::new(),
+            uart: VecDeque::new(),
+            targetsel: None,
+            mode: LineMode::Jtag,
+            lockout: false,
+            deselected: false,
+            ones: 0,
+            shift: 0,
+            alert: 0,
+            activation: None,
+            wire_request: (0, 0),
+            wire_write: None,
+            wire_data: (0, 0),
+            wire_response: VecDeque::new(),
+            ctrl_stat: 0,
+            select: 0,
+            rdbuff: 0,
+            csw: 0x0300_0040,
+            tar: 0,
+        }
+    }
+
+    /// A DPv2 target like one core of an RP2040, selected by `targetsel` on a multi-drop bus.
+    /// It starts out dormant.
+    pub fn multidrop(targetsel: u32) -> Self {
+        SwdTarget { idcode: 0x0BC1_2477, targetsel: Some(targetsel), mode: LineMode::Dormant, ..SwdTarget::new() }
+    }
+
+    /// Feed SWDIO bits driven by the probe, LSB first.
+    fn clock_bits(&mut self, count: usize, data: &[u8]) {
+        self.reset_wire();
+        for n in 0..count {
+            self.clock_bit((data[n / 8] >> (n % 8)) & 1 != 0);
+        }
+    }
+
+    fn clock_bit(&mut self, bit: bool) {
+        if self.mode == LineMode::Dormant {
+            self.dormant_bit(bit);
+            return;
+        }
+        self.shift = (self.shift >> 1) | ((bit as u16) << 15);
+        if self.shift == 0xE79E && self.mode == LineMode::Jtag {
+            self.mode = LineMode::SwdPending;
+        }
+        if self.shift == 0xE73C && self.mode != LineMode::Jtag {
+            self.mode = LineMode::Jtag;
+        }
+        if self.shift == 0xE3BC && self.mode != LineMode::Jtag {
+            self.mode = LineMode::Dormant;
+            self.alert = 0;
+            self.activation = None;
+            return;
+        }
+        if bit {
+            self.ones += 1;
+            return;
+        }
+        if self.ones >= 50 {
+            self.line_reset();
+        }
+        self.ones = 0;
+    }
+
+    fn dormant_bit(&mut self, bit: bool) {
+        if let Some((code, len)) = self.activation {
+            let code = code | (bit as u16) << len;
+            self.activation = Some((code, len + 1));
+            if len + 1 == 12 {
+                if code == SWD_ACTIVATION_CODE {
+                    self.mode = LineMode::SwdPending;
+                    self.ones = 0;
+                }
+                self.activation = None;
+            }
+            return;
+        }
+        self.alert = (self.alert >> 1) | ((bit as u128) << 127);
+        if self.alert == SELECTION_ALERT {
+            self.activation = Some((0, 0));
+        }
+    }
+
+    fn reset_wire(&mut self) {
+        self.wire_request = (0, 0);
+        self.wire_write = None;
+        self.wire_data = (0, 0);
+        self.wire_response.clear();
+    }
+
+    /// SWDIO driven by the probe during DAP_SWD_Sequence, decoded as SWD packets.
+    ///
+    /// The ACK of a write is given before its data arrives, so only a locked out DP
+    /// refuses it. The write itself happens once data and parity are complete.
+    /// TARGETSEL is taken in although no DP acknowledges it.
+    fn wire_out(&mut self, bit: bool) {
+        self.clock_bit(bit);
+        if self.mode != LineMode::Swd || self.ones >= 50 {
+            self.reset_wire();
+            return;
+        }
+        // the probe drives the line again, the target is done
+        self.wire_response.clear();
+        if let Some(request) = self.wire_write {
+            let (data, len) = self.wire_data;
+            let data = data | (bit as u64) << len;
+            self.wire_data = (data, len + 1);
+            if len + 1 == 33 {
+                let value = data as u32;
+                if (data >> 32) as u32 == value.count_ones() % 2 {
+                    self.transfer(request, value).ok();
+                }
+                self.wire_write = None;
+                self.wire_data = (0, 0);
+            }
+            return;
+        }
+        let (request, len) = self.wire_request;
+        if len == 0 && !bit {
+            return; // idle cycle
+        }
+        let request = request | (bit as u8) << len;
+        if len + 1 < 8 {
+            self.wire_request = (request, len + 1);
+            return;
+        }
+        self.wire_request = (0, 0);
+        // start, stop and park bits, even parity over APnDP, RnW, A2 and A3
+        let fields = (request >> 1) & 0x0F;
+        if request & 0xC1 != 0x81 || fields.count_ones() % 2 != ((request >> 5) & 1) as u32 {
+            return;
+        }
+        // turnaround
+        self.wire_response.push_back(true);
+        let ack = if fields & DAP_TRANSFER_RnW != 0 {
+            match self.transfer(fields, 0) {
+                Ok(value) => {
+                    self.push_ack(SwdAck::Ok);
+                    self.wire_response.extend((0..32).map(|n| value & (1 << n) != 0));
+                    self.wire_response.push_back(value.count_ones() % 2 == 1);
+                    return;
+                }
+                Err(ack) => ack,
+            }
+        } else if self.lockout {
+            if fields == DP_TARGETSEL && self.targetsel.is_some() {
+                self.wire_write = Some(fields);
+            }
+            SwdAck::NoAck
+        } else {
+            self.wire_write = Some(fields);
+            SwdAck::Ok
+        };
+        self.push_ack(ack);
+    }
+
+    fn push_ack(&mut self, ack: SwdAck) {
+        // nothing driven reads as high
+        if ack != SwdAck::NoAck {
+            let ack = ack.to_dap();
+            self.wire_response.extend((0..3).map(|n| ack & (1 << n) != 0));
+        }
+    }
+
+    /// SWDIO as read by the probe during DAP_SWD_Sequence, pulled up while not driven.
+    fn wire_in(&mut self) -> bool {
+        self.wire_response.pop_front().unwrap_or(true)
+    }
+
+    fn line_reset(&mut self) {
+        match self.mode {
+            LineMode::Jtag | LineMode::Dormant => (),
+            LineMode::SwdPending | LineMode::Swd => {
+                self.mode = LineMode::Swd;
+                // the DP does not respond until IDCODE is read
+                self.lockout = true;
+                self.deselected = false;
+            }
+        }
+    }
+
+    fn transfer(&mut self, request: u8, value: u32) -> Result<u32, SwdAck> {
+        let addr = request & (DAP_TRANSFER_A2 | DAP_TRANSFER_A3);
+        let read = request & DAP_TRANSFER_RnW != 0;
+        if self.mode != LineMode::Swd {
+            return Err(SwdAck::NoAck);
+        }
+        if self.lockout {
+            if request & DAP_TRANSFER_APnDP == 0 && !read && addr == DP_TARGETSEL && self.targetsel.is_some() {
+                self.deselected = self.targetsel != Some(value);
+                return Err(SwdAck::NoAck);
+            }
+            if self.deselected {
+                return Err(SwdAck::NoAck);
+            }
+            if request & DAP_TRANSFER_APnDP == 0 && read && addr == DP_IDCODE {
+                self.lockout = false;
+            } else {
+                return Err(SwdAck::NoAck);
+            }
+        }
+        if request & DAP_TRANSFER_APnDP == 0 {
+            Ok(self.dp_access(addr, read, value))
+        } else {
+            self.ap_access(addr, read, value)
+        }
+    }
+
+    /// A DPACC or APACC scan through the JTAG-DP.
+    fn jtag_transfer(&mut self, request: u8, value: u32) -> Result<u32, SwdAck> {
+        if self.mode != LineMode::Jtag {
+            return Err(SwdAck::NoAck);
+        }
+        let addr = request & (DAP_TRANSFER_A2 | DAP_TRANSFER_A3);
+        let read = request & DAP_TRANSFER_RnW != 0;
+        if request & DAP_TRANSFER_APnDP == 0 {
+            Ok(self.dp_access(addr, read, value))
+        } else {
+            self.ap_access(addr, read, value)
+        }
+    }
+
+    fn dp_access(&mut self, addr: u8, read: bool, value: u32) -> u32 {
+        if read {
+            match addr {
+                DP_IDCODE => self.idcode,
+                DP_CTRL_STAT if self.select & 0xF == 0 => self.ctrl_stat,
+                // the part and designer fields of TARGETSEL, TINSTANCE and DPv2 multi-drop support
+                DP_CTRL_STAT if self.select & 0xF == DP_BANK_TARGETID => self.targetsel.map_or(0, |t| t & 0x0FFF_FFFF),
+                DP_CTRL_STAT if self.select & 0xF == DP_BANK_DLPIDR => self.targetsel.map_or(0, |t| t & 0xF000_0000 | 1),
+                DP_RESEND | DP_RDBUFF => self.rdbuff,
+                _ => 0,
+            }
+        } else {
+            match addr {
+                DP_ABORT => {
+                    if value & DAPABORT != 0 {
+                        self.busy = 0;
+                    }
+                    if value & ORUNERRCLR != 0 {
+                        self.ctrl_stat &= !STICKYORUN;
+                    }
+                    if value & WDERRCLR != 0 {
+                        self.ctrl_stat &= !WDATAERR;
+                    }
+                    if value & STKERRCLR != 0 {
+                        self.ctrl_stat &= !STICKYERR;
+                    }
+                    if value & STKCMPCLR != 0 {
+                        self.ctrl_stat &= !STICKYCMP;
+                    }
+                }
+                DP_CTRL_STAT if self.select & 0xF == 0 => {
+                    let sticky = STICKYORUN | STICKYCMP | STICKYERR | WDATAERR;
+                    let mut ctrl_stat = (self.ctrl_stat & sticky) | (value & !sticky & !(CSYSPWRUPACK | CDBGPWRUPACK));
+                    // power domains come up immediately
+                    if ctrl_stat & CSYSPWRUPREQ != 0 {
+                        ctrl_stat |= CSYSPWRUPACK;
+                    }
+                    if ctrl_stat & CDBGPWRUPREQ != 0 {
+                        ctrl_stat |= CDBGPWRUPACK;
+                    }
+                    self.ctrl_stat = ctrl_stat;
+                }
+                DP_SELECT => self.select = value,
+                _ => (),
+            }
+            0
+        }
+    }
+
+    fn ap_access(&mut self, addr: u8, read: bool, value: u32) -> Result<u32, SwdAck> {
+        if self.ctrl_stat & STICKYERR != 0 {
+            return Err(SwdAck::Fault);
+        }
+        if self.busy > 0 {
+            self.busy -= 1;
+            return Err(SwdAck::Wait);
+        }
+        if self.ctrl_stat & CDBGPWRUPACK == 0 {
+            self.ctrl_stat |= STICKYERR;
+            return Err(SwdAck::Fault);
+        }
+        let apsel = self.select >> 24;
+        let reg = (self.select & 0xF0) as u8 | addr;
+        if apsel != 0 {
+            // no such AP, reads as zero
+            self.rdbuff = 0;
+            return Ok(0);
+        }
+        let result = match (reg, read) {
+            (AP_CSW, true) => Some(self.csw),
+            (AP_CSW, false) => {
+                self.csw = (value & 0xFF00_0F77) | 0x40; // DeviceEn
+                Some(0)
+            }
+            (AP_TAR, true) => Some(self.tar),
+            (AP_TAR, false) => {
+                self.tar = value;
+                Some(0)
+            }
+            (AP_DRW, _) => self.drw_access(self.tar, read, value),
+            (r, _) if (AP_BD0..AP_BD0 + 0x10).contains(&r) => {
+                let addr = (self.tar & !0xF) | (r & 0xC) as u32;
+                self.mem_access(addr, read, value, 0xF)
+            }
+            (AP_CFG, true) => Some(0),
+            (AP_BASE, true) => Some(0xE00F_F003),
+            (AP_IDR, true) => Some(self.ap_idr),
+            (_, true) => Some(0),
+            (_, false) => Some(0),
+        };
+        match result {
+            Some(data) => {
+                if read {
+                    self.rdbuff = data;
+                }
+                Ok(data)
+            }
+            None => {
+                self.ctrl_stat |= STICKYERR;
+                Err(SwdAck::Fault)
+            }
+        }
+    }
+
+    fn drw_access(&mut self, addr: u32, read: bool, value: u32) -> Option<u32> {
+        let size = 1u32 << (self.csw & 0x7).min(2);
+        let lanes = (((1u32 << size) - 1) << (addr & 3)) as u8;
+        let data = self.mem_access(addr, read, value, lanes)?;
+        if (self.csw >> 4) & 0x3 == 0x1 {
+            // single auto increment, wraps within a 1KB boundary
+            self.tar = (self.tar & !0x3FF) | (self.tar.wrapping_add(size) & 0x3FF);
+        }
+        Some(data)
+    }
+
+    fn mem_access(&mut self, addr: u32, read: bool, value: u32, lanes: u8) -> Option<u32> {
+        if read {
+            self.memory.read(addr)
+        } else {
+            self.memory.write(addr, value, lanes)?;
+            if (ITM_STIM0..ITM_STIM0 + 0x80).contains(&addr) {
+                self.itm_write((addr - ITM_STIM0) as u8 / 4, value, lanes);
+            }
+            Some(0)
+        }
+    }
+
+    /// Send the instrumentation packet for a stimulus port write on SWO, if the port is enabled.
+    fn itm_write(&mut self, port: u8, value: u32, lanes: u8) {
+        let tcr = self.memory.read(ITM_TCR).unwrap_or(0);
+        let ter = self.memory.read(ITM_TER).unwrap_or(0);
+        if tcr & ITM_TCR_ITMENA == 0 || ter & (1 << port) == 0 {
+            return;
+        }
+        let bytes: Vec<u8> = (0..4).filter(|n| lanes & (1 << n) != 0).map(|n| (value >> (8 * n)) as u8).collect();
+        let size = match bytes.len() {
+            1 => 0b01,
+            2 => 0b10,
+            _ => 0b11,
+        };
+        self.swo.push_back(port << 3 | size);
+        self.swo.extend(bytes);
+    }
+}
+
+impl Default for SwdTarget {
+    fn default() -> Self {
+        SwdTarget::new()
+    }
+}
+
+/// IDCODE instruction of an ARM JTAG-DP.
+const JTAG_DP_IDCODE: u32 = 0xE;
+
+/// One device in a JTAG scan chain.
+///
+/// Only the BYPASS, IDCODE and boundary scan data registers are modelled.
+pub struct JtagTap {
+    pub ir_len: u8,
+    pub idcode: Option<u32>,
+    /// Instruction which selects the IDCODE register.
+    pub idcode_instruction: u32,
+    /// Value loaded into the instruction register in Capture-IR, ending in 0b01.
+    pub ir_capture: u32,
+    /// Whether this is the JTAG-DP of the [`SwdTarget`], reachable with DAP_Transfer.
+    pub dp: bool,
+    pub boundary: Option<BoundaryRegister>,
+    ir: u32,
+    shift: u64,
+    shift_len: u32,
+}
+
+impl JtagTap {
+    pub fn new(ir_len: u8, idcode: Option<u32>, idcode_instruction: u32) -> Self {
+        let mut tap = JtagTap {
+            ir_len,
+            idcode,
+            idcode_instruction,
+            ir_capture: 0b01,
+            dp: false,
+            boundary: None,
+            ir: 0,
+            shift: 0,
+            shift_len: 1,
+        };
+        tap.reset();
+        tap
+    }
+
+    /// The JTAG-DP of an SWJ-DP as found on Cortex-M.
+    pub fn arm_dp(idcode: u32) -> Self {
+        let mut tap = JtagTap::new(4, Some(idcode), JTAG_DP_IDCODE);
+        tap.dp = true;
+        tap
+    }
+
+    fn bypass(&self) -> u32 {
+        ((1u64 << self.ir_len) - 1) as u32
+    }
+
+    fn reset(&mut self) {
+        // IDCODE is selected after reset, BYPASS on devices without one
+        self.ir = if self.idcode.is_some() { self.idcode_instruction } else { self.bypass() };
+    }
+
+    fn capture_ir(&mut self) {
+        self.shift = self.ir_capture as u64;
+        self.shift_len = self.ir_len as u32;
+    }
+
+    fn update_ir(&mut self) {
+        self.ir = self.shift as u32 & self.bypass();
+    }
+
+    fn capture_dr(&mut self) {
+        match (self.idcode, &self.boundary) {
+            (Some(idcode), _) if self.ir == self.idcode_instruction => {
+                self.shift = idcode as u64;
+                self.shift_len = 32;
+            }
+            (_, Some(boundary)) if boundary.is_selected(self.ir) => {
+                self.shift = boundary.pins;
+                self.shift_len = boundary.len;
+            }
+            _ => {
+                self.shift = 0;
+                self.shift_len = 1;
+            }
+        }
+    }
+
+    fn update_dr(&mut self) {
+        let ir = self.ir;
+        if let Some(boundary) = self.boundary.as_mut().filter(|boundary| boundary.is_selected(ir)) {
+            boundary.update = self.shift;
+        }
+    }
+}
+
+/// Boundary register of a [`JtagTap`], at most 64 cells.
+pub struct BoundaryRegister {
+    pub len: u32,
+    pub sample_instruction: u32,
+    pub extest_instruction: u32,
+    /// Levels captured from the pins.
+    pub pins: u64,
+    /// Update latch, what the outputs drive during EXTEST.
+    pub update: u64,
+}
+
+impl BoundaryRegister {
+    fn is_selected(&self, ir: u32) -> bool {
+        ir == self.sample_instruction || ir == self.extest_instruction
+    }
+}
+
+/// A JTAG scan chain, index 0 nearest to TDO.
+pub struct JtagChain {
+    pub taps: Vec<JtagTap>,
+    state: TapState,
+}
+
+impl JtagChain {
+    pub fn new(taps: Vec<JtagTap>) -> Self {
+        let mut chain = JtagChain { taps, state: TapState::RunTestIdle };
+        chain.enter(TapState::TestLogicReset);
+        chain
+    }
+
+    /// One TCK cycle. Returns the TDO level.
+    pub fn clock(&mut self, tms: bool, tdi: bool) -> bool {
+        let mut tdo = false;
+        if matches!(self.state, TapState::ShiftDr | TapState::ShiftIr) {
+            let mut input = tdi;
+            for tap in self.taps.iter_mut().rev() {
+                let output = tap.shift & 1 != 0;
+                tap.shift = (tap.shift >> 1) | ((input as u64) << (tap.shift_len - 1));
+                input = output;
+            }
+            tdo = input;
+        }
+        self.enter(self.state.next(tms));
+        tdo
+    }
+
+    fn enter(&mut self, state: TapState) {
+        self.state = state;
+        for tap in &mut self.taps {
+            match state {
+                TapState::TestLogicReset => tap.reset(),
+                TapState::CaptureIr => tap.capture_ir(),
+                TapState::UpdateIr => tap.update_ir(),
+                TapState::CaptureDr => tap.capture_dr(),
+                TapState::UpdateDr => tap.update_dr(),
+                _ => (),
+            }
+        }
+    }
+}
+
+/// Size of the simulated SWO trace buffer.
+const SWO_BUFFER_SIZE: usize = 4096;
+
+/// Clock the SWO baudrate is divided from.
+const SWO_CLOCK: u32 = 72_000_000;
+const SWO_MAX_BAUDRATE: u32 = SWO_CLOCK / 2;
+
+/// Size of each of the simulated UART buffers.
+const UART_BUFFER_SIZE: usize = 1024;
+
+/// Clock the UART baudrate is divided from.
+const UART_CLOCK: u32 = 48_000_000;
+
+/// A CMSIS-DAP probe implemented in software.
+///
+/// Commands are answered the way the CMSIS-DAP firmware does, with an
+/// [`SwdTarget`] attached to the SWD port.
+pub struct SimulatedProbe {
+    pub vendor: Option<String>,
+    pub product: Option<String>,
+    pub serial: Option<String>,
+    pub fw_version: Option<String>,
+    /// DAP_Info Capabilities, the second byte is only reported when non-zero.
+    pub capabilities: u16,
+    /// Whether DAP_QueueCommands is understood, as on firmware 1.1 and later.
+    pub queue_commands: bool,
+    pub target: SwdTarget,
+    /// Further DPs on the SWD bus of `target`, each with its own `targetsel`.
+    pub multidrop: Vec<SwdTarget>,
+    /// JTAG scan chain, by default just the JTAG-DP of the target.
+    pub jtag: JtagChain,
+    packet_size: usize,
+    packet_count: usize,
+    port: u8,
+    clock: u32,
+    pins: u8,
+    swo_transport: u8,
+    swo_mode: u8,
+    swo_active: bool,
+    swo_overrun: bool,
+    /// Bytes captured since the start, the Index of DAP_SWO_ExtendedStatus.
+    swo_index: u32,
+    swo_buffer: VecDeque<u8>,
+    uart_transport: u8,
+    uart_rx_enabled: bool,
+    uart_tx_enabled: bool,
+    uart_rx_lost: bool,
+    uart_rx: VecDeque<u8>,
+    ir_lengths: Vec<u8>,
+    match_mask: u32,
+    match_retry: u16,
+    wait_retry: u16,
+    timestamp: u32,
+    queued: Vec<Vec<u8>>,
+    responses: VecDeque<Vec<u8>>,
+}
+
+impl SimulatedProbe {
+    pub fn new() -> Self {
+        SimulatedProbe {
+            vendor: Some(String::from("ARM")),
+            product: Some(String::from("Simulated CMSIS-DAP")),
+            serial: Some(String::from("0123456789")),
+            fw_version: Some(String::from("2.1.0")),
+            capabilities: 0x0097, // SWD, JTAG, SWO UART, atomic commands, UART
+            queue_commands: true,
+            target: SwdTarget::new(),
+            multidrop: Vec::new(),
+            jtag: JtagChain::new(vec![JtagTap::arm_dp(0x4BA0_0477)]),
+            packet_size: 64,
+            packet_count: 1,
+            port: 0,
+            clock: 1_000_000,
+            pins: 0xFF,
+            swo_transport: DAP_SWO_TRANSPORT_NONE,
+            swo_mode: DAP_SWO_MODE_OFF,
+            swo_active: false,
+            swo_overrun: false,
+            swo_index: 0,
+            swo_buffer: VecDeque::new(),
+            uart_transport: DAP_UART_TRANSPORT_NONE,
+            uart_rx_enabled: false,
+            uart_tx_enabled: false,
+            uart_rx_lost: false,
+            uart_rx: VecDeque::new(),
+            ir_lengths: Vec::new(),
+            match_mask: 0,
+            match_retry: 0,
+            wait_retry: 100,
+            timestamp: 0,
+            queued: Vec::new(),
+            responses: VecDeque::new(),
+        }
+    }
+
+    pub fn set_packet_size(&mut self, packet_size: usize) {
+        self.packet_size = packet_size;
+    }
+
+    pub fn set_packet_count(&mut self, packet_count: usize) {
+        self.packet_count = packet_count;
+    }
+
+    /// Current SWJ clock frequency in Hz.
+    pub fn clock(&self) -> u32 {
+        self.clock
+    }
+
+    /// Process one request packet and produce its response packet.
+    pub fn process_packet(&mut self, request: &[u8]) -> Vec<u8> {
+        let mut response = Vec::new();
+        self.process_command(request, &mut response);
+        response
+    }
+
+    /// Execute the command at the start of `request`, appending its response.
+    /// Returns the length of the request, or `None` if the command was not understood.
+    fn process_command(&mut self, request: &[u8], response: &mut Vec<u8>) -> Option<usize> {
+        let id = *request.first()?;
+        let len = match id {
+            ID_DAP_Info => self.info(request, response),
+            ID_DAP_Connect => {
+                let port = *request.get(1)?;
+                self.port = match port {
+                    0 | DAP_PORT_SWD => DAP_PORT_SWD,
+                    DAP_PORT_JTAG if self.capabilities & 0x02 != 0 => DAP_PORT_JTAG,
+                    _ => 0,
+                };
+                response.extend([ID_DAP_Connect, self.port]);
+                Some(2)
+            }
+            ID_DAP_Disconnect => {
+                self.port = 0;
+                response.extend([ID_DAP_Disconnect, DAP_OK]);
+                Some(1)
+            }
+            ID_DAP_SWJ_Clock => {
+                let clock = u32::from_le_bytes(request.get(1..5)?.try_into().unwrap());
+                let status = if clock == 0 {
+                    DAP_ERROR
+                } else {
+                    self.clock = clock;
+                    DAP_OK
+                };
+                response.extend([ID_DAP_SWJ_Clock, status]);
+                Some(5)
+            }
+            ID_DAP_SWJ_Sequence => {
+                let count = match *request.get(1)? {
+                    0 => 256,
+                    n => n as usize,
+                };
+                let data = request.get(2..2 + count.div_ceil(8))?;
+                if self.port != 0 {
+                    // SWDIO and TMS share a pin
+                    for target in self.swd_targets() {
+                        target.clock_bits(count, data);
+                    }
+                }
+                if self.port == DAP_PORT_JTAG {
+                    for n in 0..count {
+                        self.jtag.clock(data[n / 8] & (1 << (n % 8)) != 0, true);
+                    }
+                }
+                response.extend([ID_DAP_SWJ_Sequence, DAP_OK]);
+                Some(2 + data.len())
+            }
+            ID_DAP_SWJ_Pins => {
+                let output = *request.get(1)?;
+                let select = *request.get(2)?;
+                request.get(3..7)?;
+                let pins = (self.pins & !select) | (output & select);
+                if self.pins & DAP_SWJ_nTRST != 0 && pins & DAP_SWJ_nTRST == 0 {
+                    // nTRST puts every TAP into Test-Logic-Reset
+                    for _ in 0..5 {
+                        self.jtag.clock(true, true);
+                    }
+                }
+                self.pins = pins;
+                response.extend([ID_DAP_SWJ_Pins, self.pins]);
+                Some(7)
+            }
+            ID_DAP_Delay => {
+                request.get(1..3)?;
+                response.extend([ID_DAP_Delay, DAP_OK]);
+                Some(3)
+            }
+            ID_DAP_TransferConfigure => {
+                // idle cycles take no time here
+                self.wait_retry = u16::from_le_bytes(request.get(2..4)?.try_into().unwrap());
+                self.match_retry = u16::from_le_bytes(request.get(4..6)?.try_into().unwrap());
+                response.extend([ID_DAP_TransferConfigure, DAP_OK]);
+                Some(6)
+            }
+            ID_DAP_Transfer => self.transfer(request, response),
+            ID_DAP_TransferBlock => self.transfer_block(request, response),
+            ID_DAP_SWD_Configure if self.capabilities & 0x01 != 0 => {
+                // the target's turnaround period is fixed at one cycle
+                let config = *request.get(1)?;
+                let status = if config & !(DAP_SWD_TURNAROUND_MASK | DAP_SWD_DATA_PHASE) == 0 { DAP_OK } else { DAP_ERROR };
+                response.extend([ID_DAP_SWD_Configure, status]);
+                Some(2)
+            }
+            ID_DAP_SWD_Sequence if self.capabilities & 0x01 != 0 => self.swd_sequence(request, response),
+            ID_DAP_JTAG_Configure => {
+                let count = *request.get(1)? as usize;
+                self.ir_lengths = request.get(2..2 + count)?.to_vec();
+                response.extend([ID_DAP_JTAG_Configure, DAP_OK]);
+                Some(2 + count)
+            }
+            ID_DAP_JTAG_Sequence => self.jtag_sequence(request, response),
+            ID_DAP_JTAG_IDCODE => {
+                let index = *request.get(1)? as usize;
+                match self.jtag_idcode(index) {
+                    Some(idcode) => {
+                        response.extend([ID_DAP_JTAG_IDCODE, DAP_OK]);
+                        response.extend(idcode.to_le_bytes());
+                    }
+                    None => response.extend([ID_DAP_JTAG_IDCODE, DAP_ERROR]),
+                }
+                Some(2)
+            }
+            ID_DAP_WriteABORT => {
+                let value = u32::from_le_bytes(request.get(2..6)?.try_into().unwrap());
+                // DP_ABORT can be written regardless of the state of the DP
+                let status = if self.port != 0 {
+                    for target in self.swd_targets().filter(|target| !target.deselected) {
+                        target.dp_access(DP_ABORT, false, value);
+                    }
+                    DAP_OK
+                } else {
+                    DAP_ERROR
+                };
+                response.extend([ID_DAP_WriteABORT, status]);
+                Some(6)
+            }
+            ID_DAP_SWO_Transport | ID_DAP_SWO_Mode | ID_DAP_SWO_Control if self.capabilities & 0x0C != 0 => {
+                let value = *request.get(1)?;
+                let valid = match id {
+                    ID_DAP_SWO_Transport => match value {
+                        DAP_SWO_TRANSPORT_NONE | DAP_SWO_TRANSPORT_DATA => true,
+                        DAP_SWO_TRANSPORT_ENDPOINT => self.capabilities & 0x40 != 0,
+                        _ => false,
+                    },
+                    ID_DAP_SWO_Mode => match value {
+                        DAP_SWO_MODE_OFF => true,
+                        DAP_SWO_MODE_UART => self.capabilities & 0x04 != 0,
+                        DAP_SWO_MODE_MANCHESTER => self.capabilities & 0x08 != 0,
+                        _ => false,
+                    },
+                    _ => value == DAP_SWO_CONTROL_STOP || value == DAP_SWO_CONTROL_START,
+                };
+                if valid {
+                    match id {
+                        ID_DAP_SWO_Transport => self.swo_transport = value,
+                        ID_DAP_SWO_Mode => self.swo_mode = value,
+                        _ => {
+                            self.swo_active = value == DAP_SWO_CONTROL_START;
+                            if self.swo_active {
+                                self.swo_buffer.clear();
+                                self.swo_overrun = false;
+                                self.target.swo.clear();
+                            }
+                        }
+                    }
+                }
+                response.extend([id, if valid { DAP_OK } else { DAP_ERROR }]);
+                Some(2)
+            }
+            ID_DAP_SWO_Baudrate if self.capabilities & 0x0C != 0 => {
+                let baudrate = u32::from_le_bytes(request.get(1..5)?.try_into().unwrap());
+                // the baudrate is derived from the SWJ clock, as by a UART prescaler
+                let actual = match baudrate {
+                    0 => 0,
+                    b if b > SWO_MAX_BAUDRATE => 0,
+                    b => SWO_CLOCK / (SWO_CLOCK / b),
+                };
+                response.push(ID_DAP_SWO_Baudrate);
+                response.extend(actual.to_le_bytes());
+                Some(5)
+            }
+            ID_DAP_SWO_Status if self.capabilities & 0x0C != 0 => {
+                self.capture_swo();
+                response.extend([ID_DAP_SWO_Status, self.swo_status()]);
+                response.extend((self.swo_buffer.len() as u32).to_le_bytes());
+                Some(1)
+            }
+            ID_DAP_SWO_ExtendedStatus if self.capabilities & 0x0C != 0 => {
+                let control = *request.get(1)?;
+                self.capture_swo();
+                response.push(ID_DAP_SWO_ExtendedStatus);
+                if control & DAP_SWO_EXT_STATUS != 0 {
+                    response.push(self.swo_status());
+                }
+                if control & DAP_SWO_EXT_COUNT != 0 {
+                    response.extend((self.swo_buffer.len() as u32).to_le_bytes());
+                }
+                if control & DAP_SWO_EXT_INDEX != 0 {
+                    response.extend(self.swo_index.to_le_bytes());
+                    response.extend(self.timestamp.to_le_bytes());
+                }
+                Some(2)
+            }
+            ID_DAP_SWO_Data if self.capabilities & 0x0C != 0 => {
+                let max = u16::from_le_bytes(request.get(1..3)?.try_into().unwrap()) as usize;
+                self.capture_swo();
+                let status = self.swo_status();
+                let count = if self.swo_transport == DAP_SWO_TRANSPORT_DATA {
+                    max.min(self.swo_buffer.len()).min(self.packet_size - 4)
+                } else {
+                    0
+                };
+                response.extend([ID_DAP_SWO_Data, status]);
+                response.extend((count as u16).to_le_bytes());
+                response.extend(self.swo_buffer.drain(..count));
+                self.swo_overrun = false;
+                Some(3)
+            }
+            ID_DAP_UART_Transport if self.capabilities & 0x80 != 0 => {
+                let transport = *request.get(1)?;
+                let valid = match transport {
+                    DAP_UART_TRANSPORT_NONE | DAP_UART_TRANSPORT_DAP_COMMAND => true,
+                    DAP_UART_TRANSPORT_USB_COM_PORT => self.capabilities & 0x0100 != 0,
+                    _ => false,
+                };
+                if valid {
+                    self.uart_transport = transport;
+                }
+                response.extend([ID_DAP_UART_Transport, if valid { DAP_OK } else { DAP_ERROR }]);
+                Some(2)
+            }
+            ID_DAP_UART_Configure if self.capabilities & 0x80 != 0 => {
+                let control = *request.get(1)?;
+                let baudrate = u32::from_le_bytes(request.get(2..6)?.try_into().unwrap());
+                let mut status = 0;
+                if !(5..=8).contains(&(control & DAP_UART_DATA_BITS_MASK)) {
+                    status |= DAP_UART_CFG_ERROR_DATA_BITS;
+                }
+                if control & DAP_UART_PARITY_MASK == DAP_UART_PARITY_MASK {
+                    status |= DAP_UART_CFG_ERROR_PARITY;
+                }
+                if control & DAP_UART_STOP_BITS_MASK == DAP_UART_STOP_BITS_MASK {
+                    status |= DAP_UART_CFG_ERROR_STOP_BITS;
+                }
+                let actual = match baudrate {
+                    0 => 0,
+                    b => UART_CLOCK / (UART_CLOCK / b).max(1),
+                };
+                response.extend([ID_DAP_UART_Configure, status]);
+                response.extend(actual.to_le_bytes());
+                Some(6)
+            }
+            ID_DAP_UART_Control if self.capabilities & 0x80 != 0 => {
+                let control = *request.get(1)?;
+                if control & DAP_UART_CONTROL_RX_BUF_FLUSH != 0 {
+                    self.uart_rx.clear();
+                    self.uart_rx_lost = false;
+                }
+                if control & DAP_UART_CONTROL_RX_DISABLE != 0 {
+                    self.uart_rx_enabled = false;
+                }
+                if control & DAP_UART_CONTROL_RX_ENABLE != 0 {
+                    self.uart_rx_enabled = true;
+                }
+                if control & DAP_UART_CONTROL_TX_DISABLE != 0 {
+                    self.uart_tx_enabled = false;
+                }
+                if control & DAP_UART_CONTROL_TX_ENABLE != 0 {
+                    self.uart_tx_enabled = true;
+                }
+                response.extend([ID_DAP_UART_Control, DAP_OK]);
+                Some(2)
+            }
+            ID_DAP_UART_Status if self.capabilities & 0x80 != 0 => {
+                self.receive_uart();
+                response.extend([ID_DAP_UART_Status, self.uart_status()]);
+                response.extend((self.uart_rx.len() as u32).to_le_bytes());
+                // transmitted bytes reach the target at once
+                response.extend(0u32.to_le_bytes());
+                Some(1)
+            }
+            ID_DAP_UART_Transfer if self.capabilities & 0x80 != 0 => {
+                let count = u16::from_le_bytes(request.get(1..3)?.try_into().unwrap()) as usize;
+                let data = request.get(3..3 + count)?;
+                if self.uart_transport != DAP_UART_TRANSPORT_DAP_COMMAND {
+                    response.extend([ID_DAP_UART_Transfer, DAP_ERROR]);
+                    response.extend([0; 4]);
+                    return Some(3 + count);
+                }
+                let sent = if self.uart_tx_enabled { count } else { 0 };
+                self.target.uart.extend(&data[..sent]);
+                self.receive_uart();
+                let status = self.uart_status();
+                let received = self.uart_rx.len().min(self.packet_size - 6);
+                response.extend([ID_DAP_UART_Transfer, status]);
+                response.extend((sent as u16).to_le_bytes());
+                response.extend((received as u16).to_le_bytes());
+                response.extend(self.uart_rx.drain(..received));
+                self.uart_rx_lost = false;
+                Some(3 + count)
+            }
+            // atomic commands are only implemented when the capability says so
+            ID_DAP_ExecuteCommands if self.capabilities & 0x10 != 0 => {
+                let count = *request.get(1)?;
+                response.extend([ID_DAP_ExecuteCommands, count]);
+                let mut ptr = 2;
+                for _ in 0..count {
+                    ptr += self.process_command(request.get(ptr..)?, response)?;
+                }
+                Some(ptr)
+            }
+            _ => None,
+        };
+        if len.is_none() {
+            response.push(ID_DAP_Invalid);
+        }
+        len
+    }
+
+    fn info(&mut self, request: &[u8], response: &mut Vec<u8>) -> Option<usize> {
+        let id = *request.get(1)?;
+        let string = |s: &Option<String>| -> Vec<u8> {
+            match s {
+                Some(s) => {
+                    let mut data = s.as_bytes().to_vec();
+                    data.push(0); // terminating NUL character
+                    data
+                }
+                None => Vec::new(),
+            }
+        };
+        let data = match id {
+            DAP_ID_VENDOR => string(&self.vendor),
+            DAP_ID_PRODUCT => string(&self.product),
+            DAP_ID_SER_NUM => string(&self.serial),
+            DAP_ID_FW_VER => string(&self.fw_version),
+            DAP_ID_CAPABILITIES => {
+                let bytes = self.capabilities.to_le_bytes();
+                bytes[..if bytes[1] != 0 { 2 } else { 1 }].to_vec()
+            }
+            DAP_ID_PACKET_COUNT => vec![self.packet_count as u8],
+            DAP_ID_PACKET_SIZE => (self.packet_size as u16).to_le_bytes().to_vec(),
+            DAP_ID_SWO_BUFFER_SIZE if self.capabilities & 0x0C != 0 => (SWO_BUFFER_SIZE as u32).to_le_bytes().to_vec(),
+            DAP_ID_UART_RX_BUFFER_SIZE | DAP_ID_UART_TX_BUFFER_SIZE if self.capabilities & 0x80 != 0 => {
+                (UART_BUFFER_SIZE as u32).to_le_bytes().to_vec()
+            }
+            _ => Vec::new(),
+        };
+        response.extend([ID_DAP_Info, data.len() as u8]);
+        response.extend(data);
+        Some(2)
+    }
+
+    fn transfer(&mut self, request: &[u8], response: &mut Vec<u8>) -> Option<usize> {
+        let dap_index = *request.get(1)? as usize;
+        let count = *request.get(2)? as usize;
+        // parse all transfer requests first, the request length does not depend on the outcome
+        let mut transfers = Vec::new();
+        let mut ptr = 3;
+        for _ in 0..count {
+            let req = *request.get(ptr)?;
+            ptr += 1;
+            let has_data = req & DAP_TRANSFER_RnW == 0 || req & DAP_TRANSFER_MATCH_VALUE != 0;
+            let value = if has_data {
+                let value = u32::from_le_bytes(request.get(ptr..ptr + 4)?.try_into().unwrap());
+                ptr += 4;
+                value
+            } else {
+                0
+            };
+            transfers.push((req, value));
+        }
+
+        let start = response.len();
+        response.extend([ID_DAP_Transfer, 0, 0]);
+        let mut done = 0;
+        let mut ack = SwdAck::Ok;
+        let mut mismatch = false;
+        let connected = self.connected(dap_index);
+        for (req, value) in transfers {
+            if !connected {
+                ack = SwdAck::NoAck;
+                break;
+            }
+            let read = req & DAP_TRANSFER_RnW != 0;
+            if !read && req & DAP_TRANSFER_MATCH_MASK != 0 {
+                self.match_mask = value;
+                done += 1;
+                continue;
+            }
+            if read && req & DAP_TRANSFER_MATCH_VALUE != 0 {
+                let mut retry = self.match_retry;
+                loop {
+                    match self.swd_transfer(req, 0) {
+                        Ok(data) if data & self.match_mask == value & self.match_mask => break,
+                        Ok(_) if retry > 0 => retry -= 1,
+                        Ok(_) => {
+                            mismatch = true;
+                            break;
+                        }
+                        Err(e) => {
+                            ack = e;
+                            break;
+                        }
+                    }
+                }
+                if ack != SwdAck::Ok || mismatch {
+                    break;
+                }
+                done += 1;
+                continue;
+            }
+            match self.swd_transfer(req, value) {
+                Ok(data) => {
+                    if req & DAP_TRANSFER_TIMESTAMP != 0 {
+                        response.extend(self.timestamp.to_le_bytes());
+                    }
+                    if read {
+                        response.extend(data.to_le_bytes());
+                    }
+                    done += 1;
+                }
+                Err(e) => {
+                    ack = e;
+                    break;
+                }
+            }
+        }
+        response[start + 1] = done;
+        response[start + 2] = ack.to_dap() | if mismatch { DAP_TRANSFER_MISMATCH } else { 0 };
+        Some(ptr)
+    }
+
+    fn transfer_block(&mut self, request: &[u8], response: &mut Vec<u8>) -> Option<usize> {
+        let dap_index = *request.get(1)? as usize;
+        let count = u16::from_le_bytes(request.get(2..4)?.try_into().unwrap()) as usize;
+        let req = *request.get(4)?;
+        let read = req & DAP_TRANSFER_RnW != 0;
+        let len = if read { 5 } else { 5 + 4 * count };
+        let values: Vec<u32> = if read {
+            vec![0; count]
+        } else {
+            request.get(5..len)?.chunks(4).map(|bytes| u32::from_le_bytes(bytes.try_into().unwrap())).collect()
+        };
+
+        let start = response.len();
+        response.extend([ID_DAP_TransferBlock, 0, 0, 0]);
+        let mut done: u16 = 0;
+        let mut ack = SwdAck::Ok;
+        if !self.connected(dap_index) {
+            ack = SwdAck::NoAck;
+        } else {
+            for value in values {
+                match self.swd_transfer(req, value) {
+                    Ok(data) => {
+                        if read {
+                            response.extend(data.to_le_bytes());
+                        }
+                        done += 1;
+                    }
+                    Err(e) => {
+                        ack = e;
+                        break;
+                    }
+                }
+            }
+        }
+        response[start + 1..start + 3].copy_from_slice(&done.to_le_bytes());
+        response[start + 3] = ack.to_dap();
+        Some(len)
+    }
+
+    /// Every DP on the SWD bus.
+    fn swd_targets(&mut self) -> impl Iterator<Item = &mut SwdTarget> {
+        std::iter::once(&mut self.target).chain(self.multidrop.iter_mut())
+    }
+
+    /// Whether DAP_Transfer can reach the DP at `dap_index`.
+    fn connected(&self, dap_index: usize) -> bool {
+        match self.port {
+            DAP_PORT_SWD => true,
+            // the probe addresses the device by the configured chain, which has to match the real one
+            DAP_PORT_JTAG => self.jtag.taps.get(dap_index).is_some_and(|tap| tap.dp)
+                && self.ir_lengths.iter().eq(self.jtag.taps.iter().map(|tap| &tap.ir_len)),
+            _ => false,
+        }
+    }
+
+    /// One SWD or JTAG transaction including the probe's own WAIT retries.
+    fn swd_transfer(&mut self, req: u8, value: u32) -> Result<u32, SwdAck> {
+        let mut retry = self.wait_retry;
+        loop {
+            self.timestamp = self.timestamp.wrapping_add(1);
+            let result = if self.port == DAP_PORT_JTAG {
+                self.target.jtag_transfer(req, value)
+            } else {
+                // every DP sees the packet, at most one is selected and answers
+                let mut result = Err(SwdAck::NoAck);
+                for target in self.swd_targets() {
+                    match target.transfer(req, value) {
+                        Err(SwdAck::NoAck) => (),
+                        answer => result = answer,
+                    }
+                }
+                result
+            };
+            match result {
+                Err(SwdAck::Wait) if retry > 0 => retry -= 1,
+                result => return result,
+            }
+        }
+    }
+
+    /// Move what the target sent on SWO into the trace buffer while capture is active.
+    fn capture_swo(&mut self) {
+        if !self.swo_active || self.swo_mode == DAP_SWO_MODE_OFF {
+            return;
+        }
+        for byte in self.target.swo.drain(..) {
+            if self.swo_buffer.len() < SWO_BUFFER_SIZE {
+                self.swo_buffer.push_back(byte);
+                self.swo_index = self.swo_index.wrapping_add(1);
+            } else {
+                self.swo_overrun = true;
+            }
+        }
+    }
+
+    fn swo_status(&self) -> u8 {
+        let mut status = 0;
+        if self.swo_active {
+            status |= DAP_SWO_CAPTURE_ACTIVE;
+        }
+        if self.swo_overrun {
+            status |= DAP_SWO_BUFFER_OVERRUN;
+        }
+        status
+    }
+
+    /// Move what the target sent on its UART into the receive buffer while receiving is enabled.
+    fn receive_uart(&mut self) {
+        if !self.uart_rx_enabled {
+            return;
+        }
+        for byte in self.target.uart.drain(..) {
+            if self.uart_rx.len() < UART_BUFFER_SIZE {
+                self.uart_rx.push_back(byte);
+            } else {
+                self.uart_rx_lost = true;
+            }
+        }
+    }
+
+    fn uart_status(&self) -> u8 {
+        let mut status = 0;
+        if self.uart_rx_enabled {
+            status |= DAP_UART_STATUS_RX_ENABLED;
+        }
+        if self.uart_rx_lost {
+            status |= DAP_UART_STATUS_RX_DATA_LOST;
+        }
+        if self.uart_tx_enabled {
+            status |= DAP_UART_STATUS_TX_ENABLED;
+        }
+        status
+    }
+
+    fn swd_sequence(&mut self, request: &[u8], response: &mut Vec<u8>) -> Option<usize> {
+        let count = *request.get(1)? as usize;
+        let mut ptr = 2;
+        let mut input = Vec::new();
+        for _ in 0..count {
+            let info = *request.get(ptr)?;
+            ptr += 1;
+            let cycles = match info & 0x3F {
+                0 => 64,
+                n => n as usize,
+            };
+            let mut bits = vec![0; cycles.div_ceil(8)];
+            if info & 0x80 != 0 {
+                for n in 0..cycles {
+                    // SWDIO is pulled up, any DP driving it low wins
+                    if self.port == DAP_PORT_SWD && self.swd_targets().fold(true, |swdio, target| target.wire_in() & swdio) {
+                        bits[n / 8] |= 1 << (n % 8);
+                    }
+                }
+                input.extend(bits);
+            } else {
+                let data = request.get(ptr..ptr + bits.len())?;
+                ptr += bits.len();
+                for n in 0..cycles {
+                    if self.port == DAP_PORT_SWD {
+                        let bit = (data[n / 8] >> (n % 8)) & 1 != 0;
+                        for target in self.swd_targets() {
+                            target.wire_out(bit);
+                        }
+                    }
+                }
+            }
+        }
+        response.extend([ID_DAP_SWD_Sequence, DAP_OK]);
+        response.extend(input);
+        Some(ptr)
+    }
+
+    fn jtag_sequence(&mut self, request: &[u8], response: &mut Vec<u8>) -> Option<usize> {
+        let count = *request.get(1)? as usize;
+        response.extend([ID_DAP_JTAG_Sequence, DAP_OK]);
+        let mut ptr = 2;
+        for _ in 0..count {
+            let info = *request.get(ptr)?;
+            let tck_count = match info & 0x3F {
+                0 => 64,
+                n => n as usize,
+            };
+            let tdi = request.get(ptr + 1..ptr + 1 + tck_count.div_ceil(8))?;
+            let mut tdo = vec![0u8; tdi.len()];
+            for n in 0..tck_count {
+                if self.jtag.clock(info & (1 << 6) != 0, tdi[n / 8] & (1 << (n % 8)) != 0) {
+                    tdo[n / 8] |= 1 << (n % 8);
+                }
+            }
+            if info & (1 << 7) != 0 {
+                response.extend(tdo);
+            }
+            ptr += 1 + tdi.len();
+        }
+        Some(ptr)
+    }
+
+    /// Read an IDCODE the way the firmware does, which relies on the configured IR lengths.
+    fn jtag_idcode(&mut self, index: usize) -> Option<u32> {
+        if index >= self.ir_lengths.len() {
+            return None;
+        }
+        // IDCODE instruction for the addressed device, BYPASS for all others
+        let mut ir = Vec::new();
+        for (n, &len) in self.ir_lengths.iter().enumerate() {
+            ir.extend((0..len).map(|bit| n != index || (JTAG_DP_IDCODE >> bit) & 1 != 0));
+        }
+        self.jtag_scan(&[true, true, false, false], &ir);
+        // one BYPASS bit for every device nearer to TDO, then the IDCODE
+        let tdo = self.jtag_scan(&[true, false, false], &vec![false; index + 32]);
+        Some(tdo[index..].iter().rev().fold(0, |idcode, &bit| idcode << 1 | bit as u32))
+    }
+
+    /// Shift `tdi` from Run-Test/Idle through the register reached by `path` and back.
+    fn jtag_scan(&mut self, path: &[bool], tdi: &[bool]) -> Vec<bool> {
+        for &tms in path {
+            self.jtag.clock(tms, true);
+        }
+        let tdo = tdi.iter().enumerate().map(|(n, &bit)| self.jtag.clock(n + 1 == tdi.len(), bit)).collect();
+        self.jtag.clock(true, true);
+        self.jtag.clock(false, true);
+        tdo
+    }
+}
+
+impl Default for SimulatedProbe {
+    fn default() -> Self {
+        SimulatedProbe::new()
+    }
+}
+
+impl DapTransport for SimulatedProbe {
+    fn send_packet(&mut self, buf: &[u8]) -> rusb::Result<usize> {
+        if buf.len() > self.packet_size {
+            return Err(rusb::Error::Overflow);
+        }
+        if buf.first() == Some(&ID_DAP_QueueCommands) && self.queue_commands {
+            // held back until a packet which is not queued arrives
+            if self.queued.len() + 1 >= self.packet_count {
+                // all buffers are taken, the firmware would stop accepting packets
+                return Err(rusb::Error::Timeout);
+            }
+            self.queued.push(buf.to_vec());
+            return Ok(buf.len());
+        }
+        for mut request in std::mem::take(&mut self.queued) {
+            request[0] = ID_DAP_ExecuteCommands;
+            let response = self.process_packet(&request);
+            self.responses.push_back(response);
+        }
+        let response = self.process_packet(buf);
+        self.responses.push_back(response);
+        Ok(buf.len())
+    }
+    fn receive_packet(&mut self, buf: &mut [u8]) -> rusb::Result<usize> {
+        let response = self.responses.pop_front().ok_or(rusb::Error::Timeout)?;
+        let len = response.len().min(buf.len());
+        buf[..len].copy_from_slice(&response[..len]);
+        Ok(len)
+    }
+    fn has_swo_endpoint(&self) -> bool {
+        self.capabilities & 0x40 != 0
+    }
+    fn read_swo(&mut self, buf: &mut [u8], _timeout: Duration) -> rusb::Result<usize> {
+        if !self.has_swo_endpoint() {
+            return Err(rusb::Error::NotSupported);
+        }
+        self.capture_swo();
+        if self.swo_transport != DAP_SWO_TRANSPORT_ENDPOINT || self.swo_buffer.is_empty() {
+            return Err(rusb::Error::Timeout);
+        }
+        let len = buf.len().min(self.swo_buffer.len());
+        for (dst, src) in buf.iter_mut().zip(self.swo_buffer.drain(..len)) {
+            *dst = src;
+        }
+        Ok(len)
+    }
+    fn packet_size(&self) -> usize {
+        self.packet_size
+    }
+    fn packet_count(&self) -> usize {
+        self.packet_count
+    }
+}
@@ -0,0 +1,690 @@
+use std::fmt;
+
+use crate::command::{Command, DAP_SWJ_nTRST};
+use crate::error::DapError;
+use crate::jtag::{get_bit, set_bit, shift_sequences, tms_sequences, Jtag, TapState};
+use crate::transport::DapTransport;
+
+/// Longest delay of a single DAP_Delay.
+const MAX_DELAY_US: u64 = u16::MAX as u64;
+
+/// Where in an SVF or XSVF file something happened.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Location {
+    /// Line of an SVF file, starting at 1.
+    Line(usize),
+    /// Byte offset of an XSVF instruction.
+    Offset(usize),
+}
+
+impl fmt::Display for Location {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Location::Line(line) => write!(f, "line {}", line),
+            Location::Offset(offset) => write!(f, "offset {:#X}", offset),
+        }
+    }
+}
+
+/// Failure to parse or play an SVF or XSVF file.
+#[derive(thiserror::Error, Debug)]
+pub enum SvfError {
+    #[error("{at}: {message}")]
+    Parse { at: Location, message: String },
+    /// The first vector whose TDO did not match. Values are hex, MSB first, as in SVF.
+    #[error("{at}: TDO mismatch at bit {bit}: expected {expected}, got {actual}, mask {mask}")]
+    Mismatch { at: Location, bit: usize, expected: String, actual: String, mask: String },
+    #[error("{0}")]
+    Dap(#[from] DapError),
+}
+
+fn parse_error(at: Location, message: impl Into<String>) -> SvfError {
+    SvfError::Parse { at, message: message.into() }
+}
+
+/// Parameters of SIR, SDR and their headers and trailers. Bit strings are LSB first.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Scan {
+    pub len: usize,
+    pub tdi: Option<Vec<u8>>,
+    pub tdo: Option<Vec<u8>>,
+    pub mask: Option<Vec<u8>>,
+    pub smask: Option<Vec<u8>>,
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct RunTest {
+    pub run_state: Option<TapState>,
+    /// Number of clocks, and whether they are SCK rather than TCK.
+    pub run_count: Option<(u32, bool)>,
+    /// Minimum time in seconds.
+    pub min_time: Option<f64>,
+    pub max_time: Option<f64>,
+    pub end_state: Option<TapState>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Trst {
+    On,
+    Off,
+    Z,
+    Absent,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum SvfCommand {
+    EndDr(TapState),
+    EndIr(TapState),
+    /// TCK frequency in Hz, `None` for full speed.
+    Frequency(Option<f64>),
+    Hdr(Scan),
+    Hir(Scan),
+    Tdr(Scan),
+    Tir(Scan),
+    Sdr(Scan),
+    Sir(Scan),
+    RunTest(RunTest),
+    State(Vec<TapState>),
+    Trst(Trst),
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct SvfStatement {
+    pub line: usize,
+    pub command: SvfCommand,
+}
+
+fn state_from_name(name: &str) -> Option<TapState> {
+    let state = match name {
+        "RESET" => TapState::TestLogicReset,
+        "IDLE" => TapState::RunTestIdle,
+        "DRSELECT" => TapState::SelectDr,
+        "DRCAPTURE" => TapState::CaptureDr,
+        "DRSHIFT" => TapState::ShiftDr,
+        "DREXIT1" => TapState::Exit1Dr,
+        "DRPAUSE" => TapState::PauseDr,
+        "DREXIT2" => TapState::Exit2Dr,
+        "DRUPDATE" => TapState::UpdateDr,
+        "IRSELECT" => TapState::SelectIr,
+        "IRCAPTURE" => TapState::CaptureIr,
+        "IRSHIFT" => TapState::ShiftIr,
+        "IREXIT1" => TapState::Exit1Ir,
+        "IRPAUSE" => TapState::PauseIr,
+        "IREXIT2" => TapState::Exit2Ir,
+        "IRUPDATE" => TapState::UpdateIr,
+        _ => return None,
+    };
+    Some(state)
+}
+
+/// Convert an SVF hex string (MSB first) into `len` bits, LSB first.
+fn parse_hex(hex: &str, len: usize) -> Option<Vec<u8>> {
+    let mut bits = vec![0u8; len.div_ceil(8)];
+    for (n, c) in hex.chars().rev().enumerate() {
+        let digit = c.to_digit(16)?;
+        for bit in 0..4 {
+            if digit & (1 << bit) != 0 {
+                if n * 4 + bit >= len {
+                    // set bits beyond the length are an error
+                    return None;
+                }
+                set_bit(&mut bits, n * 4 + bit, true);
+            }
+        }
+    }
+    Some(bits)
+}
+
+/// Format `len` bits, LSB first, as an SVF hex string.
+fn to_hex(bits: &[u8], len: usize) -> String {
+    (0..len.div_ceil(4))
+        .rev()
+        .map(|digit| {
+            let value = (0..4).filter(|&bit| digit * 4 + bit < len && get_bit(bits, digit * 4 + bit)).fold(0, |v, bit| v | 1 << bit);
+            std::char::from_digit(value, 16).unwrap().to_ascii_uppercase()
+        })
+        .collect()
+}
+
+/// Split SVF text into statements, each a list of tokens and its first line.
+/// Comments are dropped and every parenthesized hex string becomes a single token.
+fn tokenize(text: &str) -> Result<Vec<(usize, Vec<String>)>, SvfError> {
+    let mut statements = Vec::new();
+    let mut tokens: Vec<String> = Vec::new();
+    let mut start = 0;
+    let mut token = String::new();
+    let mut in_parens = false;
+    for (n, line) in text.lines().enumerate() {
+        let line_number = n + 1;
+        let line = match (line.find('!'), line.find("//")) {
+            (Some(a), Some(b)) => &line[..a.min(b)],
+            (Some(a), None) | (None, Some(a)) => &line[..a],
+            (None, None) => line,
+        };
+        for c in line.chars() {
+            if in_parens {
+                match c {
+                    ')' => {
+                        in_parens = false;
+                        tokens.push(std::mem::take(&mut token));
+                    }
+                    c if c.is_whitespace() => (),
+                    c => token.push(c),
+                }
+                continue;
+            }
+            if c.is_whitespace() || c == '(' || c == ';' {
+                if !token.is_empty() {
+                    tokens.push(std::mem::take(&mut token));
+                }
+            } else {
+                if tokens.is_empty() && token.is_empty() {
+                    start = line_number;
+                }
+                token.push(c.to_ascii_uppercase());
+            }
+            match c {
+                '(' => in_parens = true,
+                ';' if !tokens.is_empty() => statements.push((start, std::mem::take(&mut tokens))),
+                _ => (),
+            }
+        }
+        if !token.is_empty() && !in_parens {
+            tokens.push(std::mem::take(&mut token));
+        }
+    }
+    if in_parens || !tokens.is_empty() || !token.is_empty() {
+        return Err(parse_error(Location::Line(start), "statement is not terminated"));
+    }
+    Ok(statements)
+}
+
+/// Parse the text of an SVF file.
+pub fn parse_svf(text: &str) -> Result<Vec<SvfStatement>, SvfError> {
+    let mut statements = Vec::new();
+    for (line, tokens) in tokenize(text)? {
+        let at = Location::Line(line);
+        let state = |name: &str| state_from_name(name).ok_or_else(|| parse_error(at, format!("unknown state {}", name)));
+        let stable = |name: &str| match state(name)? {
+            state if state.is_stable() => Ok(state),
+            _ => Err(parse_error(at, format!("{} is not a stable state", name))),
+        };
+        let number = |token: &str| token.parse::<f64>().map_err(|_| parse_error(at, format!("bad number {}", token)));
+        let args = &tokens[1..];
+        let command = match tokens[0].as_str() {
+            "ENDDR" | "ENDIR" => {
+                let state = stable(args.first().ok_or_else(|| parse_error(at, "missing state"))?)?;
+                if tokens[0] == "ENDDR" {
+                    SvfCommand::EndDr(state)
+                } else {
+                    SvfCommand::EndIr(state)
+                }
+            }
+            "FREQUENCY" => match args.first() {
+                Some(value) => SvfCommand::Frequency(Some(number(value)?)),
+                None => SvfCommand::Frequency(None),
+            },
+            "HDR" | "HIR" | "TDR" | "TIR" | "SDR" | "SIR" => {
+                let len = args.first().and_then(|len| len.parse().ok()).ok_or_else(|| parse_error(at, "missing length"))?;
+                let mut scan = Scan { len, ..Scan::default() };
+                for pair in args[1..].chunks(2) {
+                    let value = match pair {
+                        [_, hex] => parse_hex(hex, len).ok_or_else(|| parse_error(at, format!("bad hex value {}", hex)))?,
+                        _ => return Err(parse_error(at, "missing value")),
+                    };
+                    match pair[0].as_str() {
+                        "TDI" => scan.tdi = Some(value),
+                        "TDO" => scan.tdo = Some(value),
+                        "MASK" => scan.mask = Some(value),
+                        "SMASK" => scan.smask = Some(value),
+                        other => return Err(parse_error(at, format!("unknown parameter {}", other))),
+                    }
+                }
+                match tokens[0].as_str() {
+                    "HDR" => SvfCommand::Hdr(scan),
+                    "HIR" => SvfCommand::Hir(scan),
+                    "TDR" => SvfCommand::Tdr(scan),
+                    "TIR" => SvfCommand::Tir(scan),
+                    "SDR" => SvfCommand::Sdr(scan),
+                    _ => SvfCommand::Sir(scan),
+                }
+            }
+            "RUNTEST" => {
+                let mut run_test = RunTest::default();
+                let mut args = args.iter().peekable();
+                if let Some(state) = args.peek().and_then(|name| state_from_name(name)) {
+                    run_test.run_state = Some(state);
+                    args.next();
+                }
+                while let Some(arg) = args.next() {
+                    match arg.as_str() {
+                        "MAXIMUM" => {
+                            run_test.max_time = Some(number(args.next().ok_or_else(|| parse_error(at, "missing time"))?)?);
+                            args.next(); // SEC
+                        }
+                        "ENDSTATE" => run_test.end_state = Some(stable(args.next().ok_or_else(|| parse_error(at, "missing state"))?)?),
+                        value => {
+                            let value = number(value)?;
+                            match args.next().map(String::as_str) {
+                                Some("TCK") => run_test.run_count = Some((value as u32, false)),
+                                Some("SCK") => run_test.run_count = Some((value as u32, true)),
+                                Some("SEC") => run_test.min_time = Some(value),
+                                _ => return Err(parse_error(at, "expected TCK, SCK or SEC")),
+                            }
+                        }
+                    }
+                }
+                SvfCommand::RunTest(run_test)
+            }
+            "STATE" => SvfCommand::State(args.iter().map(|name| state(name)).collect::<Result<_, _>>()?),
+            "TRST" => match args.first().map(String::as_str) {
+                Some("ON") => SvfCommand::Trst(Trst::On),
+                Some("OFF") => SvfCommand::Trst(Trst::Off),
+                Some("Z") => SvfCommand::Trst(Trst::Z),
+                Some("ABSENT") => SvfCommand::Trst(Trst::Absent),
+                _ => return Err(parse_error(at, "bad TRST mode")),
+            },
+            other => return Err(parse_error(at, format!("unsupported command {}", other))),
+        };
+        statements.push(SvfStatement { line, command });
+    }
+    Ok(statements)
+}
+
+/// TDI, MASK and SMASK of a scan command carry over while the length does not change.
+/// TDO only applies when given.
+#[derive(Clone, Debug, Default)]
+struct StickyScan {
+    len: usize,
+    tdi: Vec<u8>,
+    tdo: Option<Vec<u8>>,
+    mask: Vec<u8>,
+    smask: Vec<u8>,
+}
+
+impl StickyScan {
+    fn update(&mut self, scan: &Scan, at: Location) -> Result<(), SvfError> {
+        if scan.len != self.len {
+            self.len = scan.len;
+            self.tdi = Vec::new();
+            self.mask = vec![0xFF; scan.len.div_ceil(8)];
+            self.smask = vec![0xFF; scan.len.div_ceil(8)];
+            if scan.tdi.is_none() && scan.len > 0 {
+                return Err(parse_error(at, "TDI is required when the length changes"));
+            }
+        }
+        if let Some(tdi) = &scan.tdi {
+            self.tdi = tdi.clone();
+        }
+        if let Some(mask) = &scan.mask {
+            self.mask = mask.clone();
+        }
+        if let Some(smask) = &scan.smask {
+            self.smask = smask.clone();
+        }
+        self.tdo = scan.tdo.clone();
+        Ok(())
+    }
+}
+
+/// Copy `len` bits of `src` into `dst`, starting at bit `offset`.
+fn put_bits(dst: &mut Vec<u8>, offset: usize, src: &[u8], len: usize) {
+    for n in 0..len {
+        set_bit(dst, offset + n, get_bit(src, n));
+    }
+}
+
+/// Plays SVF and XSVF files on the JTAG chain.
+///
+/// The player keeps track of the TAP state, so files may leave the TAP in any
+/// stable state between commands.
+pub struct Player<'a, T: DapTransport> {
+    jtag: Jtag<'a, T>,
+    state: TapState,
+}
+
+impl<'a, T: DapTransport> Player<'a, T> {
+    /// Take over the chain, starting from Test-Logic-Reset.
+    pub fn new(mut jtag: Jtag<'a, T>) -> Result<Self, DapError> {
+        jtag.sequences(&tms_sequences(&TapState::TestLogicReset.path_to(TapState::TestLogicReset)))?;
+        Ok(Player { jtag, state: TapState::TestLogicReset })
+    }
+
+    pub fn state(&self) -> TapState {
+        self.state
+    }
+
+    pub fn goto(&mut self, state: TapState) -> Result<(), DapError> {
+        let path = self.state.path_to(state);
+        if !path.is_empty() {
+            self.jtag.sequences(&tms_sequences(&path))?;
+        }
+        self.state = state;
+        Ok(())
+    }
+
+    /// Shift `len` bits through IR or DR and return TDO. With `end` the TAP moves on to
+    /// that state, without it the TAP stays in Shift-xR for another shift.
+    pub fn shift(&mut self, ir: bool, tdi: &[u8], len: usize, end: Option<TapState>) -> Result<Vec<u8>, DapError> {
+        let shift_state = if ir { TapState::ShiftIr } else { TapState::ShiftDr };
+        let mut sequences = tms_sequences(&self.state.path_to(shift_state));
+        sequences.extend(shift_sequences(tdi, len, end.is_some()));
+        self.state = shift_state;
+        if let Some(end) = end {
+            let exit1 = if ir { TapState::Exit1Ir } else { TapState::Exit1Dr };
+            let from = if len > 0 { exit1 } else { shift_state };
+            sequences.extend(tms_sequences(&from.path_to(end)));
+            self.state = end;
+        }
+        self.jtag.sequences(&sequences)
+    }
+
+    /// Clock `tck_count` cycles in `state`, wait `delay_us` and move on to `end`.
+    pub fn run_test(&mut self, state: TapState, tck_count: u32, delay_us: u64, end: TapState) -> Result<(), DapError> {
+        self.goto(state)?;
+        if tck_count > 0 {
+            let tms = state == TapState::TestLogicReset;
+            let sequences: Vec<_> = (0..tck_count as usize)
+                .step_by(64)
+                .flat_map(|start| {
+                    let len = (tck_count as usize - start).min(64);
+                    let mut sequence = shift_sequences(&[0u8; 8], len, false);
+                    sequence.iter_mut().for_each(|sequence| {
+                        sequence.tms = tms;
+                        sequence.capture = false;
+                    });
+                    sequence
+                })
+                .collect();
+            self.jtag.sequences(&sequences)?;
+        }
+        self.delay(delay_us)?;
+        self.goto(end)
+    }
+
+    /// Wait with DAP_Delay, so the time is measured by the probe.
+    ///
+    /// One DAP_Delay per packet, several in one could keep the probe from
+    /// answering for longer than the USB timeout.
+    pub fn delay(&mut self, us: u64) -> Result<(), DapError> {
+        let mut left = us;
+        while left > 0 {
+            let chunk = left.min(MAX_DELAY_US);
+            self.jtag.session().command(&Command::Delay(chunk as u16))?;
+            left -= chunk;
+        }
+        Ok(())
+    }
+
+    pub fn set_trst(&mut self, asserted: bool) -> Result<(), DapError> {
+        let output = if asserted { 0 } else { DAP_SWJ_nTRST };
+        self.jtag.session().command(&Command::SwjPins { output, select: DAP_SWJ_nTRST, wait: 0 })?;
+        Ok(())
+    }
+
+    pub fn set_frequency(&mut self, hz: u32) -> Result<(), DapError> {
+        self.jtag.session().command(&Command::SwjClock(hz))?;
+        Ok(())
+    }
+
+    /// Execute parsed SVF statements, stopping at the first TDO mismatch.
+    pub fn run_svf(&mut self, statements: &[SvfStatement]) -> Result<(), SvfError> {
+        let mut hdr = StickyScan::default();
+        let mut hir = StickyScan::default();
+        let mut tdr = StickyScan::default();
+        let mut tir = StickyScan::default();
+        let mut sdr = StickyScan::default();
+        let mut sir = StickyScan::default();
+        let mut enddr = TapState::RunTestIdle;
+        let mut endir = TapState::RunTestIdle;
+        let mut run_state = TapState::RunTestIdle;
+        let mut end_state = TapState::RunTestIdle;
+        let mut frequency = None;
+
+        for statement in statements {
+            let at = Location::Line(statement.line);
+            log::trace!("{}: {:?}", at, statement.command);
+            match &statement.command {
+                SvfCommand::EndDr(state) => enddr = *state,
+                SvfCommand::EndIr(state) => endir = *state,
+                SvfCommand::Frequency(hz) => {
+                    frequency = *hz;
+                    if let Some(hz) = hz {
+                        self.set_frequency(*hz as u32)?;
+                    }
+                }
+                SvfCommand::Hdr(scan) => hdr.update(scan, at)?,
+                SvfCommand::Hir(scan) => hir.update(scan, at)?,
+                SvfCommand::Tdr(scan) => tdr.update(scan, at)?,
+                SvfCommand::Tir(scan) => tir.update(scan, at)?,
+                SvfCommand::Sdr(scan) | SvfCommand::Sir(scan) => {
+                    let ir = matches!(statement.command, SvfCommand::Sir(_));
+                    let (header, data, trailer, end) = if ir {
+                        sir.update(scan, at)?;
+                        (&hir, &sir, &tir, endir)
+                    } else {
+                        sdr.update(scan, at)?;
+                        (&hdr, &sdr, &tdr, enddr)
+                    };
+                    // header first, so it ends up in the devices nearest to TDO
+                    let (mut tdi, mut expected, mut mask) = (Vec::new(), Vec::new(), Vec::new());
+                    let mut len = 0;
+                    for part in [header, data, trailer].iter() {
+                        let zeros = vec![0u8; part.len.div_ceil(8)];
+                        let (part_tdo, part_mask) = match &part.tdo {
+                            Some(tdo) => (tdo, &part.mask),
+                            None => (&zeros, &zeros),
+                        };
+                        // TDI bits outside SMASK are don't care, drive them low
+                        let part_tdi: Vec<u8> = part.tdi.iter().zip(&part.smask).map(|(tdi, smask)| tdi & smask).collect();
+                        put_bits(&mut tdi, len, &part_tdi, part.len);
+                        put_bits(&mut expected, len, part_tdo, part.len);
+                        put_bits(&mut mask, len, part_mask, part.len);
+                        len += part.len;
+                    }
+                    let captured = self.shift(ir, &tdi, len, Some(end))?;
+                    verify(at, &captured, &expected, &mask, len)?;
+                }
+                SvfCommand::RunTest(params) => {
+                    if let Some(state) = params.run_state {
+                        run_state = state;
+                        end_state = state;
+                    }
+                    if let Some(state) = params.end_state {
+                        end_state = state;
+                    }
+                    // the run lasts for the count or the minimum time, whichever is longer
+                    let mut tck_count = 0;
+                    let mut seconds = params.min_time.unwrap_or(0.0);
+                    match params.run_count {
+                        Some((count, false)) => {
+                            tck_count = count;
+                            // without a known TCK frequency the clocks can not count towards the time
+                            if let Some(hz) = frequency {
+                                seconds = (seconds - count as f64 / hz).max(0.0);
+                            }
+                        }
+                        Some((count, true)) => {
+                            // SCK is a system clock the probe does not drive, wait instead
+                            let hz = frequency.ok_or_else(|| parse_error(at, "RUNTEST in SCK needs a FREQUENCY"))?;
+                            seconds = seconds.max(count as f64 / hz);
+                        }
+                        None => (),
+                    }
+                    self.run_test(run_state, tck_count, (seconds * 1e6).ceil() as u64, end_state)?;
+                }
+                SvfCommand::State(states) => {
+                    for state in states {
+                        self.goto(*state)?;
+                    }
+                }
+                SvfCommand::Trst(Trst::On) => self.set_trst(true)?,
+                SvfCommand::Trst(Trst::Off) => self.set_trst(false)?,
+                SvfCommand::Trst(_) => (),
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Compare `len` bits of TDO with the expected value where `mask` is set.
+pub fn verify(at: Location, captured: &[u8], expected: &[u8], mask: &[u8], len: usize) -> Result<(), SvfError> {
+    let bit = (0..len).find(|&n| get_bit(mask, n) && get_bit(captured, n) != get_bit(expected, n));
+    match bit {
+        Some(bit) => Err(SvfError::Mismatch {
+            at,
+            bit,
+            expected: to_hex(expected, len),
+            actual: to_hex(captured, len),
+            mask: to_hex(mask, len),
+        }),
+        None => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::command::DAP_PORT_JTAG;
+    use crate::jtag::swd_to_jtag_sequence;
+    use crate::session::DapSession;
+    use crate::simulator::SimulatedProbe;
+
+    fn parse(text: &str) -> Vec<SvfCommand> {
+        parse_svf(text).unwrap().into_iter().map(|statement| statement.command).collect()
+    }
+
+    /// Play `text` on the single TAP of the simulator.
+    fn play(text: &str) -> Result<(), SvfError> {
+        let mut session = DapSession::open(SimulatedProbe::new())?;
+        session.execute_commands(vec![Command::Connect(DAP_PORT_JTAG), swd_to_jtag_sequence()])?;
+        Player::new(Jtag::new(&mut session))?.run_svf(&parse_svf(text)?)
+    }
+
+    fn parse_err(text: &str) -> (Location, String) {
+        match parse_svf(text) {
+            Err(SvfError::Parse { at, message }) => (at, message),
+            result => panic!("expected a parse error, got {:?}", result),
+        }
+    }
+
+    #[test]
+    fn hex_values() {
+        assert_eq!(parse_hex("1F", 5), Some(vec![0x1F]));
+        assert_eq!(parse_hex("0123", 16), Some(vec![0x23, 0x01]));
+        assert_eq!(parse_hex("3", 1), None);
+        assert_eq!(parse_hex("G", 4), None);
+        assert_eq!(to_hex(&[0x23, 0x01], 9), "123");
+    }
+
+    #[test]
+    fn scans() {
+        let commands = parse("SIR 4 TDI (e);\nSDR 32 TDI(00000000)\n  TDO (4BA00477) MASK (0FFFFFFF);\nHDR 0;");
+        assert_eq!(
+            commands,
+            [
+                SvfCommand::Sir(Scan { len: 4, tdi: Some(vec![0x0E]), ..Scan::default() }),
+                SvfCommand::Sdr(Scan {
+                    len: 32,
+                    tdi: Some(vec![0; 4]),
+                    tdo: Some(vec![0x77, 0x04, 0xA0, 0x4B]),
+                    mask: Some(vec![0xFF, 0xFF, 0xFF, 0x0F]),
+                    smask: None,
+                }),
+                SvfCommand::Hdr(Scan { len: 0, ..Scan::default() }),
+            ]
+        );
+    }
+
+    #[test]
+    fn comments_and_lines() {
+        let statements = parse_svf("! header\n// more\n\nTRST OFF; ENDIR IDLE ! trailing\n;\nSTATE RESET\n  IDLE;").unwrap();
+        assert_eq!(
+            statements,
+            [
+                SvfStatement { line: 4, command: SvfCommand::Trst(Trst::Off) },
+                SvfStatement { line: 4, command: SvfCommand::EndIr(TapState::RunTestIdle) },
+                SvfStatement { line: 6, command: SvfCommand::State(vec![TapState::TestLogicReset, TapState::RunTestIdle]) },
+            ]
+        );
+    }
+
+    #[test]
+    fn run_test() {
+        let commands = parse("RUNTEST IDLE 100 TCK 1E-3 SEC MAXIMUM 1 SEC ENDSTATE DRPAUSE;\nRUNTEST 10 SCK;\nFREQUENCY 1E6 HZ;\nFREQUENCY;");
+        assert_eq!(
+            commands,
+            [
+                SvfCommand::RunTest(RunTest {
+                    run_state: Some(TapState::RunTestIdle),
+                    run_count: Some((100, false)),
+                    min_time: Some(1e-3),
+                    max_time: Some(1.0),
+                    end_state: Some(TapState::PauseDr),
+                }),
+                SvfCommand::RunTest(RunTest { run_count: Some((10, true)), ..RunTest::default() }),
+                SvfCommand::Frequency(Some(1e6)),
+                SvfCommand::Frequency(None),
+            ]
+        );
+    }
+
+    #[test]
+    fn errors() {
+        assert_eq!(parse_err("SIR 4 TDI (1);\nSIR 4 TDI (1F);").0, Location::Line(2));
+        assert_eq!(parse_err("ENDDR DRSHIFT;").1, "DRSHIFT is not a stable state");
+        assert_eq!(parse_err("SDR 8 TDI;").1, "missing value");
+        assert_eq!(parse_err("SDR 8 TDI (00) XYZ (00);").1, "unknown parameter XYZ");
+        assert_eq!(parse_err("RUNTEST 10;").1, "expected TCK, SCK or SEC");
+        assert_eq!(parse_err("PIO (0);").1, "unsupported command PIO");
+        assert_eq!(parse_err("\nSIR 4 TDI (1)").1, "statement is not terminated");
+        assert_eq!(parse_err("SIR 4 TDI (1;").0, Location::Line(1));
+    }
+
+    #[test]
+    fn sticky_scan() {
+        let mut sticky = StickyScan::default();
+        let at = Location::Line(1);
+        assert!(sticky.update(&Scan { len: 8, ..Scan::default() }, at).is_err());
+        sticky.update(&Scan { len: 8, tdi: Some(vec![0x5A]), mask: Some(vec![0x0F]), tdo: Some(vec![0x01]), ..Scan::default() }, at).unwrap();
+        sticky.update(&Scan { len: 8, ..Scan::default() }, at).unwrap();
+        // TDI and MASK carry over, TDO does not
+        assert_eq!((sticky.tdi.as_slice(), sticky.mask.as_slice(), sticky.tdo.as_deref()), (&[0x5A][..], &[0x0F][..], None));
+        // a new length resets the masks
+        sticky.update(&Scan { len: 4, tdi: Some(vec![0x3]), ..Scan::default() }, at).unwrap();
+        assert_eq!((sticky.mask.as_slice(), sticky.smask.as_slice()), (&[0xFF][..], &[0xFF][..]));
+    }
+
+    #[test]
+    fn verify_tdo() {
+        let at = Location::Line(3);
+        verify(at, &[0x12, 0x34], &[0x12, 0x34], &[0xFF, 0xFF], 16).unwrap();
+        verify(at, &[0x12, 0x34], &[0x12, 0x84], &[0xFF, 0x0F], 16).unwrap();
+        match verify(at, &[0x12, 0x34], &[0x13, 0x34], &[0xFF, 0xFF], 12) {
+            Err(SvfError::Mismatch { bit, expected, actual, mask, .. }) => {
+                assert_eq!((bit, expected.as_str(), actual.as_str(), mask.as_str()), (0, "413", "412", "FFF"));
+            }
+            result => panic!("{:?}", result),
+        }
+    }
+
+    #[test]
+    fn play_idcode() {
+        play("SIR 4 TDI (E);\nSDR 32 TDI (0) TDO (4BA00477);").unwrap();
+        play("SIR 4 TDI (E);\nSDR 32 TDI (0) TDO (FBA00477) MASK (0FFFFFFF);").unwrap();
+        // SMASK is about TDI only, the mismatch still counts
+        match play("SIR 4 TDI (E);\nSDR 32 TDI (0) TDO (4BA00478) SMASK (0);") {
+            Err(SvfError::Mismatch { at: Location::Line(2), bit: 0, .. }) => (),
+            result => panic!("expected a mismatch, got {:?}", result),
+        }
+    }
+
+    #[test]
+    fn run_test_in_sck() {
+        play("FREQUENCY 1E6 HZ;\nRUNTEST 1000 SCK;").unwrap();
+        match play("RUNTEST 1000 SCK;") {
+            Err(SvfError::Parse { at: Location::Line(1), .. }) => (),
+            result => panic!("expected an error, got {:?}", result),
+        }
+    }
+}
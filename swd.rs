@@ -0,0 +1,274 @@
+use crate::batch::run_sequences;
+use crate::command::*;
+use crate::dp::{DP_BANK_DLPIDR, DP_BANK_TARGETID, DP_CTRL_STAT, DP_IDCODE, DP_SELECT, DP_TARGETSEL};
+use crate::error::DapError;
+use crate::jtag::get_bits;
+use crate::session::DapSession;
+use crate::transport::DapTransport;
+
+/// Selection alert which wakes a dormant SWJ-DP, LSB first.
+const SELECTION_ALERT: [u8; 16] = [
+    0x92, 0xF3, 0x09, 0x62, 0x95, 0x2D, 0x85, 0x86, 0xE9, 0xAF, 0xDD, 0xE3, 0xA2, 0x0E, 0xBC, 0x19,
+];
+
+/// Activation code which selects SWD after the selection alert.
+const SWD_ACTIVATION_CODE: u8 = 0x1A;
+
+/// SWD-to-Dormant select sequence, LSB first.
+const SWD_TO_DORMANT: u16 = 0xE3BC;
+
+// TARGETSEL of the DPs on an RP2040
+pub const RP2040_CORE0: u32 = 0x0100_2927;
+pub const RP2040_CORE1: u32 = 0x1100_2927;
+pub const RP2040_RESCUE: u32 = 0xF100_2927;
+
+/// A DP which answered on a multi-drop bus.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct MultiDropTarget {
+    pub targetsel: u32,
+    pub idcode: u32,
+}
+
+/// Raw SWD through DAP_SWD_Configure and DAP_SWD_Sequence.
+///
+/// For the wire level protocol which DAP_Transfer does not cover, such as dormant
+/// state entry and exit or a TARGETSEL write which no target acknowledges.
+pub struct Swd<'a, T: DapTransport> {
+    session: &'a mut DapSession<T>,
+    turnaround: u8,
+}
+
+impl<'a, T: DapTransport> Swd<'a, T> {
+    /// The turnaround period is assumed to be the default of one cycle until configured.
+    pub fn new(session: &'a mut DapSession<T>) -> Self {
+        Swd { session, turnaround: 1 }
+    }
+
+    pub fn session(&mut self) -> &mut DapSession<T> {
+        self.session
+    }
+
+    /// Set the turnaround period of 1 to 4 cycles, and whether WAIT and FAULT are
+    /// followed by a data phase. Both have to match the DP's DLCR.
+    pub fn configure(&mut self, turnaround: u8, data_phase: bool) -> Result<(), DapError> {
+        let mut config = (turnaround.clamp(1, 4) - 1) & DAP_SWD_TURNAROUND_MASK;
+        if data_phase {
+            config |= DAP_SWD_DATA_PHASE;
+        }
+        self.session.command(&Command::SwdConfigure(config))?;
+        self.turnaround = turnaround.clamp(1, 4);
+        Ok(())
+    }
+
+    /// Run `sequences` and return the SWDIO bits of the input sequences as one LSB first bit string.
+    pub fn sequences(&mut self, sequences: &[SwdSequence]) -> Result<Vec<u8>, DapError> {
+        run_sequences(self.session, sequences)
+    }
+
+    /// Read a DP or AP register with a single SWD packet.
+    pub fn read(&mut self, ap: bool, addr: u8) -> Result<u32, DapError> {
+        let trn = self.turnaround;
+        let mut sequences = vec![SwdSequence::output(8, &[request(ap, true, addr)])];
+        // turnaround, ACK, data, parity, turnaround
+        sequences.extend(input_sequences(trn as usize + 3 + 33 + trn as usize));
+        sequences.push(SwdSequence::output(8, &[0])); // idle
+        let swdio = self.sequences(&sequences)?;
+        let ack = TransferAck::from(get_bits(&swdio, trn as usize, 3)[0]);
+        if ack != TransferAck::Ok {
+            return Err(DapError::TransferFailed { command: ID_DAP_SWD_Sequence, index: 0, ack });
+        }
+        let data = get_bits(&swdio, trn as usize + 3, 33);
+        let value = u32::from_le_bytes([data[0], data[1], data[2], data[3]]);
+        if value.count_ones() % 2 != (data[4] & 1) as u32 {
+            return Err(DapError::TransferFailed { command: ID_DAP_SWD_Sequence, index: 0, ack: TransferAck::ProtocolError });
+        }
+        Ok(value)
+    }
+
+    /// Write a DP or AP register with a single SWD packet and return the ACK.
+    ///
+    /// The data phase is driven whatever the ACK, as a TARGETSEL write needs,
+    /// so after WAIT or FAULT the DP may see it as a new packet.
+    pub fn write(&mut self, ap: bool, addr: u8, value: u32) -> Result<TransferAck, DapError> {
+        let trn = self.turnaround;
+        let mut data = value.to_le_bytes().to_vec();
+        data.push((value.count_ones() % 2) as u8);
+        let mut sequences = vec![SwdSequence::output(8, &[request(ap, false, addr)])];
+        // turnaround, ACK, turnaround
+        sequences.extend(input_sequences(trn as usize + 3 + trn as usize));
+        sequences.extend(output_sequences(&data, 33));
+        sequences.push(SwdSequence::output(8, &[0])); // idle
+        let swdio = self.sequences(&sequences)?;
+        Ok(TransferAck::from(get_bits(&swdio, trn as usize, 3)[0]))
+    }
+
+    /// Select one DP on a multi-drop bus and return its IDCODE.
+    ///
+    /// A line reset deselects every DP, the TARGETSEL write whose ACK nobody drives
+    /// selects the one which matches, and reading IDCODE takes it out of lockout.
+    /// TARGETID and DLPIDR are checked against `targetsel` as well, since a
+    /// single-drop DP answers whatever was written to TARGETSEL.
+    /// The DP stays selected for DAP_Transfer until the next line reset.
+    pub fn select_target(&mut self, targetsel: u32) -> Result<u32, DapError> {
+        self.sequences(&line_reset_sequences())?;
+        self.write(false, DP_TARGETSEL, targetsel)?;
+        let idcode = self.read(false, DP_IDCODE)?;
+        if (idcode >> 12) & 0xF < 2 {
+            return Err(DapError::TargetSel { targetsel, reason: "DP is older than DPv2" });
+        }
+        let targetid = self.read_bank(DP_BANK_TARGETID)?;
+        let dlpidr = self.read_bank(DP_BANK_DLPIDR)?;
+        self.write_checked(DP_SELECT, 0)?;
+        if (targetid ^ targetsel) & 0x0FFF_FFFF != 0 || (dlpidr ^ targetsel) >> 28 != 0 {
+            return Err(DapError::TargetSel { targetsel, reason: "TARGETID or DLPIDR does not match" });
+        }
+        Ok(idcode)
+    }
+
+    /// Try every TARGETSEL value of `candidates` and return the DPs which answered.
+    ///
+    /// None of them is left selected, the next access has to start with a line reset.
+    pub fn scan_targets(&mut self, candidates: &[u32]) -> Result<Vec<MultiDropTarget>, DapError> {
+        let mut targets = Vec::new();
+        for &targetsel in candidates {
+            match self.select_target(targetsel) {
+                Ok(idcode) => targets.push(MultiDropTarget { targetsel, idcode }),
+                Err(DapError::TransferFailed { command: ID_DAP_SWD_Sequence, .. }) | Err(DapError::TargetSel { .. }) => (),
+                Err(e) => return Err(e),
+            }
+        }
+        self.sequences(&line_reset_sequences())?;
+        Ok(targets)
+    }
+
+    /// Read a DPv2 register at A[3:2] = 0x4 in DP bank `bank`.
+    fn read_bank(&mut self, bank: u32) -> Result<u32, DapError> {
+        self.write_checked(DP_SELECT, bank)?;
+        self.read(false, DP_CTRL_STAT)
+    }
+
+    fn write_checked(&mut self, addr: u8, value: u32) -> Result<(), DapError> {
+        match self.write(false, addr, value)? {
+            TransferAck::Ok => Ok(()),
+            ack => Err(DapError::TransferFailed { command: ID_DAP_SWD_Sequence, index: 0, ack }),
+        }
+    }
+}
+
+/// SWD packet request: start, APnDP, RnW, A[3:2], parity, stop, park.
+fn request(ap: bool, read: bool, addr: u8) -> u8 {
+    let fields = (ap as u8) | (read as u8) << 1 | (addr & 0x0C);
+    let parity = (fields.count_ones() % 2) as u8;
+    1 | fields << 1 | parity << 5 | 1 << 7
+}
+
+/// Drive `bits` bits of `swdio`, LSB first.
+pub fn output_sequences(swdio: &[u8], bits: usize) -> Vec<SwdSequence> {
+    (0..bits)
+        .step_by(MAX_SEQUENCE_BITS)
+        .map(|start| {
+            let len = (bits - start).min(MAX_SEQUENCE_BITS);
+            SwdSequence::output(len as u8, &get_bits(swdio, start, len))
+        })
+        .collect()
+}
+
+/// Read SWDIO for `bits` cycles.
+pub fn input_sequences(bits: usize) -> Vec<SwdSequence> {
+    (0..bits)
+        .step_by(MAX_SEQUENCE_BITS)
+        .map(|start| SwdSequence::input((bits - start).min(MAX_SEQUENCE_BITS) as u8))
+        .collect()
+}
+
+/// At least 50 cycles with SWDIO high followed by idle cycles.
+pub fn line_reset_sequences() -> Vec<SwdSequence> {
+    output_sequences(&[0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0x0F], 56)
+}
+
+/// Line reset and the SWD-to-Dormant select sequence.
+pub fn swd_to_dormant_sequences() -> Vec<SwdSequence> {
+    let mut sequences = line_reset_sequences();
+    sequences.extend(output_sequences(&SWD_TO_DORMANT.to_le_bytes(), 16));
+    sequences
+}
+
+/// Wake a dormant SWJ-DP into SWD: the selection alert after 8 cycles high,
+/// 4 cycles low, the SWD activation code and a line reset.
+pub fn dormant_to_swd_sequences() -> Vec<SwdSequence> {
+    let mut sequences = output_sequences(&[0xFF], 8);
+    sequences.extend(output_sequences(&SELECTION_ALERT, 128));
+    sequences.extend(output_sequences(&[SWD_ACTIVATION_CODE << 4, SWD_ACTIVATION_CODE >> 4], 12));
+    sequences.extend(line_reset_sequences());
+    sequences
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dp::{jtag_to_swd_sequence, DebugPort};
+    use crate::simulator::{SimulatedProbe, SwdTarget};
+
+    /// Connect SWD to `probe` and wake any dormant DPs, as for an RP2040.
+    fn connect(probe: SimulatedProbe) -> DapSession<SimulatedProbe> {
+        let mut session = DapSession::open(probe).unwrap();
+        session.execute_commands(vec![Command::Connect(DAP_PORT_SWD), jtag_to_swd_sequence()]).unwrap();
+        let mut wake = swd_to_dormant_sequences();
+        wake.extend(dormant_to_swd_sequences());
+        Swd::new(&mut session).sequences(&wake).unwrap();
+        session
+    }
+
+    fn rp2040() -> SimulatedProbe {
+        let mut probe = SimulatedProbe::new();
+        probe.target = SwdTarget::multidrop(RP2040_CORE0);
+        probe.multidrop.push(SwdTarget::multidrop(RP2040_CORE1));
+        probe
+    }
+
+    #[test]
+    fn scan_targets() {
+        let mut session = connect(rp2040());
+        let targets = Swd::new(&mut session).scan_targets(&[RP2040_CORE0, RP2040_RESCUE, RP2040_CORE1]).unwrap();
+        assert_eq!(
+            targets,
+            [
+                MultiDropTarget { targetsel: RP2040_CORE0, idcode: 0x0BC1_2477 },
+                MultiDropTarget { targetsel: RP2040_CORE1, idcode: 0x0BC1_2477 },
+            ]
+        );
+    }
+
+    #[test]
+    fn select_target() {
+        let mut session = connect(rp2040());
+        assert_eq!(Swd::new(&mut session).select_target(RP2040_CORE1).unwrap(), 0x0BC1_2477);
+        // DAP_Transfer now talks to the second core
+        let mut dp = DebugPort::new(&mut session, 0);
+        dp.write_dp(DP_SELECT, DP_BANK_DLPIDR).unwrap();
+        assert_eq!(dp.read_dp(DP_CTRL_STAT).unwrap() >> 28, RP2040_CORE1 >> 28);
+        dp.write_dp(DP_SELECT, 0).unwrap();
+
+        // no DP drives the bus for an unknown TARGETSEL
+        match Swd::new(&mut session).select_target(RP2040_RESCUE) {
+            Err(DapError::TransferFailed { command: ID_DAP_SWD_Sequence, ack: TransferAck::NoAck, .. }) => (),
+            result => panic!("expected no ACK, got {:?}", result),
+        }
+    }
+
+    #[test]
+    fn single_drop_dp() {
+        // a DPv1 ignores TARGETSEL altogether
+        let mut session = connect(SimulatedProbe::new());
+        match Swd::new(&mut session).select_target(RP2040_CORE0) {
+            Err(DapError::TargetSel { reason, .. }) => assert_eq!(reason, "DP is older than DPv2"),
+            result => panic!("expected a TARGETSEL error, got {:?}", result),
+        }
+
+        // a single-drop DPv2 answers, but its TARGETID does not match
+        let mut probe = SimulatedProbe::new();
+        probe.target.idcode = 0x2BA0_2477;
+        let mut session = connect(probe);
+        assert_eq!(Swd::new(&mut session).scan_targets(&[RP2040_CORE0]).unwrap(), []);
+    }
+}
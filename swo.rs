@@ -0,0 +1,180 @@
+use std::collections::VecDeque;
+use std::io;
+use std::time::{Duration, Instant};
+
+use crate::command::*;
+use crate::error::DapError;
+use crate::session::DapSession;
+use crate::transport::DapTransport;
+
+/// How long a read of the byte stream waits for trace data by default.
+const DEFAULT_TIMEOUT: Duration = Duration::from_millis(100);
+
+/// Pause after a DAP_SWO_Data which returned nothing.
+const POLL_INTERVAL: Duration = Duration::from_millis(1);
+
+/// Bytes requested from the SWO endpoint at once, a multiple of every bulk packet size.
+const ENDPOINT_READ_LEN: usize = 4096;
+
+/// SWO line coding.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SwoMode {
+    Uart,
+    Manchester,
+}
+
+impl SwoMode {
+    fn to_dap(self) -> u8 {
+        match self {
+            SwoMode::Uart => DAP_SWO_MODE_UART,
+            SwoMode::Manchester => DAP_SWO_MODE_MANCHESTER,
+        }
+    }
+}
+
+/// Trace status from DAP_SWO_Status.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SwoStatus {
+    pub active: bool,
+    pub stream_error: bool,
+    /// Trace data was lost because the probe's buffer was full.
+    pub overrun: bool,
+    /// Bytes waiting in the probe's buffer.
+    pub count: u32,
+}
+
+impl SwoStatus {
+    fn new(status: u8, count: u32) -> Self {
+        SwoStatus {
+            active: status & DAP_SWO_CAPTURE_ACTIVE != 0,
+            stream_error: status & DAP_SWO_STREAM_ERROR != 0,
+            overrun: status & DAP_SWO_BUFFER_OVERRUN != 0,
+            count,
+        }
+    }
+}
+
+/// SWO trace capture.
+///
+/// Trace data comes from the SWO endpoint when the probe has one and reports SWO
+/// streaming, otherwise from polling DAP_SWO_Data. Either way it can be read as a
+/// byte stream through [`std::io::Read`], where a read fails with
+/// [`io::ErrorKind::TimedOut`] if no data arrived within the timeout.
+pub struct Swo<'a, T: DapTransport> {
+    session: &'a mut DapSession<T>,
+    endpoint: bool,
+    timeout: Duration,
+    pending: VecDeque<u8>,
+}
+
+impl<'a, T: DapTransport> Swo<'a, T> {
+    pub fn new(session: &'a mut DapSession<T>) -> Self {
+        let endpoint = session.capabilities().swo_streaming && session.transport().has_swo_endpoint();
+        Swo { session, endpoint, timeout: DEFAULT_TIMEOUT, pending: VecDeque::new() }
+    }
+
+    pub fn session(&mut self) -> &mut DapSession<T> {
+        self.session
+    }
+
+    /// Whether trace data comes from the SWO endpoint rather than DAP_SWO_Data.
+    pub fn uses_endpoint(&self) -> bool {
+        self.endpoint
+    }
+
+    pub fn set_timeout(&mut self, timeout: Duration) {
+        self.timeout = timeout;
+    }
+
+    /// Stop capturing, then select the transport, `mode` and `baudrate`.
+    /// Returns the baudrate the probe actually uses.
+    pub fn configure(&mut self, mode: SwoMode, baudrate: u32) -> Result<u32, DapError> {
+        let transport = if self.endpoint { DAP_SWO_TRANSPORT_ENDPOINT } else { DAP_SWO_TRANSPORT_DATA };
+        let responses = self.session.commands(&[
+            Command::SwoControl(DAP_SWO_CONTROL_STOP),
+            Command::SwoTransport(transport),
+            Command::SwoMode(mode.to_dap()),
+            Command::SwoBaudrate(baudrate),
+        ])?;
+        self.pending.clear();
+        match responses[3] {
+            Response::SwoBaudrate(baudrate) => Ok(baudrate),
+            _ => unreachable!(),
+        }
+    }
+
+    pub fn start(&mut self) -> Result<(), DapError> {
+        self.session.command(&Command::SwoControl(DAP_SWO_CONTROL_START))?;
+        Ok(())
+    }
+
+    pub fn stop(&mut self) -> Result<(), DapError> {
+        self.session.command(&Command::SwoControl(DAP_SWO_CONTROL_STOP))?;
+        Ok(())
+    }
+
+    pub fn status(&mut self) -> Result<SwoStatus, DapError> {
+        match self.session.command(&Command::SwoStatus)? {
+            Response::SwoStatus { status, count } => Ok(SwoStatus::new(status, count)),
+            _ => unreachable!(),
+        }
+    }
+
+    /// Trace data the probe has captured so far, possibly none.
+    ///
+    /// A read from the SWO endpoint waits up to the timeout for data to arrive,
+    /// DAP_SWO_Data returns at once.
+    pub fn read_data(&mut self) -> Result<Vec<u8>, DapError> {
+        if self.endpoint {
+            let mut buf = vec![0; ENDPOINT_READ_LEN];
+            return match self.session.transport_mut().read_swo(&mut buf, self.timeout) {
+                Ok(len) => {
+                    buf.truncate(len);
+                    Ok(buf)
+                }
+                Err(rusb::Error::Timeout) => Ok(Vec::new()),
+                Err(e) => Err(DapError::usb(ID_DAP_SWO_Data, e)),
+            };
+        }
+        let max = (self.session.packet_size() - 4).min(u16::MAX as usize) as u16;
+        match self.session.command(&Command::SwoData(max))? {
+            Response::SwoData { status, data } => {
+                let status = SwoStatus::new(status, 0);
+                if status.overrun {
+                    log::warn!("SWO buffer overrun, trace data was lost");
+                }
+                if status.stream_error {
+                    log::warn!("SWO stream error");
+                }
+                Ok(data)
+            }
+            _ => unreachable!(),
+        }
+    }
+}
+
+impl<'a, T: DapTransport> io::Read for Swo<'a, T> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        let deadline = Instant::now() + self.timeout;
+        while self.pending.is_empty() {
+            let data = self.read_data().map_err(io::Error::other)?;
+            if data.is_empty() {
+                if Instant::now() >= deadline {
+                    return Err(io::ErrorKind::TimedOut.into());
+                }
+                if !self.endpoint {
+                    std::thread::sleep(POLL_INTERVAL);
+                }
+            }
+            self.pending.extend(data);
+        }
+        let len = buf.len().min(self.pending.len());
+        for (dst, src) in buf.iter_mut().zip(self.pending.drain(..len)) {
+            *dst = src;
+        }
+        Ok(len)
+    }
+}
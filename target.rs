@@ -0,0 +1,53 @@
+use crate::command::Command;
+use crate::error::DapError;
+use crate::session::{DapSession, TransferConfig};
+use crate::transport::DapTransport;
+
+/// Connection settings suited to a kind of target.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct TargetProfile {
+    pub name: &'static str,
+    /// SWJ clock in Hz.
+    pub clock: u32,
+    pub transfer: TransferConfig,
+}
+
+/// Built-in profiles, the first one is the default.
+pub const PROFILES: [TargetProfile; 3] = [
+    TargetProfile {
+        name: "default",
+        clock: 16_000_000,
+        transfer: TransferConfig { idle_cycles: 0, wait_retry: 100, match_retry: 0 },
+    },
+    // a slow bus or a core running from a low clock keeps the AP busy for longer
+    TargetProfile {
+        name: "slow",
+        clock: 1_000_000,
+        transfer: TransferConfig { idle_cycles: 2, wait_retry: 1000, match_retry: 100 },
+    },
+    // waking the debug domain of a sleeping target can take milliseconds
+    TargetProfile {
+        name: "low-power",
+        clock: 100_000,
+        transfer: TransferConfig { idle_cycles: 8, wait_retry: u16::MAX, match_retry: 1000 },
+    },
+];
+
+impl TargetProfile {
+    /// Look up one of the [`PROFILES`].
+    pub fn by_name(name: &str) -> Option<&'static TargetProfile> {
+        PROFILES.iter().find(|profile| profile.name.eq_ignore_ascii_case(name))
+    }
+
+    /// Set the SWJ clock and the transfer settings on a connected session.
+    pub fn apply<T: DapTransport>(&self, session: &mut DapSession<T>) -> Result<(), DapError> {
+        session.command(&Command::SwjClock(self.clock))?;
+        session.configure_transfers(&self.transfer)
+    }
+}
+
+impl Default for TargetProfile {
+    fn default() -> Self {
+        PROFILES[0]
+    }
+}
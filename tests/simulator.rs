@@ -0,0 +1,198 @@
+//! The connection sequence of the CLI and the layers above it, run against the simulated probe.
+
+use std::io::{Read, Write};
+use std::time::Duration;
+
+use rusb_cmsis_dap::command::*;
+use rusb_cmsis_dap::dp::*;
+use rusb_cmsis_dap::itm::{self, ItmConfig, ItmDecoder, ItmEvent, ITM_STIM0};
+use rusb_cmsis_dap::jtag::{swd_to_jtag_sequence, Jtag};
+use rusb_cmsis_dap::memory::{MemAp, AP_IDR};
+use rusb_cmsis_dap::session::DapSession;
+use rusb_cmsis_dap::simulator::SimulatedProbe;
+use rusb_cmsis_dap::swo::{Swo, SwoMode};
+use rusb_cmsis_dap::uart::{Uart, UartConfig, UartStream};
+use rusb_cmsis_dap::DapError;
+
+const CPUID: u32 = 0xE000_ED00;
+const SRAM: u32 = 0x2000_0000;
+
+/// Connect through SWD and read IDCODE, as `run_test` does.
+fn connect_swd(probe: SimulatedProbe) -> DapSession<SimulatedProbe> {
+    let mut session = DapSession::open(probe).unwrap();
+    session.command(&Command::Connect(DAP_PORT_SWD)).unwrap();
+    session.execute_commands(vec![jtag_to_swd_sequence(), swd_reset_sequence()]).unwrap();
+    let mut dp = DebugPort::new(&mut session, 0);
+    assert_eq!(dp.read_dp(DP_IDCODE).unwrap(), 0x2BA0_1477);
+    dp.clear_sticky_errors().unwrap();
+    dp.power_up().unwrap();
+    session
+}
+
+fn expect_ack(result: Result<impl std::fmt::Debug, DapError>, expected: TransferAck) {
+    match result {
+        Err(DapError::TransferFailed { ack, .. }) => assert_eq!(ack, expected),
+        result => panic!("expected {:?}, got {:?}", expected, result),
+    }
+}
+
+#[test]
+fn swd_connect_and_read_cpuid() {
+    let mut session = connect_swd(SimulatedProbe::new());
+    let mut ap = MemAp::new(DebugPort::new(&mut session, 0), 0);
+    assert_eq!(ap.read_reg(AP_IDR).unwrap(), 0x0477_0021);
+    assert_eq!(ap.read32(CPUID).unwrap(), 0x410F_C241);
+}
+
+#[test]
+fn swd_requires_idcode_after_line_reset() {
+    let mut session = DapSession::open(SimulatedProbe::new()).unwrap();
+    session.command(&Command::Connect(DAP_PORT_SWD)).unwrap();
+    session.execute_commands(vec![jtag_to_swd_sequence(), swd_reset_sequence()]).unwrap();
+    let mut dp = DebugPort::new(&mut session, 0);
+    expect_ack(dp.read_dp(DP_CTRL_STAT), TransferAck::NoAck);
+    dp.read_dp(DP_IDCODE).unwrap();
+    dp.read_dp(DP_CTRL_STAT).unwrap();
+}
+
+#[test]
+fn power_up_handshake() {
+    let mut session = connect_swd(SimulatedProbe::new());
+    let ctrl_stat = DebugPort::new(&mut session, 0).read_dp(DP_CTRL_STAT).unwrap();
+    assert_eq!(ctrl_stat & (CSYSPWRUPACK | CDBGPWRUPACK), CSYSPWRUPACK | CDBGPWRUPACK);
+}
+
+#[test]
+fn memory_read_write() {
+    let mut session = connect_swd(SimulatedProbe::new());
+    let mut ap = MemAp::new(DebugPort::new(&mut session, 0), 0);
+    ap.write32(SRAM, 0x1234_5678).unwrap();
+    assert_eq!(ap.read32(SRAM).unwrap(), 0x1234_5678);
+
+    // long enough for DAP_TransferBlock and crossing the 1KB TAR wrap
+    let data: Vec<u32> = (0..600u32).map(|n| n.wrapping_mul(0x0101_0101)).collect();
+    ap.write_block(SRAM + 0x3F0, &data).unwrap();
+    assert_eq!(ap.read_block(SRAM + 0x3F0, data.len()).unwrap(), data);
+}
+
+#[test]
+fn memory_over_small_packets() {
+    let mut probe = SimulatedProbe::new();
+    probe.set_packet_size(24);
+    let mut session = connect_swd(probe);
+    let mut ap = MemAp::new(DebugPort::new(&mut session, 0), 0);
+    let data: Vec<u32> = (0..40).collect();
+    ap.write_block(SRAM, &data).unwrap();
+    assert_eq!(ap.read_block(SRAM, data.len()).unwrap(), data);
+}
+
+#[test]
+fn fault_recovery() {
+    let mut session = connect_swd(SimulatedProbe::new());
+    let mut ap = MemAp::new(DebugPort::new(&mut session, 0), 0);
+    expect_ack(ap.read32(0x1000_0000), TransferAck::Fault);
+    // the sticky error has been cleared
+    assert_eq!(ap.read32(CPUID).unwrap(), 0x410F_C241);
+}
+
+#[test]
+fn wait_is_retried() {
+    let mut session = connect_swd(SimulatedProbe::new());
+    session.transport_mut().target.busy = 50;
+    let mut ap = MemAp::new(DebugPort::new(&mut session, 0), 0);
+    assert_eq!(ap.read32(CPUID).unwrap(), 0x410F_C241);
+}
+
+#[test]
+fn wait_recovery() {
+    let mut session = connect_swd(SimulatedProbe::new());
+    session.transport_mut().target.busy = u32::MAX;
+    let mut ap = MemAp::new(DebugPort::new(&mut session, 0), 0);
+    expect_ack(ap.read32(CPUID), TransferAck::Wait);
+    // DAPABORT has cancelled the stalled transaction
+    assert_eq!(ap.read32(CPUID).unwrap(), 0x410F_C241);
+}
+
+#[test]
+fn match_read() {
+    let mut session = connect_swd(SimulatedProbe::new());
+    let mut dp = DebugPort::new(&mut session, 0);
+    let powered = CSYSPWRUPACK | CDBGPWRUPACK;
+    dp.transfer(&[TransferRequest::MatchMask(powered), TransferRequest::ReadMatch { ap: false, addr: DP_CTRL_STAT, value: powered }])
+        .unwrap();
+    let result = dp.transfer(&[TransferRequest::MatchMask(powered), TransferRequest::ReadMatch { ap: false, addr: DP_CTRL_STAT, value: 0 }]);
+    expect_ack(result, TransferAck::Mismatch);
+    assert_eq!(dp.read_dp(DP_IDCODE).unwrap(), 0x2BA0_1477);
+}
+
+#[test]
+fn jtag_connect_and_read_cpuid() {
+    let mut session = DapSession::open(SimulatedProbe::new()).unwrap();
+    session.command(&Command::Connect(DAP_PORT_JTAG)).unwrap();
+    session.execute_commands(vec![swd_to_jtag_sequence()]).unwrap();
+    let mut jtag = Jtag::new(&mut session);
+    let taps = jtag.scan_chain().unwrap();
+    assert_eq!(taps.len(), 1);
+    jtag.configure(&[taps[0].ir_len]).unwrap();
+    assert_eq!(jtag.idcode(0).unwrap(), 0x4BA0_0477);
+
+    let mut dp = DebugPort::new(&mut session, 0);
+    dp.clear_sticky_errors().unwrap();
+    dp.power_up().unwrap();
+    let mut ap = MemAp::new(dp, 0);
+    assert_eq!(ap.read32(CPUID).unwrap(), 0x410F_C241);
+}
+
+#[test]
+fn swo_captures_itm_writes() {
+    let mut session = connect_swd(SimulatedProbe::new());
+    let baudrate = Swo::new(&mut session).configure(SwoMode::Uart, 2_000_000).unwrap();
+    let mut ap = MemAp::new(DebugPort::new(&mut session, 0), 0);
+    itm::configure(&mut ap, &ItmConfig { trace_clock: 64_000_000, baudrate, ..Default::default() }).unwrap();
+
+    let mut swo = Swo::new(&mut session);
+    swo.start().unwrap();
+    let mut ap = MemAp::new(DebugPort::new(&mut session, 0), 0);
+    ap.write32(ITM_STIM0, 0x6C6C_6548).unwrap();
+
+    let mut swo = Swo::new(&mut session);
+    let mut events = Vec::new();
+    let mut decoder = ItmDecoder::new();
+    for _ in 0..10 {
+        events.extend(decoder.feed(&swo.read_data().unwrap()));
+    }
+    assert!(events.contains(&ItmEvent::Instrumentation { port: 0, data: b"Hell".to_vec() }), "{:?}", events);
+}
+
+#[test]
+fn uart_echo() {
+    let mut session = DapSession::open(SimulatedProbe::new()).unwrap();
+    let mut uart = Uart::new(&mut session);
+    // the probe gets as close as its clock divider allows
+    let baudrate = uart.configure(&UartConfig::default()).unwrap();
+    assert!(baudrate.abs_diff(115_200) < 1152, "{} baud", baudrate);
+    uart.start().unwrap();
+    let (sent, mut received) = uart.transfer(b"ping").unwrap();
+    assert_eq!(sent, 4);
+    for _ in 0..10 {
+        received.extend(uart.transfer(&[]).unwrap().1);
+    }
+    assert_eq!(received, b"ping");
+}
+
+#[test]
+fn uart_stream_echo() {
+    let session = DapSession::open(SimulatedProbe::new()).unwrap();
+    let mut stream = UartStream::open(session, &UartConfig::default()).unwrap();
+    stream.set_timeout(Duration::from_secs(5));
+    stream.write_all(b"hello\n").unwrap();
+    let mut received: Vec<u8> = Vec::new();
+    let mut buf = [0; 16];
+    while received.len() < 6 {
+        let len = stream.read(&mut buf).unwrap();
+        assert_ne!(len, 0, "stream closed");
+        received.extend(&buf[..len]);
+    }
+    assert_eq!(received, b"hello\n");
+    stream.close().unwrap();
+}
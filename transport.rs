@@ -0,0 +1,338 @@
+use rusb::{DeviceHandle, UsbContext};
+
+use std::collections::VecDeque;
+use std::time::Duration;
+
+/// Timeout applied to every USB transfer of a CMSIS-DAP packet.
+pub const USB_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Default packet size of CMSIS-DAP firmware until DAP_Info says otherwise.
+pub const DEFAULT_PACKET_SIZE: usize = 64;
+
+/// A pipe that carries CMSIS-DAP command packets to a probe and response packets back.
+pub trait DapTransport {
+    /// Send one command packet. Returns the number of bytes written.
+    fn send_packet(&mut self, buf: &[u8]) -> rusb::Result<usize>;
+    /// Receive one response packet into `buf`. Returns the number of bytes read.
+    fn receive_packet(&mut self, buf: &mut [u8]) -> rusb::Result<usize>;
+    /// Maximum size of a single command or response packet.
+    fn packet_size(&self) -> usize;
+    /// Number of packets the probe can buffer.
+    fn packet_count(&self) -> usize {
+        1
+    }
+    /// Adopt the packet size reported by the probe through DAP_Info.
+    fn set_packet_size(&mut self, _packet_size: usize) {}
+    /// Adopt the packet count reported by the probe through DAP_Info.
+    fn set_packet_count(&mut self, _packet_count: usize) {}
+    /// Whether trace data can be read with [`DapTransport::read_swo`].
+    fn has_swo_endpoint(&self) -> bool {
+        false
+    }
+    /// Read trace data from the SWO endpoint of a CMSIS-DAPv2 probe.
+    /// Returns the number of bytes read, or `rusb::Error::Timeout` if there was none.
+    fn read_swo(&mut self, _buf: &mut [u8], _timeout: Duration) -> rusb::Result<usize> {
+        Err(rusb::Error::NotSupported)
+    }
+
+    /// Send `requests` and return their responses in order, keeping up to
+    /// `packet_count()` requests in flight so the probe never waits for the host.
+    ///
+    /// On failure the responses still in flight are read and dropped, so they
+    /// are not taken for the responses of the next requests.
+    fn transfer_pipelined(&mut self, requests: &[Vec<u8>]) -> rusb::Result<Vec<Vec<u8>>> {
+        let depth = self.packet_count().max(1);
+        let mut responses = Vec::with_capacity(requests.len());
+        let mut sent = 0;
+        while responses.len() < requests.len() {
+            while sent < requests.len() && sent - responses.len() < depth {
+                if let Err(e) = self.send_packet(&requests[sent]) {
+                    discard_responses(self, sent - responses.len());
+                    return Err(e);
+                }
+                sent += 1;
+            }
+            let mut buf = vec![0u8; self.packet_size()];
+            match self.receive_packet(&mut buf) {
+                Ok(len) => buf.truncate(len),
+                Err(e) => {
+                    // a response which timed out may still turn up
+                    discard_responses(self, sent - responses.len());
+                    return Err(e);
+                }
+            }
+            responses.push(buf);
+        }
+        Ok(responses)
+    }
+}
+
+/// Read and drop up to `count` responses to requests that are still in flight,
+/// giving up at the first error.
+pub fn discard_responses<T: DapTransport + ?Sized>(transport: &mut T, count: usize) {
+    let mut buf = vec![0u8; transport.packet_size()];
+    for _ in 0..count {
+        if transport.receive_packet(&mut buf).is_err() {
+            break;
+        }
+    }
+}
+
+impl<D: DapTransport + ?Sized> DapTransport for Box<D> {
+    fn send_packet(&mut self, buf: &[u8]) -> rusb::Result<usize> {
+        (**self).send_packet(buf)
+    }
+    fn receive_packet(&mut self, buf: &mut [u8]) -> rusb::Result<usize> {
+        (**self).receive_packet(buf)
+    }
+    fn packet_size(&self) -> usize {
+        (**self).packet_size()
+    }
+    fn packet_count(&self) -> usize {
+        (**self).packet_count()
+    }
+    fn set_packet_size(&mut self, packet_size: usize) {
+        (**self).set_packet_size(packet_size)
+    }
+    fn set_packet_count(&mut self, packet_count: usize) {
+        (**self).set_packet_count(packet_count)
+    }
+    fn has_swo_endpoint(&self) -> bool {
+        (**self).has_swo_endpoint()
+    }
+    fn read_swo(&mut self, buf: &mut [u8], timeout: Duration) -> rusb::Result<usize> {
+        (**self).read_swo(buf, timeout)
+    }
+}
+
+/// CMSIS-DAPv2 : a vendor specific interface with a pair of bulk endpoints,
+/// and optionally a third one which streams SWO trace data.
+pub struct BulkTransport<T: UsbContext> {
+    handle: DeviceHandle<T>,
+    out_ep: u8,
+    in_ep: u8,
+    swo_ep: Option<u8>,
+    packet_size: usize,
+    packet_count: usize,
+}
+
+impl<T: UsbContext> BulkTransport<T> {
+    pub fn new(handle: DeviceHandle<T>, out_ep: u8, in_ep: u8, swo_ep: Option<u8>) -> Self {
+        BulkTransport { handle, out_ep, in_ep, swo_ep, packet_size: DEFAULT_PACKET_SIZE, packet_count: 1 }
+    }
+}
+
+impl<T: UsbContext> DapTransport for BulkTransport<T> {
+    fn send_packet(&mut self, buf: &[u8]) -> rusb::Result<usize> {
+        self.handle.write_bulk(self.out_ep, buf, USB_TIMEOUT)
+    }
+    fn receive_packet(&mut self, buf: &mut [u8]) -> rusb::Result<usize> {
+        self.handle.read_bulk(self.in_ep, buf, USB_TIMEOUT)
+    }
+    fn packet_size(&self) -> usize {
+        self.packet_size
+    }
+    fn packet_count(&self) -> usize {
+        self.packet_count
+    }
+    fn set_packet_size(&mut self, packet_size: usize) {
+        self.packet_size = packet_size;
+    }
+    fn set_packet_count(&mut self, packet_count: usize) {
+        self.packet_count = packet_count;
+    }
+    fn has_swo_endpoint(&self) -> bool {
+        self.swo_ep.is_some()
+    }
+    fn read_swo(&mut self, buf: &mut [u8], timeout: Duration) -> rusb::Result<usize> {
+        let swo_ep = self.swo_ep.ok_or(rusb::Error::NotSupported)?;
+        self.handle.read_bulk(swo_ep, buf, timeout)
+    }
+}
+
+/// CMSIS-DAPv1 : a HID interface with an interrupt IN endpoint.
+/// If the interface has no interrupt OUT endpoint (or it is not to be used),
+/// reports are sent through SET_REPORT on the control pipe.
+pub struct HidTransport<T: UsbContext> {
+    handle: DeviceHandle<T>,
+    if_num: u8,
+    out_ep: Option<u8>,
+    in_ep: u8,
+    packet_size: usize,
+    packet_count: usize,
+}
+
+impl<T: UsbContext> HidTransport<T> {
+    pub fn new(handle: DeviceHandle<T>, if_num: u8, out_ep: Option<u8>, in_ep: u8) -> Self {
+        HidTransport { handle, if_num, out_ep, in_ep, packet_size: DEFAULT_PACKET_SIZE, packet_count: 1 }
+    }
+}
+
+impl<T: UsbContext> DapTransport for HidTransport<T> {
+    fn send_packet(&mut self, buf: &[u8]) -> rusb::Result<usize> {
+        // HID reports are always of the full report size.
+        let report = pad_report(buf, self.packet_size);
+        match self.out_ep {
+            Some(out_ep) => self.handle.write_interrupt(out_ep, &report, USB_TIMEOUT),
+            None => set_report(&self.handle, self.if_num, &report),
+        }
+    }
+    fn receive_packet(&mut self, buf: &mut [u8]) -> rusb::Result<usize> {
+        self.handle.read_interrupt(self.in_ep, buf, USB_TIMEOUT)
+    }
+    fn packet_size(&self) -> usize {
+        self.packet_size
+    }
+    fn packet_count(&self) -> usize {
+        self.packet_count
+    }
+    fn set_packet_size(&mut self, packet_size: usize) {
+        self.packet_size = packet_size;
+    }
+    fn set_packet_count(&mut self, packet_count: usize) {
+        self.packet_count = packet_count;
+    }
+}
+
+/// CMSIS-DAPv1 over a HID interface with no endpoints at all.
+/// Reports travel through SET_REPORT / GET_REPORT on the control pipe,
+/// so only one packet is ever in flight whatever the probe can buffer.
+pub struct HidControlTransport<T: UsbContext> {
+    handle: DeviceHandle<T>,
+    if_num: u8,
+    packet_size: usize,
+}
+
+impl<T: UsbContext> HidControlTransport<T> {
+    pub fn new(handle: DeviceHandle<T>, if_num: u8) -> Self {
+        HidControlTransport { handle, if_num, packet_size: DEFAULT_PACKET_SIZE }
+    }
+}
+
+impl<T: UsbContext> DapTransport for HidControlTransport<T> {
+    fn send_packet(&mut self, buf: &[u8]) -> rusb::Result<usize> {
+        let report = pad_report(buf, self.packet_size);
+        set_report(&self.handle, self.if_num, &report)
+    }
+    fn receive_packet(&mut self, buf: &mut [u8]) -> rusb::Result<usize> {
+        // GET_REPORT
+        self.handle.read_control(0xA1, 0x01, 0x0100, self.if_num as u16, buf, USB_TIMEOUT)
+    }
+    fn packet_size(&self) -> usize {
+        self.packet_size
+    }
+    fn set_packet_size(&mut self, packet_size: usize) {
+        self.packet_size = packet_size;
+    }
+}
+
+fn set_report<T: UsbContext>(handle: &DeviceHandle<T>, if_num: u8, report: &[u8]) -> rusb::Result<usize> {
+    // SET_REPORT
+    handle.write_control(0x21, 0x09, 0x0200, if_num as u16, report, USB_TIMEOUT)
+}
+
+fn pad_report(buf: &[u8], report_size: usize) -> Vec<u8> {
+    let mut report = buf.to_vec();
+    if report.len() < report_size {
+        report.resize(report_size, 0);
+    }
+    report
+}
+
+type Responder = Box<dyn FnMut(&[u8]) -> Option<Vec<u8>> + Send>;
+
+/// A transport with no hardware behind it.
+///
+/// Every sent packet is recorded. Responses are either queued up front with
+/// [`MemoryTransport::push_response`] or produced by a responder function.
+pub struct MemoryTransport {
+    packet_size: usize,
+    packet_count: usize,
+    sent: Vec<Vec<u8>>,
+    responses: VecDeque<Vec<u8>>,
+    responder: Option<Responder>,
+}
+
+impl MemoryTransport {
+    pub fn new(packet_size: usize) -> Self {
+        MemoryTransport {
+            packet_size,
+            packet_count: 1,
+            sent: Vec::new(),
+            responses: VecDeque::new(),
+            responder: None,
+        }
+    }
+
+    /// Create a transport which answers every packet by calling `responder`.
+    pub fn with_responder<F>(packet_size: usize, responder: F) -> Self
+    where
+        F: FnMut(&[u8]) -> Option<Vec<u8>> + Send + 'static,
+    {
+        let mut transport = MemoryTransport::new(packet_size);
+        transport.responder = Some(Box::new(responder));
+        transport
+    }
+
+    /// Queue a response to be returned by the next `receive_packet()`.
+    pub fn push_response(&mut self, response: &[u8]) {
+        self.responses.push_back(response.to_vec());
+    }
+
+    /// Packets sent so far, oldest first.
+    pub fn sent(&self) -> &[Vec<u8>] {
+        &self.sent
+    }
+}
+
+impl DapTransport for MemoryTransport {
+    fn send_packet(&mut self, buf: &[u8]) -> rusb::Result<usize> {
+        if buf.len() > self.packet_size {
+            return Err(rusb::Error::Overflow);
+        }
+        self.sent.push(buf.to_vec());
+        if let Some(responder) = self.responder.as_mut() {
+            if let Some(response) = responder(buf) {
+                self.responses.push_back(response);
+            }
+        }
+        Ok(buf.len())
+    }
+    fn receive_packet(&mut self, buf: &mut [u8]) -> rusb::Result<usize> {
+        let response = self.responses.pop_front().ok_or(rusb::Error::Timeout)?;
+        let len = response.len().min(buf.len());
+        buf[..len].copy_from_slice(&response[..len]);
+        Ok(len)
+    }
+    fn packet_size(&self) -> usize {
+        self.packet_size
+    }
+    fn packet_count(&self) -> usize {
+        self.packet_count
+    }
+    fn set_packet_size(&mut self, packet_size: usize) {
+        self.packet_size = packet_size;
+    }
+    fn set_packet_count(&mut self, packet_count: usize) {
+        self.packet_count = packet_count;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::command::{DAP_ID_VENDOR, DAP_OK, ID_DAP_Delay, ID_DAP_Info};
+    use crate::simulator::SimulatedProbe;
+
+    #[test]
+    fn pipeline_failure_leaves_no_responses_behind() {
+        let mut probe = SimulatedProbe::new();
+        probe.set_packet_count(4);
+        let info = vec![ID_DAP_Info, DAP_ID_VENDOR];
+        // the simulator refuses a packet which is too long, after two went out
+        let too_long = vec![ID_DAP_Info; DEFAULT_PACKET_SIZE + 1];
+        assert!(probe.transfer_pipelined(&[info.clone(), info, too_long]).is_err());
+        let responses = probe.transfer_pipelined(&[vec![ID_DAP_Delay, 1, 0]]).unwrap();
+        assert_eq!(responses[0], [ID_DAP_Delay, DAP_OK]);
+    }
+}
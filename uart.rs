@@ -0,0 +1,499 @@
+use std::collections::VecDeque;
+use std::io;
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+use crate::command::*;
+use crate::error::DapError;
+use crate::session::DapSession;
+use crate::transport::DapTransport;
+
+/// How long a read of [`UartStream`] waits for data by default.
+const DEFAULT_TIMEOUT: Duration = Duration::from_millis(100);
+
+/// Pause between DAP_UART_Transfer polls which moved no data.
+const POLL_INTERVAL: Duration = Duration::from_millis(5);
+
+/// Bytes waiting to be sent before writes to [`UartStream`] block.
+const TX_QUEUE_LIMIT: usize = 4096;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Parity {
+    None,
+    Odd,
+    Even,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum StopBits {
+    One,
+    OneAndHalf,
+    Two,
+}
+
+/// Line settings of the target UART.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct UartConfig {
+    pub baudrate: u32,
+    /// 5 to 8.
+    pub data_bits: u8,
+    pub parity: Parity,
+    pub stop_bits: StopBits,
+}
+
+impl Default for UartConfig {
+    /// 115200 baud, 8N1.
+    fn default() -> Self {
+        UartConfig { baudrate: 115_200, data_bits: 8, parity: Parity::None, stop_bits: StopBits::One }
+    }
+}
+
+impl UartConfig {
+    fn control(&self) -> u8 {
+        let parity = match self.parity {
+            Parity::None => DAP_UART_PARITY_NONE,
+            Parity::Odd => DAP_UART_PARITY_ODD,
+            Parity::Even => DAP_UART_PARITY_EVEN,
+        };
+        let stop_bits = match self.stop_bits {
+            StopBits::One => DAP_UART_STOP_BITS_1,
+            StopBits::OneAndHalf => DAP_UART_STOP_BITS_1_5,
+            StopBits::Two => DAP_UART_STOP_BITS_2,
+        };
+        (self.data_bits & DAP_UART_DATA_BITS_MASK) | parity | stop_bits
+    }
+}
+
+/// UART status from DAP_UART_Status.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct UartStatus {
+    pub rx_enabled: bool,
+    /// Received data was lost because the probe's buffer was full.
+    pub rx_data_lost: bool,
+    pub framing_error: bool,
+    pub parity_error: bool,
+    pub tx_enabled: bool,
+    /// Bytes waiting in the probe's receive buffer.
+    pub rx_count: u32,
+    /// Bytes waiting in the probe's transmit buffer.
+    pub tx_count: u32,
+}
+
+impl UartStatus {
+    fn new(status: u8, rx_count: u32, tx_count: u32) -> Self {
+        UartStatus {
+            rx_enabled: status & DAP_UART_STATUS_RX_ENABLED != 0,
+            rx_data_lost: status & DAP_UART_STATUS_RX_DATA_LOST != 0,
+            framing_error: status & DAP_UART_STATUS_FRAMING_ERROR != 0,
+            parity_error: status & DAP_UART_STATUS_PARITY_ERROR != 0,
+            tx_enabled: status & DAP_UART_STATUS_TX_ENABLED != 0,
+            rx_count,
+            tx_count,
+        }
+    }
+}
+
+/// The target UART of a CMSIS-DAP 2.1 probe, driven with DAP_UART_* commands.
+pub struct Uart<'a, T: DapTransport> {
+    session: &'a mut DapSession<T>,
+}
+
+impl<'a, T: DapTransport> Uart<'a, T> {
+    pub fn new(session: &'a mut DapSession<T>) -> Self {
+        Uart { session }
+    }
+
+    pub fn session(&mut self) -> &mut DapSession<T> {
+        self.session
+    }
+
+    /// Route the UART through DAP_UART_Transfer and apply `config`.
+    /// Returns the baudrate the probe actually uses.
+    pub fn configure(&mut self, config: &UartConfig) -> Result<u32, DapError> {
+        let responses = self.session.commands(&[
+            Command::UartTransport(DAP_UART_TRANSPORT_DAP_COMMAND),
+            Command::UartConfigure { control: config.control(), baudrate: config.baudrate },
+        ])?;
+        match responses[1] {
+            Response::UartConfigure { status: 0, baudrate } => Ok(baudrate),
+            Response::UartConfigure { status, .. } => {
+                for (bit, setting) in [
+                    (DAP_UART_CFG_ERROR_DATA_BITS, "data bits"),
+                    (DAP_UART_CFG_ERROR_PARITY, "parity"),
+                    (DAP_UART_CFG_ERROR_STOP_BITS, "stop bits"),
+                ] {
+                    if status & bit != 0 {
+                        log::warn!("UART {} not supported by the probe", setting);
+                    }
+                }
+                Err(DapError::Status { command: ID_DAP_UART_Configure })
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    /// Flush both buffers and enable receiving and transmitting.
+    pub fn start(&mut self) -> Result<(), DapError> {
+        let control = DAP_UART_CONTROL_RX_BUF_FLUSH
+            | DAP_UART_CONTROL_TX_BUF_FLUSH
+            | DAP_UART_CONTROL_RX_ENABLE
+            | DAP_UART_CONTROL_TX_ENABLE;
+        self.session.command(&Command::UartControl(control))?;
+        Ok(())
+    }
+
+    pub fn stop(&mut self) -> Result<(), DapError> {
+        self.session.command(&Command::UartControl(DAP_UART_CONTROL_RX_DISABLE | DAP_UART_CONTROL_TX_DISABLE))?;
+        Ok(())
+    }
+
+    pub fn status(&mut self) -> Result<UartStatus, DapError> {
+        match self.session.command(&Command::UartStatus)? {
+            Response::UartStatus { status, rx_count, tx_count } => Ok(UartStatus::new(status, rx_count, tx_count)),
+            _ => unreachable!(),
+        }
+    }
+
+    /// Send as much of `data` as fits in one packet and collect what has been received.
+    /// Returns the number of bytes the probe took, which may be fewer when its
+    /// transmit buffer is full, and the received bytes.
+    pub fn transfer(&mut self, data: &[u8]) -> Result<(usize, Vec<u8>), DapError> {
+        let len = data.len().min(self.session.packet_size() - 3);
+        match self.session.command(&Command::UartTransfer(data[..len].to_vec()))? {
+            Response::UartTransfer { status, sent, data } => {
+                if status & DAP_UART_STATUS_RX_DATA_LOST != 0 {
+                    log::warn!("UART receive buffer overrun, data was lost");
+                }
+                if status & (DAP_UART_STATUS_FRAMING_ERROR | DAP_UART_STATUS_PARITY_ERROR) != 0 {
+                    log::warn!("UART framing or parity error");
+                }
+                Ok((sent.min(len), data))
+            }
+            _ => unreachable!(),
+        }
+    }
+}
+
+struct State {
+    rx: VecDeque<u8>,
+    tx: VecDeque<u8>,
+    stop: bool,
+    /// Set when the polling thread has ended, with the reason if it failed.
+    closed: Option<Option<String>>,
+}
+
+struct Shared {
+    state: Mutex<State>,
+    changed: Condvar,
+}
+
+impl Shared {
+    fn lock(&self) -> MutexGuard<'_, State> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+/// The target console as a byte stream.
+///
+/// A background thread owns the session and polls DAP_UART_Transfer, sending what
+/// was written and queueing what was received. A read fails with
+/// [`io::ErrorKind::TimedOut`] if nothing arrived within the timeout, and returns 0
+/// once the thread has stopped. Like [`std::net::TcpStream`], `&UartStream` is a
+/// stream too, so one thread can read while another writes.
+pub struct UartStream<T: DapTransport + Send + 'static> {
+    shared: Arc<Shared>,
+    thread: Option<JoinHandle<Result<DapSession<T>, DapError>>>,
+    timeout: Duration,
+}
+
+impl<T: DapTransport + Send + 'static> UartStream<T> {
+    /// Configure and start the UART, then hand the session to the polling thread.
+    pub fn open(mut session: DapSession<T>, config: &UartConfig) -> Result<Self, DapError> {
+        let mut uart = Uart::new(&mut session);
+        let baudrate = uart.configure(config)?;
+        log::debug!("UART {} baud", baudrate);
+        uart.start()?;
+
+        let shared = Arc::new(Shared {
+            state: Mutex::new(State { rx: VecDeque::new(), tx: VecDeque::new(), stop: false, closed: None }),
+            changed: Condvar::new(),
+        });
+        let thread_shared = shared.clone();
+        let thread = std::thread::spawn(move || {
+            let result = poll(&mut session, &thread_shared);
+            let mut state = thread_shared.lock();
+            state.closed = Some(result.as_ref().err().map(ToString::to_string));
+            thread_shared.changed.notify_all();
+            drop(state);
+            result.map(|_| session)
+        });
+        Ok(UartStream { shared, thread: Some(thread), timeout: DEFAULT_TIMEOUT })
+    }
+
+    pub fn set_timeout(&mut self, timeout: Duration) {
+        self.timeout = timeout;
+    }
+
+    /// Stop the polling thread and the UART, and return the session.
+    pub fn close(mut self) -> Result<DapSession<T>, DapError> {
+        let mut session = self.join().expect("UART thread already joined")?;
+        Uart::new(&mut session).stop()?;
+        Ok(session)
+    }
+
+    fn join(&mut self) -> Option<Result<DapSession<T>, DapError>> {
+        let thread = self.thread.take()?;
+        self.shared.lock().stop = true;
+        self.shared.changed.notify_all();
+        Some(thread.join().expect("UART thread panicked"))
+    }
+
+    fn read_bytes(&self, buf: &mut [u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        let deadline = Instant::now() + self.timeout;
+        let mut state = self.shared.lock();
+        while state.rx.is_empty() {
+            match &state.closed {
+                Some(None) => return Ok(0),
+                Some(Some(e)) => return Err(io::Error::other(e.clone())),
+                None => (),
+            }
+            let now = Instant::now();
+            if now >= deadline {
+                return Err(io::ErrorKind::TimedOut.into());
+            }
+            state = self.shared.changed.wait_timeout(state, deadline - now).unwrap_or_else(|e| e.into_inner()).0;
+        }
+        let len = buf.len().min(state.rx.len());
+        for (dst, src) in buf.iter_mut().zip(state.rx.drain(..len)) {
+            *dst = src;
+        }
+        Ok(len)
+    }
+
+    /// Queue `buf`, waiting while too much is queued already.
+    fn write_bytes(&self, buf: &[u8]) -> io::Result<usize> {
+        let mut state = self.shared.lock();
+        loop {
+            if state.closed.is_some() {
+                return Err(io::ErrorKind::BrokenPipe.into());
+            }
+            if state.tx.len() < TX_QUEUE_LIMIT {
+                break;
+            }
+            state = self.shared.changed.wait(state).unwrap_or_else(|e| e.into_inner());
+        }
+        let len = buf.len().min(TX_QUEUE_LIMIT - state.tx.len());
+        state.tx.extend(&buf[..len]);
+        Ok(len)
+    }
+
+    /// Wait until every queued byte has been handed to the probe.
+    fn flush_bytes(&self) -> io::Result<()> {
+        let mut state = self.shared.lock();
+        while !state.tx.is_empty() {
+            if state.closed.is_some() {
+                return Err(io::ErrorKind::BrokenPipe.into());
+            }
+            state = self.shared.changed.wait(state).unwrap_or_else(|e| e.into_inner());
+        }
+        Ok(())
+    }
+}
+
+impl<T: DapTransport + Send + 'static> Drop for UartStream<T> {
+    fn drop(&mut self) {
+        if let Some(Ok(mut session)) = self.join() {
+            Uart::new(&mut session).stop().ok();
+        }
+    }
+}
+
+/// Body of the polling thread.
+fn poll<T: DapTransport>(session: &mut DapSession<T>, shared: &Shared) -> Result<(), DapError> {
+    let mut uart = Uart::new(session);
+    loop {
+        let data: Vec<u8> = {
+            let state = shared.lock();
+            if state.stop {
+                return Ok(());
+            }
+            state.tx.iter().copied().take(u16::MAX as usize).collect()
+        };
+        let (sent, received) = uart.transfer(&data)?;
+        let idle = sent == 0 && received.is_empty();
+        {
+            let mut state = shared.lock();
+            state.tx.drain(..sent);
+            state.rx.extend(received);
+        }
+        shared.changed.notify_all();
+        if idle {
+            let state = shared.lock();
+            if !state.stop {
+                drop(shared.changed.wait_timeout(state, POLL_INTERVAL));
+            }
+        }
+    }
+}
+
+impl<T: DapTransport + Send + 'static> io::Read for UartStream<T> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.read_bytes(buf)
+    }
+}
+
+impl<T: DapTransport + Send + 'static> io::Read for &UartStream<T> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.read_bytes(buf)
+    }
+}
+
+impl<T: DapTransport + Send + 'static> io::Write for UartStream<T> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.write_bytes(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.flush_bytes()
+    }
+}
+
+impl<T: DapTransport + Send + 'static> io::Write for &UartStream<T> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.write_bytes(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.flush_bytes()
+    }
+}
+
+#[cfg(target_os = "linux")]
+pub use self::pty::Pty;
+
+#[cfg(target_os = "linux")]
+mod pty {
+    use std::ffi::CStr;
+    use std::fs::{File, OpenOptions};
+    use std::io::{self, Read, Write};
+    use std::os::unix::io::{AsRawFd, FromRawFd};
+    use std::path::{Path, PathBuf};
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::time::Duration;
+
+    use super::UartStream;
+    use crate::transport::DapTransport;
+
+    /// How often the thread waiting for terminal input checks whether to stop.
+    const INPUT_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+    /// A pseudo terminal in raw mode, for terminal programs to open the target console.
+    ///
+    /// The terminal side stays open as long as the `Pty` does, so data arriving
+    /// while no program has it open is kept rather than lost.
+    pub struct Pty {
+        master: File,
+        _slave: File,
+        path: PathBuf,
+    }
+
+    impl Pty {
+        pub fn open() -> io::Result<Self> {
+            // SAFETY: plain libc calls, the descriptor is owned by the File from here on
+            let master = unsafe {
+                let fd = libc::posix_openpt(libc::O_RDWR | libc::O_NOCTTY);
+                if fd < 0 {
+                    return Err(io::Error::last_os_error());
+                }
+                File::from_raw_fd(fd)
+            };
+            let fd = master.as_raw_fd();
+            let mut name = [0 as libc::c_char; 128];
+            // SAFETY: fd is a pty master and name is large enough for any pty path
+            let path = unsafe {
+                if libc::grantpt(fd) != 0
+                    || libc::unlockpt(fd) != 0
+                    || libc::ptsname_r(fd, name.as_mut_ptr(), name.len()) != 0
+                {
+                    return Err(io::Error::last_os_error());
+                }
+                PathBuf::from(CStr::from_ptr(name.as_ptr()).to_string_lossy().into_owned())
+            };
+            let slave = OpenOptions::new().read(true).write(true).open(&path)?;
+            let slave_fd = slave.as_raw_fd();
+            // SAFETY: termios is plain data filled in by tcgetattr
+            unsafe {
+                let mut termios = std::mem::zeroed::<libc::termios>();
+                if libc::tcgetattr(slave_fd, &mut termios) != 0 {
+                    return Err(io::Error::last_os_error());
+                }
+                libc::cfmakeraw(&mut termios);
+                if libc::tcsetattr(slave_fd, libc::TCSANOW, &termios) != 0 {
+                    return Err(io::Error::last_os_error());
+                }
+            }
+            Ok(Pty { master, _slave: slave, path })
+        }
+
+        /// Device path for terminal programs, such as /dev/pts/3.
+        pub fn path(&self) -> &Path {
+            &self.path
+        }
+
+        /// Wait up to `timeout` for the terminal side to send something.
+        fn readable(&self, timeout: Duration) -> io::Result<bool> {
+            let mut fds = libc::pollfd { fd: self.master.as_raw_fd(), events: libc::POLLIN, revents: 0 };
+            // SAFETY: a single pollfd which lives across the call
+            if unsafe { libc::poll(&mut fds, 1, timeout.as_millis() as libc::c_int) } < 0 {
+                let e = io::Error::last_os_error();
+                return if e.kind() == io::ErrorKind::Interrupted { Ok(false) } else { Err(e) };
+            }
+            Ok(fds.revents & libc::POLLIN != 0)
+        }
+
+        /// Copy between the pty and `stream` until either fails.
+        pub fn serve<T: DapTransport + Send + 'static>(&self, stream: &UartStream<T>) -> io::Result<()> {
+            // the terminal side is held open, so a blocking read would only return on input
+            let stop = AtomicBool::new(false);
+            std::thread::scope(|scope| {
+                let input = scope.spawn(|| -> io::Result<()> {
+                    let mut buf = [0; 256];
+                    while !stop.load(Ordering::Relaxed) {
+                        if !self.readable(INPUT_POLL_INTERVAL)? {
+                            continue;
+                        }
+                        let len = (&self.master).read(&mut buf)?;
+                        let mut writer = stream;
+                        writer.write_all(&buf[..len])?;
+                    }
+                    Ok(())
+                });
+                let mut buf = [0; 256];
+                let result = loop {
+                    let mut reader = stream;
+                    match reader.read(&mut buf) {
+                        Ok(0) => break Ok(()),
+                        Ok(len) => {
+                            if let Err(e) = (&self.master).write_all(&buf[..len]) {
+                                break Err(e);
+                            }
+                        }
+                        Err(e) if e.kind() == io::ErrorKind::TimedOut => {
+                            if input.is_finished() {
+                                break Ok(());
+                            }
+                        }
+                        Err(e) => break Err(e),
+                    }
+                };
+                stop.store(true, Ordering::Relaxed);
+                let input = input.join().expect("pty thread panicked");
+                result.and(input)
+            })
+        }
+    }
+}
@@ -0,0 +1,363 @@
+use crate::jtag::TapState;
+use crate::svf::{verify, Location, Player, SvfError};
+use crate::transport::DapTransport;
+
+const XCOMPLETE: u8 = 0x00;
+const XTDOMASK: u8 = 0x01;
+const XSIR: u8 = 0x02;
+const XSDR: u8 = 0x03;
+const XRUNTEST: u8 = 0x04;
+const XREPEAT: u8 = 0x07;
+const XSDRSIZE: u8 = 0x08;
+const XSDRTDO: u8 = 0x09;
+const XSETSDRMASKS: u8 = 0x0A;
+const XSDRINC: u8 = 0x0B;
+const XSDRB: u8 = 0x0C;
+const XSDRC: u8 = 0x0D;
+const XSDRE: u8 = 0x0E;
+const XSDRTDOB: u8 = 0x0F;
+const XSDRTDOC: u8 = 0x10;
+const XSDRTDOE: u8 = 0x11;
+const XSTATE: u8 = 0x12;
+const XENDIR: u8 = 0x13;
+const XENDDR: u8 = 0x14;
+const XSIR2: u8 = 0x15;
+const XCOMMENT: u8 = 0x16;
+const XWAIT: u8 = 0x17;
+
+/// Retries of a failed XSDRTDO until the file says otherwise.
+const DEFAULT_REPEAT: u8 = 32;
+
+/// TAP states in XSVF encoding order.
+const STATES: [TapState; 16] = [
+    TapState::TestLogicReset,
+    TapState::RunTestIdle,
+    TapState::SelectDr,
+    TapState::CaptureDr,
+    TapState::ShiftDr,
+    TapState::Exit1Dr,
+    TapState::PauseDr,
+    TapState::Exit2Dr,
+    TapState::UpdateDr,
+    TapState::SelectIr,
+    TapState::CaptureIr,
+    TapState::ShiftIr,
+    TapState::Exit1Ir,
+    TapState::PauseIr,
+    TapState::Exit2Ir,
+    TapState::UpdateIr,
+];
+
+/// An XSVF instruction. Bit strings are LSB first.
+#[derive(Clone, Debug, PartialEq)]
+pub enum XsvfInstruction {
+    Complete,
+    TdoMask(Vec<u8>),
+    Sir { len: usize, tdi: Vec<u8> },
+    Sdr(Vec<u8>),
+    /// Microseconds to spend in Run-Test/Idle after each scan.
+    RunTest(u32),
+    Repeat(u8),
+    SdrSize(usize),
+    SdrTdo { tdi: Vec<u8>, tdo: Vec<u8> },
+    /// XSDRB/C/E and XSDRTDOB/C/E: a DR shift split over several instructions.
+    SdrPart { tdi: Vec<u8>, tdo: Option<Vec<u8>>, first: bool, last: bool },
+    State(TapState),
+    EndIr(TapState),
+    EndDr(TapState),
+    Comment(String),
+    Wait { wait_state: TapState, end_state: TapState, us: u32 },
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct XsvfStatement {
+    pub offset: usize,
+    pub instruction: XsvfInstruction,
+}
+
+struct Reader<'d> {
+    data: &'d [u8],
+    pos: usize,
+    start: usize,
+}
+
+impl<'d> Reader<'d> {
+    fn error(&self, message: &str) -> SvfError {
+        SvfError::Parse { at: Location::Offset(self.start), message: message.into() }
+    }
+
+    fn bytes(&mut self, len: usize) -> Result<&'d [u8], SvfError> {
+        let bytes = self.data.get(self.pos..self.pos + len).ok_or_else(|| self.error("truncated instruction"))?;
+        self.pos += len;
+        Ok(bytes)
+    }
+
+    fn u8(&mut self) -> Result<u8, SvfError> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16, SvfError> {
+        let bytes = self.bytes(2)?;
+        Ok(u16::from_be_bytes([bytes[0], bytes[1]]))
+    }
+
+    fn u32(&mut self) -> Result<u32, SvfError> {
+        let bytes = self.bytes(4)?;
+        Ok(u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    }
+
+    /// A `len` bit value, stored MSB first.
+    fn bits(&mut self, len: usize) -> Result<Vec<u8>, SvfError> {
+        let mut bits = self.bytes(len.div_ceil(8))?.to_vec();
+        bits.reverse();
+        Ok(bits)
+    }
+
+    fn state(&mut self) -> Result<TapState, SvfError> {
+        let code = self.u8()?;
+        STATES.get(code as usize).copied().ok_or_else(|| self.error("bad TAP state"))
+    }
+
+    /// XENDIR and XENDDR only choose between Run-Test/Idle and the pause state.
+    fn end_state(&mut self, pause: TapState) -> Result<TapState, SvfError> {
+        match self.u8()? {
+            0 => Ok(TapState::RunTestIdle),
+            1 => Ok(pause),
+            _ => Err(self.error("bad end state")),
+        }
+    }
+}
+
+/// Parse an XSVF file.
+pub fn parse_xsvf(data: &[u8]) -> Result<Vec<XsvfStatement>, SvfError> {
+    let mut reader = Reader { data, pos: 0, start: 0 };
+    let mut statements = Vec::new();
+    let mut sdr_size = 0;
+    while reader.pos < data.len() {
+        reader.start = reader.pos;
+        let opcode = reader.u8()?;
+        let instruction = match opcode {
+            XCOMPLETE => XsvfInstruction::Complete,
+            XTDOMASK => XsvfInstruction::TdoMask(reader.bits(sdr_size)?),
+            XSIR => {
+                let len = reader.u8()? as usize;
+                XsvfInstruction::Sir { len, tdi: reader.bits(len)? }
+            }
+            XSIR2 => {
+                let len = reader.u16()? as usize;
+                XsvfInstruction::Sir { len, tdi: reader.bits(len)? }
+            }
+            XSDR => XsvfInstruction::Sdr(reader.bits(sdr_size)?),
+            XRUNTEST => XsvfInstruction::RunTest(reader.u32()?),
+            XREPEAT => XsvfInstruction::Repeat(reader.u8()?),
+            XSDRSIZE => {
+                sdr_size = reader.u32()? as usize;
+                XsvfInstruction::SdrSize(sdr_size)
+            }
+            XSDRTDO => XsvfInstruction::SdrTdo { tdi: reader.bits(sdr_size)?, tdo: reader.bits(sdr_size)? },
+            XSDRB | XSDRC | XSDRE => XsvfInstruction::SdrPart {
+                tdi: reader.bits(sdr_size)?,
+                tdo: None,
+                first: opcode == XSDRB,
+                last: opcode == XSDRE,
+            },
+            XSDRTDOB | XSDRTDOC | XSDRTDOE => XsvfInstruction::SdrPart {
+                tdi: reader.bits(sdr_size)?,
+                tdo: Some(reader.bits(sdr_size)?),
+                first: opcode == XSDRTDOB,
+                last: opcode == XSDRTDOE,
+            },
+            XSTATE => XsvfInstruction::State(reader.state()?),
+            XENDIR => XsvfInstruction::EndIr(reader.end_state(TapState::PauseIr)?),
+            XENDDR => XsvfInstruction::EndDr(reader.end_state(TapState::PauseDr)?),
+            XCOMMENT => {
+                let len = data[reader.pos..].iter().position(|&b| b == 0).ok_or_else(|| reader.error("unterminated comment"))?;
+                let text = String::from_utf8_lossy(reader.bytes(len + 1)?[..len].as_ref()).into_owned();
+                XsvfInstruction::Comment(text)
+            }
+            XWAIT => XsvfInstruction::Wait { wait_state: reader.state()?, end_state: reader.state()?, us: reader.u32()? },
+            XSETSDRMASKS | XSDRINC => return Err(reader.error("XSETSDRMASKS and XSDRINC are not supported")),
+            _ => return Err(reader.error("unknown instruction")),
+        };
+        let complete = instruction == XsvfInstruction::Complete;
+        statements.push(XsvfStatement { offset: reader.start, instruction });
+        if complete {
+            break;
+        }
+    }
+    Ok(statements)
+}
+
+impl<'a, T: DapTransport> Player<'a, T> {
+    /// Execute parsed XSVF instructions, stopping at the first TDO mismatch that
+    /// persists through the XREPEAT retries.
+    pub fn run_xsvf(&mut self, statements: &[XsvfStatement]) -> Result<(), SvfError> {
+        let mut sdr_size: usize = 0;
+        let mut mask: Option<Vec<u8>> = None;
+        let mut expected: Option<Vec<u8>> = None;
+        let mut repeat = DEFAULT_REPEAT;
+        let mut runtest = 0;
+        let mut endir = TapState::RunTestIdle;
+        let mut enddr = TapState::RunTestIdle;
+
+        for statement in statements {
+            let at = Location::Offset(statement.offset);
+            log::trace!("{}: {:?}", at, statement.instruction);
+            let all_ones = vec![0xFF; sdr_size.div_ceil(8)];
+            match &statement.instruction {
+                XsvfInstruction::Complete => break,
+                XsvfInstruction::TdoMask(value) => mask = Some(value.clone()),
+                XsvfInstruction::Sir { len, tdi } => {
+                    let end = if runtest > 0 { TapState::RunTestIdle } else { endir };
+                    self.shift(true, tdi, *len, Some(end))?;
+                    self.delay(runtest as u64)?;
+                }
+                XsvfInstruction::Sdr(tdi) | XsvfInstruction::SdrTdo { tdi, .. } => {
+                    if let XsvfInstruction::SdrTdo { tdo, .. } = &statement.instruction {
+                        expected = Some(tdo.clone());
+                    }
+                    let end = if runtest > 0 { TapState::RunTestIdle } else { enddr };
+                    let mask = mask.as_ref().unwrap_or(&all_ones);
+                    // as the Xilinx reference player: a retry goes back to Shift-DR through
+                    // Pause-DR without updating DR, and waits 25% longer each time
+                    let mut wait = runtest;
+                    for attempt in 0..=repeat {
+                        let captured = self.shift(false, tdi, sdr_size, Some(TapState::Exit1Dr))?;
+                        let result = match &expected {
+                            Some(expected) => verify(at, &captured, expected, mask, sdr_size),
+                            None => Ok(()),
+                        };
+                        match result {
+                            Err(_) if attempt < repeat => {
+                                log::debug!("{}: TDO mismatch, retrying", at);
+                                self.goto(TapState::PauseDr)?;
+                                self.goto(TapState::Exit2Dr)?;
+                                self.goto(TapState::ShiftDr)?;
+                                self.delay(wait as u64)?;
+                                wait += wait >> 2;
+                            }
+                            result => {
+                                self.goto(end)?;
+                                self.delay(wait as u64)?;
+                                result?;
+                                break;
+                            }
+                        }
+                    }
+                }
+                XsvfInstruction::RunTest(us) => runtest = *us,
+                XsvfInstruction::Repeat(count) => repeat = *count,
+                XsvfInstruction::SdrSize(size) => sdr_size = *size,
+                XsvfInstruction::SdrPart { tdi, tdo, first, last } => {
+                    if *first && self.state() != TapState::ShiftDr {
+                        self.goto(TapState::ShiftDr)?;
+                    }
+                    let end = if *last { Some(enddr) } else { None };
+                    let captured = self.shift(false, tdi, sdr_size, end)?;
+                    if let Some(tdo) = tdo {
+                        verify(at, &captured, tdo, mask.as_ref().unwrap_or(&all_ones), sdr_size)?;
+                    }
+                }
+                XsvfInstruction::State(state) => self.goto(*state)?,
+                XsvfInstruction::EndIr(state) => endir = *state,
+                XsvfInstruction::EndDr(state) => enddr = *state,
+                XsvfInstruction::Comment(text) => log::info!("{}", text),
+                XsvfInstruction::Wait { wait_state, end_state, us } => {
+                    self.goto(*wait_state)?;
+                    self.delay(*us as u64)?;
+                    self.goto(*end_state)?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::command::{Command, DAP_PORT_JTAG};
+    use crate::jtag::{swd_to_jtag_sequence, Jtag};
+    use crate::session::DapSession;
+    use crate::simulator::SimulatedProbe;
+
+    /// Read the IDCODE of the single TAP of the simulator and compare it with `idcode`.
+    fn read_idcode(idcode: u32) -> Vec<u8> {
+        let mut data = vec![XREPEAT, 0, XSTATE, 0, XSIR, 4, 0x0E, XSDRSIZE, 0, 0, 0, 32, XTDOMASK, 0xFF, 0xFF, 0xFF, 0xFF];
+        data.extend([XSDRTDO, 0, 0, 0, 0]);
+        data.extend(idcode.to_be_bytes());
+        data.push(XCOMPLETE);
+        data
+    }
+
+    fn play(data: &[u8]) -> Result<(), SvfError> {
+        let mut session = DapSession::open(SimulatedProbe::new())?;
+        session.execute_commands(vec![Command::Connect(DAP_PORT_JTAG), swd_to_jtag_sequence()])?;
+        Player::new(Jtag::new(&mut session))?.run_xsvf(&parse_xsvf(data)?)
+    }
+
+    #[test]
+    fn parse() {
+        let statements = parse_xsvf(&read_idcode(0x4BA0_0477)).unwrap();
+        let instructions: Vec<_> = statements.iter().map(|statement| statement.instruction.clone()).collect();
+        assert_eq!(
+            instructions,
+            [
+                XsvfInstruction::Repeat(0),
+                XsvfInstruction::State(TapState::TestLogicReset),
+                XsvfInstruction::Sir { len: 4, tdi: vec![0x0E] },
+                XsvfInstruction::SdrSize(32),
+                XsvfInstruction::TdoMask(vec![0xFF; 4]),
+                XsvfInstruction::SdrTdo { tdi: vec![0; 4], tdo: vec![0x77, 0x04, 0xA0, 0x4B] },
+                XsvfInstruction::Complete,
+            ]
+        );
+        assert_eq!(statements[5].offset, 17);
+    }
+
+    #[test]
+    fn parse_other_instructions() {
+        let mut data = vec![XSIR2, 0x00, 0x0A, 0x01, 0x23, XENDIR, 1, XENDDR, 0, XCOMMENT, b'h', b'i', 0];
+        data.extend([XWAIT, 1, 6, 0, 0, 0x01, 0x00, XSDRSIZE, 0, 0, 0, 12, XSDRTDOB, 0x0A, 0xBC, 0x00, 0x01, XSDRE, 0x00, 0x02]);
+        // nothing after XCOMPLETE is parsed
+        data.extend([XCOMPLETE, 0xEE]);
+        let instructions: Vec<_> = parse_xsvf(&data).unwrap().into_iter().map(|statement| statement.instruction).collect();
+        assert_eq!(
+            instructions,
+            [
+                XsvfInstruction::Sir { len: 10, tdi: vec![0x23, 0x01] },
+                XsvfInstruction::EndIr(TapState::PauseIr),
+                XsvfInstruction::EndDr(TapState::RunTestIdle),
+                XsvfInstruction::Comment("hi".into()),
+                XsvfInstruction::Wait { wait_state: TapState::RunTestIdle, end_state: TapState::PauseDr, us: 256 },
+                XsvfInstruction::SdrSize(12),
+                XsvfInstruction::SdrPart { tdi: vec![0xBC, 0x0A], tdo: Some(vec![0x01, 0x00]), first: true, last: false },
+                XsvfInstruction::SdrPart { tdi: vec![0x02, 0x00], tdo: None, first: false, last: true },
+                XsvfInstruction::Complete,
+            ]
+        );
+    }
+
+    #[test]
+    fn parse_errors() {
+        let offset = |data: &[u8]| match parse_xsvf(data) {
+            Err(SvfError::Parse { at: Location::Offset(offset), .. }) => offset,
+            result => panic!("expected a parse error, got {:?}", result),
+        };
+        assert_eq!(offset(&[XRUNTEST, 0, 0, 0, 0, XRUNTEST, 0, 0]), 5);
+        assert_eq!(offset(&[XSTATE, 16]), 0);
+        assert_eq!(offset(&[XENDDR, 2]), 0);
+        assert_eq!(offset(&[XCOMMENT, b'x']), 0);
+        assert_eq!(offset(&[XREPEAT, 1, XSDRINC]), 2);
+        assert_eq!(offset(&[0x42]), 0);
+    }
+
+    #[test]
+    fn play_idcode() {
+        play(&read_idcode(0x4BA0_0477)).unwrap();
+        match play(&read_idcode(0x4BA0_0478)) {
+            Err(SvfError::Mismatch { at: Location::Offset(17), bit: 0, .. }) => (),
+            result => panic!("expected a mismatch, got {:?}", result),
+        }
+    }
+}